@@ -1,2 +1,3 @@
 pub mod llm_ls;
+pub mod notification;
 pub mod request;
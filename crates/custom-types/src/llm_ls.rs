@@ -1,4 +1,4 @@
-use std::{fmt::Display, path::PathBuf};
+use std::{collections::HashMap, fmt::Display, path::PathBuf};
 
 use lsp_types::TextDocumentPositionParams;
 use serde::{Deserialize, Deserializer, Serialize};
@@ -6,6 +6,7 @@ use serde_json::{Map, Value};
 use uuid::Uuid;
 
 const HF_INFERENCE_API_HOSTNAME: &str = "api-inference.huggingface.co";
+const AZURE_OPENAI_DEFAULT_API_VERSION: &str = "2024-02-01";
 
 #[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
@@ -60,9 +61,59 @@ fn hf_default_url() -> String {
     format!("https://{HF_INFERENCE_API_HOSTNAME}")
 }
 
+fn azure_default_api_version() -> String {
+    AZURE_OPENAI_DEFAULT_API_VERSION.to_owned()
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(rename_all = "lowercase", tag = "backend")]
 pub enum Backend {
+    /// A model deployed on Azure OpenAI
+    #[serde(rename_all = "camelCase")]
+    AzureOpenAi {
+        /// The resource endpoint, e.g. `https://<resource>.openai.azure.com`, or the full url of
+        /// the deployment's completions endpoint
+        url: String,
+        /// Name of the deployment, defaults to the model
+        #[serde(default)]
+        deployment: Option<String>,
+        #[serde(default = "azure_default_api_version")]
+        api_version: String,
+        /// Use the deployment's `/chat/completions` endpoint instead of `/completions`
+        #[serde(default)]
+        chat: bool,
+    },
+    /// Any HTTP API, described by a request body template and where to find the generated text
+    /// and the error message in its responses
+    #[serde(rename_all = "camelCase")]
+    Custom {
+        /// Used as is, without appending a route
+        url: String,
+        /// Request body where the `{{prompt}}`, `{{prefix}}`, `{{suffix}}`, `{{model}}`,
+        /// `{{stream}}`, `{{request_body}}` and `{{request_body.<field>}}` placeholders are
+        /// replaced. A string made of a single placeholder is replaced with the value as is, e.g.
+        /// a number from `request_body`, and removed when the `request_body` field is not set.
+        body_template: Map<String, Value>,
+        /// Added to the user agent, `{{api_token}}` is replaced with the API token in values. The
+        /// token is not sent anywhere else, and the headers using it are left out without one
+        #[serde(default)]
+        headers: HashMap<String, String>,
+        /// Path of the generated text(s) in the response, either a JSON pointer like
+        /// `/output/text` or a dotted path where `*` matches every element like `choices.*.text`
+        generated_text_path: String,
+        /// Path of the error message in the response, using the same syntax
+        #[serde(default)]
+        error_path: Option<String>,
+    },
+    /// A backend registered by a binary embedding llm-ls, routed by its `name`
+    Extension {
+        /// The name the backend is registered with in the `BackendRegistry`
+        name: String,
+        url: String,
+        /// Passed as is to the backend
+        #[serde(default)]
+        config: Value,
+    },
     HuggingFace {
         #[serde(default = "hf_default_url", deserialize_with = "parse_url")]
         url: String,
@@ -76,6 +127,9 @@ pub enum Backend {
     OpenAi {
         url: String,
     },
+    OpenAiChat {
+        url: String,
+    },
     Tgi {
         url: String,
     },
@@ -97,23 +151,104 @@ impl Backend {
         }
     }
 
+    /// The value of the `backend` tag this variant is (de)serialized with, or the name of an
+    /// extension backend.
+    pub fn tag(&self) -> &str {
+        match self {
+            Self::AzureOpenAi { .. } => "azureopenai",
+            Self::Custom { .. } => "custom",
+            Self::Extension { name, .. } => name,
+            Self::HuggingFace { .. } => "huggingface",
+            Self::LlamaCpp { .. } => "llamacpp",
+            Self::Ollama { .. } => "ollama",
+            Self::OpenAi { .. } => "openai",
+            Self::OpenAiChat { .. } => "openaichat",
+            Self::Tgi { .. } => "tgi",
+        }
+    }
+
     pub fn url(self) -> String {
         match self {
+            Self::AzureOpenAi { url, .. } => url,
+            Self::Custom { url, .. } => url,
+            Self::Extension { url, .. } => url,
             Self::HuggingFace { url } => url,
             Self::LlamaCpp { url } => url,
             Self::Ollama { url } => url,
             Self::OpenAi { url } => url,
+            Self::OpenAiChat { url } => url,
             Self::Tgi { url } => url,
         }
     }
 }
 
+/// How the prefix and suffix surrounding the cursor are sent to the backend.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FimMode {
+    /// Wrap the prefix and suffix with the FIM tokens in a single prompt string
+    #[default]
+    Template,
+    /// Send the prefix and suffix as separate fields and let the server apply the model's own FIM
+    /// template, e.g. llama.cpp `/infill` or the `suffix` parameter of ollama and OpenAI
+    /// compatible APIs. Backends without a dedicated FIM API fall back to [`FimMode::Template`].
+    Native,
+}
+
+/// The order of the prefix and suffix in a FIM prompt.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FimOrder {
+    /// `<prefix>{prefix}<suffix>{suffix}<middle>`
+    #[default]
+    Psm,
+    /// `<prefix><suffix>{suffix}<middle>{prefix}`
+    Spm,
+}
+
+/// Prompt format of a known model family.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Preset {
+    /// Picks the preset matching the model name, if any
+    Auto,
+    /// Leaves the request as configured
+    #[default]
+    None,
+    StarCoder,
+    StarCoder2,
+    CodeLlama,
+    DeepSeek,
+    Qwen,
+    CodeGemma,
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
 pub struct FimParams {
     pub enabled: bool,
+    /// The FIM tokens, taken from the preset when empty
+    #[serde(default)]
     pub prefix: String,
+    #[serde(default)]
     pub middle: String,
+    #[serde(default)]
     pub suffix: String,
+    #[serde(default)]
+    pub mode: FimMode,
+    /// Defaults to the preset's order, PSM without a preset
+    #[serde(default)]
+    pub order: Option<FimOrder>,
+    /// Share of the token budget reserved for the suffix, the part of it the suffix doesn't use
+    /// goes to the prefix and vice versa. Prefix and suffix lines are taken alternately when unset
+    #[serde(default)]
+    pub suffix_ratio: Option<f32>,
+    #[serde(default)]
+    pub max_suffix_lines: Option<usize>,
+    /// Use the FIM tokens of the tokenizer when they follow a known convention, e.g.
+    /// `<fim_prefix>` or `<|fim_prefix|>`, falling back to the configured ones
+    #[serde(default)]
+    pub infer_tokens: bool,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -132,6 +267,210 @@ pub enum TokenizerConfig {
     },
 }
 
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct RetryConfig {
+    /// Total number of attempts, including the first one
+    pub max_attempts: u32,
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_backoff_ms: 500,
+            max_backoff_ms: 10_000,
+        }
+    }
+}
+
+fn default_credential_helper_ttl() -> u64 {
+    300
+}
+
+/// A command printing an API token on stdout, used when the client did not send one for the
+/// backend it is configured for.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CredentialHelper {
+    /// The `backend` tag the token is for, e.g. `openai`
+    pub backend: String,
+    /// Restricts the helper to the backend served at this url
+    #[serde(default)]
+    pub url: Option<String>,
+    pub command: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// How long the token is reused before running the command again
+    #[serde(default = "default_credential_helper_ttl")]
+    pub cache_ttl_secs: u64,
+}
+
+impl CredentialHelper {
+    pub fn applies_to(&self, backend: &Backend) -> bool {
+        scope_applies(&self.backend, self.url.as_deref(), backend)
+    }
+}
+
+fn scope_applies(tag: &str, url: Option<&str>, backend: &Backend) -> bool {
+    tag == backend.tag()
+        && url.map_or(true, |url| {
+            url.trim_end_matches('/') == backend.clone().url().trim_end_matches('/')
+        })
+}
+
+/// Backends identified by their `backend` tag, and optionally their url.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BackendScope {
+    pub backend: String,
+    #[serde(default)]
+    pub url: Option<String>,
+}
+
+impl BackendScope {
+    pub fn applies_to(&self, backend: &Backend) -> bool {
+        scope_applies(&self.backend, self.url.as_deref(), backend)
+    }
+}
+
+/// Server configuration sent by the editor in the `initializationOptions` of the `initialize`
+/// request, for settings that requests must not be able to change.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct InitializationOptions {
+    /// Only honoured over stdio, clients connecting to the TCP socket can't configure commands
+    pub credential_helpers: Vec<CredentialHelper>,
+    /// Backends that aren't served by OpenAI or Hugging Face but still get the API tokens found in
+    /// the environment, only honoured over stdio as well
+    pub env_token_backends: Vec<BackendScope>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct CacheConfig {
+    /// Requests sampling the generations, with `do_sample` or a `temperature` above 0 in
+    /// `request_body`, are never cached
+    pub enabled: bool,
+    pub max_entries: usize,
+    pub ttl_secs: u64,
+    /// Save the cache in llm-ls' cache directory so that it survives restarts
+    pub persist: bool,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_entries: 256,
+            ttl_secs: 600,
+            persist: false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct ConcurrencyConfig {
+    /// Requests sent to a backend at the same time, the next ones wait in a queue
+    pub max_concurrent_requests: usize,
+    /// Requests waiting for a backend, past which the queued requests for the same document are
+    /// dropped first, then the oldest ones
+    pub max_queued_requests: usize,
+    /// Send a single request for identical prompts in flight at the same time and share its
+    /// result
+    pub coalesce: bool,
+}
+
+impl Default for ConcurrencyConfig {
+    fn default() -> Self {
+        Self {
+            max_concurrent_requests: 4,
+            max_queued_requests: 16,
+            coalesce: true,
+        }
+    }
+}
+
+/// How the path, repository and language of the document are written ahead of it.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum MetadataFormat {
+    /// The repository name and file path header tokens of the preset, nothing when it has none
+    SpecialTokens,
+    /// A line comment, e.g. `// Repository: llm-ls, Path: src/main.rs, Language: rust`
+    Comment,
+    #[default]
+    None,
+}
+
+/// How the snippets of other files are written ahead of the prefix.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum SnippetFormat {
+    /// Line comments, starting with the path of the snippet's file, e.g. `// Path: src/lib.rs`
+    #[default]
+    Comment,
+    /// The file separator token followed by the path of the snippet's file on its own line, the
+    /// repository level format of models like StarCoder2
+    FileSeparator,
+}
+
+/// Context taken from other files than the one being completed.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct ContextConfig {
+    /// Add the parts of the other open documents of the same language that are the most similar
+    /// to the text before the cursor
+    pub neighboring_tabs: bool,
+    /// Add the chunks of the workspace's files that are the most relevant to the text before the
+    /// cursor, ranked with BM25 over their identifiers. Files are indexed in the background the
+    /// first time it is enabled, respecting `.gitignore`
+    pub workspace_index: bool,
+    /// Add the signatures of the symbols imported from other files of the workspace, for Python,
+    /// Rust, JavaScript, TypeScript and Go
+    pub imports: bool,
+    /// Share of the context window the snippets can use, the rest is left to the current document
+    pub max_context_ratio: f32,
+    /// Number of lines of the windows documents are split into
+    pub window_lines: usize,
+    /// Maximum number of snippets taken from each source
+    pub max_snippets: usize,
+    pub format: SnippetFormat,
+    pub file_separator: String,
+}
+
+impl Default for ContextConfig {
+    fn default() -> Self {
+        Self {
+            neighboring_tabs: false,
+            workspace_index: false,
+            imports: false,
+            max_context_ratio: 0.25,
+            window_lines: 20,
+            max_snippets: 4,
+            format: SnippetFormat::default(),
+            file_separator: "<file_sep>".to_owned(),
+        }
+    }
+}
+
+/// A backend to fall back to when the ones before it in the chain are unavailable.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BackendConfig {
+    pub model: String,
+    pub api_token: Option<String>,
+    #[serde(flatten)]
+    pub backend: Backend,
+    #[serde(default)]
+    pub request_timeout_ms: Option<u64>,
+    #[serde(default)]
+    pub extra_headers: HashMap<String, String>,
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct GetCompletionsParams {
@@ -145,14 +484,67 @@ pub struct GetCompletionsParams {
     pub model: String,
     #[serde(flatten)]
     pub backend: Backend,
+    /// Fills in the FIM tokens, header tokens, stop sequences and tokens to clear that aren't
+    /// configured, disabled by default
+    #[serde(default)]
+    pub preset: Preset,
+    /// Lays out the prompt instead of the FIM tokens with the `{prefix}`, `{suffix}`, `{snippets}`,
+    /// `{language}`, `{file_path}`, `{relative_path}` and `{repo_name}` placeholders, `{{` and `}}`
+    /// being literal braces
+    #[serde(default)]
+    pub prompt_template: Option<String>,
+    #[serde(default)]
+    pub metadata: MetadataFormat,
+    /// Sequences the generation stops at, the preset's are used when empty
+    #[serde(default)]
+    pub stop: Vec<String>,
     pub tokens_to_clear: Vec<String>,
     pub tokenizer_config: Option<TokenizerConfig>,
     pub context_window: usize,
+    /// Use the context window reported by the backend, e.g. TGI's `max_input_tokens`, ollama's
+    /// `num_ctx` or llama.cpp's `n_ctx`, falling back to `context_window` when it reports none.
+    /// `num_ctx` and `n_ctx` hold the generated tokens too, `num_predict` and `n_predict` (256
+    /// when unset) are kept for them
+    #[serde(default)]
+    pub discover_context_window: bool,
     pub tls_skip_verify_insecure: bool,
+    /// Proxy every request goes through, e.g. `http://proxy:3128` or `socks5://proxy:1080`
+    #[serde(default)]
+    pub proxy: Option<String>,
+    /// PEM encoded certificates trusted in addition to the system ones
+    #[serde(default)]
+    pub ca_cert_path: Option<PathBuf>,
+    /// PEM encoded client certificate presented for mutual TLS, it must also contain the private
+    /// key unless `client_key_path` is set
+    #[serde(default)]
+    pub client_cert_path: Option<PathBuf>,
+    #[serde(default)]
+    pub client_key_path: Option<PathBuf>,
     #[serde(default)]
     pub request_body: Map<String, Value>,
     #[serde(default)]
     pub disable_url_path_completion: bool,
+    #[serde(default)]
+    pub stream: bool,
+    #[serde(default)]
+    pub request_timeout_ms: Option<u64>,
+    /// Headers added to the requests sent to the backend, e.g. the ones required by a gateway
+    #[serde(default)]
+    pub extra_headers: HashMap<String, String>,
+    /// Backends tried in order when the previous one cannot be reached, times out, is rate
+    /// limiting or replies with a server error
+    #[serde(default)]
+    pub fallback_backends: Vec<BackendConfig>,
+    /// Applied to every backend of the chain before falling back to the next one, as well as to
+    /// tokenizer downloads
+    #[serde(default)]
+    pub retry: RetryConfig,
+    #[serde(default)]
+    pub cache: CacheConfig,
+    #[serde(default)]
+    pub concurrency: ConcurrencyConfig,
+    #[serde(default)]
+    pub context: ContextConfig,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -164,4 +556,95 @@ pub struct Completion {
 pub struct GetCompletionsResult {
     pub request_id: Uuid,
     pub completions: Vec<Completion>,
+    /// The backend that generated the completions, `None` when no request was sent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub served_by: Option<ServedBy>,
+    /// Whether the completions were served from llm-ls' completion cache
+    #[serde(default)]
+    pub cached: bool,
+}
+
+/// The backend that generated the completions, without its configuration which may hold secrets.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ServedBy {
+    pub model: String,
+    /// The `backend` tag
+    pub backend: String,
+    pub url: String,
+}
+
+impl ServedBy {
+    pub fn new(model: String, backend: &Backend) -> Self {
+        Self {
+            model,
+            backend: backend.tag().to_owned(),
+            url: backend.clone().url(),
+        }
+    }
+}
+
+/// The backend to check or list the models of, configured like in [`GetCompletionsParams`].
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BackendParams {
+    #[serde(default)]
+    #[serde(deserialize_with = "parse_ide")]
+    pub ide: Ide,
+    pub api_token: Option<String>,
+    /// Optional when listing models
+    #[serde(default)]
+    pub model: String,
+    #[serde(flatten)]
+    pub backend: Backend,
+    #[serde(default)]
+    pub tls_skip_verify_insecure: bool,
+    #[serde(default)]
+    pub proxy: Option<String>,
+    #[serde(default)]
+    pub ca_cert_path: Option<PathBuf>,
+    #[serde(default)]
+    pub client_cert_path: Option<PathBuf>,
+    #[serde(default)]
+    pub client_key_path: Option<PathBuf>,
+    #[serde(default)]
+    pub request_timeout_ms: Option<u64>,
+    #[serde(default)]
+    pub extra_headers: HashMap<String, String>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthStatus {
+    /// The backend accepted the request
+    Valid,
+    /// The backend replied with 401 or 403
+    Rejected,
+    /// The backend could not be reached or replied with an unrelated error
+    Unknown,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CheckBackendResult {
+    pub reachable: bool,
+    pub auth: AuthStatus,
+    pub latency_ms: Option<u64>,
+    /// HTTP status of the probe, `None` when the backend could not be reached
+    pub status: Option<u16>,
+    pub error: Option<String>,
+    /// What the backend reports about the model, e.g. TGI's `/info` or ollama's `/api/show`
+    pub model_metadata: Option<Value>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ListModelsResult {
+    pub models: Vec<String>,
+}
+
+/// Incremental text sent while a streamed completion is being generated, the final text is
+/// still returned in [`GetCompletionsResult`] once the backend is done.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct CompletionDeltaParams {
+    pub request_id: Uuid,
+    pub delta: String,
 }
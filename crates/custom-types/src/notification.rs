@@ -0,0 +1,11 @@
+use lsp_types::notification::Notification;
+
+use crate::llm_ls::CompletionDeltaParams;
+
+#[derive(Debug)]
+pub enum CompletionDelta {}
+
+impl Notification for CompletionDelta {
+    type Params = CompletionDeltaParams;
+    const METHOD: &'static str = "llm-ls/completionDelta";
+}
@@ -1,7 +1,8 @@
 use lsp_types::request::Request;
 
 use crate::llm_ls::{
-    AcceptCompletionParams, GetCompletionsParams, GetCompletionsResult, RejectCompletionParams,
+    AcceptCompletionParams, BackendParams, CheckBackendResult, GetCompletionsParams,
+    GetCompletionsResult, ListModelsResult, RejectCompletionParams,
 };
 
 #[derive(Debug)]
@@ -30,3 +31,21 @@ impl Request for RejectCompletion {
     type Result = ();
     const METHOD: &'static str = "llm-ls/rejectCompletion";
 }
+
+#[derive(Debug)]
+pub enum CheckBackend {}
+
+impl Request for CheckBackend {
+    type Params = BackendParams;
+    type Result = CheckBackendResult;
+    const METHOD: &'static str = "llm-ls/checkBackend";
+}
+
+#[derive(Debug)]
+pub enum ListModels {}
+
+impl Request for ListModels {
+    type Params = BackendParams;
+    type Result = ListModelsResult;
+    const METHOD: &'static str = "llm-ls/listModels";
+}
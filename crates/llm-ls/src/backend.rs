@@ -1,11 +1,25 @@
-use super::{Generation, NAME, VERSION};
-use custom_types::llm_ls::{Backend, Ide};
-use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION, USER_AGENT};
-use serde::{Deserialize, Serialize};
+use super::{Generation, Prompt};
+use custom_types::llm_ls::{Backend, FimParams, Ide};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::{Method, StatusCode};
+use serde::Deserialize;
 use serde_json::{json, Map, Value};
+use std::collections::HashMap;
 use std::fmt::Display;
+use std::sync::Arc;
 
-use crate::error::{Error, Result};
+use crate::error::{body_excerpt, Error, Result};
+
+mod azure;
+mod custom;
+mod huggingface;
+mod llamacpp;
+mod ollama;
+mod openai;
+
+pub use azure::AzureOpenAIError;
+pub(crate) use huggingface::build_api_headers;
+pub use openai::OpenAIError;
 
 #[derive(Debug, Deserialize)]
 pub struct APIError {
@@ -32,229 +46,580 @@ pub(crate) enum APIResponse {
     Error(APIError),
 }
 
-fn build_tgi_headers(api_token: Option<&String>, ide: Ide) -> Result<HeaderMap> {
-    let mut headers = HeaderMap::new();
-    let user_agent = format!("{NAME}/{VERSION}; rust/unknown; ide/{ide:?}");
-    headers.insert(USER_AGENT, HeaderValue::from_str(&user_agent)?);
+/// A completion API llm-ls knows how to talk to.
+///
+/// Implementations are stored in a [`BackendRegistry`] and looked up by the `backend` tag of the
+/// completion request, adding a provider means implementing this trait and registering it.
+/// Providers living outside of llm-ls are registered under the `name` of
+/// [`Backend::Extension`], whose `config` their factory can read.
+pub trait CompletionBackend: Send + Sync {
+    /// Completes the configured url with the route of the completion endpoint, only called when
+    /// `disable_url_path_completion` is not set.
+    fn build_url(&self, url: String, model: &str, stream: bool, native_fim: bool) -> String;
+
+    /// The configured url when `disable_url_path_completion` is set, for backends that need query
+    /// parameters whatever the route.
+    fn verbatim_url(&self, url: String) -> String {
+        url
+    }
 
-    if let Some(api_token) = api_token {
-        headers.insert(
-            AUTHORIZATION,
-            HeaderValue::from_str(&format!("Bearer {api_token}"))?,
-        );
+    fn build_headers(&self, api_token: Option<&String>, ide: Ide) -> Result<HeaderMap>;
+
+    fn build_body(
+        &self,
+        model: String,
+        prompt: &Prompt,
+        fim: &FimParams,
+        request_body: Map<String, Value>,
+        stream: bool,
+    ) -> Map<String, Value>;
+
+    /// Adds the stop sequences to the body unless the request body already has some.
+    fn insert_stop(&self, body: &mut Map<String, Value>, stop: &[String]) {
+        if !stop.is_empty() {
+            body.entry("stop").or_insert_with(|| json!(stop));
+        }
     }
 
-    Ok(headers)
-}
+    fn parse_generations(&self, text: &str) -> Result<Vec<Generation>>;
 
-fn parse_tgi_text(text: &str) -> Result<Vec<Generation>> {
-    match serde_json::from_str(text)? {
-        APIResponse::Generation(gen) => Ok(vec![gen]),
-        APIResponse::Generations(_) => Err(Error::InvalidBackend),
-        APIResponse::Error(err) => Err(Error::Tgi(err)),
+    /// Parses the payload of a single streamed event, returning `None` when it does not carry
+    /// generated text. Defaults to backends streaming one complete response object per event.
+    fn parse_stream_event(&self, data: &str) -> Result<Option<String>> {
+        self.parse_generations(data).map(first_generated_text)
     }
-}
 
-fn build_api_headers(api_token: Option<&String>, ide: Ide) -> Result<HeaderMap> {
-    build_tgi_headers(api_token, ide)
-}
+    /// The part of the text accumulated so far that is forwarded to the client while the
+    /// response is streamed. It must only grow as more text comes in.
+    fn stream_preview<'a>(&self, generated_text: &'a str) -> &'a str {
+        generated_text
+    }
+
+    /// Post processes the text accumulated from a streamed response once it is done.
+    fn finish_stream(&self, generated_text: String) -> String {
+        generated_text
+    }
 
-fn parse_api_text(text: &str) -> Result<Vec<Generation>> {
-    match serde_json::from_str(text)? {
-        APIResponse::Generation(gen) => Ok(vec![gen]),
-        APIResponse::Generations(gens) => Ok(gens),
-        APIResponse::Error(err) => Err(Error::InferenceApi(err)),
+    /// A cheap request checking that the backend is reachable and accepts the credentials,
+    /// ideally returning metadata about the model. Defaults to getting the configured url.
+    fn probe_request(&self, url: String, _model: &str) -> ProbeRequest {
+        ProbeRequest::get(url)
+    }
+
+    /// Extracts the metadata of `model` from the response to the probe request.
+    fn model_metadata(&self, response: Value, _model: &str) -> Option<Value> {
+        Some(response)
+    }
+
+    /// The number of tokens the model accepts as input, read from its metadata.
+    fn context_window(&self, _metadata: &Value) -> Option<usize> {
+        None
+    }
+
+    /// The tokens of the discovered context window left for the generation, for backends whose
+    /// context window counts the generated tokens as well as the prompt.
+    fn generation_reserve(&self, _request_body: &Map<String, Value>) -> usize {
+        0
+    }
+
+    /// The request listing the models served by the backend, `None` when it has no such API.
+    fn models_request(&self, _url: String) -> Option<ProbeRequest> {
+        None
+    }
+
+    fn parse_models(&self, _text: &str) -> Result<Vec<String>> {
+        Ok(vec![])
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct LlamaCppGeneration {
-    content: String,
+/// A request sent to a backend outside of completions, e.g. to check its configuration.
+pub struct ProbeRequest {
+    pub method: Method,
+    pub url: String,
+    pub body: Option<Value>,
 }
 
-impl From<LlamaCppGeneration> for Generation {
-    fn from(value: LlamaCppGeneration) -> Self {
-        Generation {
-            generated_text: value.content,
+impl ProbeRequest {
+    pub fn get(url: String) -> Self {
+        Self {
+            method: Method::GET,
+            url,
+            body: None,
         }
     }
-}
 
-#[derive(Debug, Deserialize)]
-#[serde(untagged)]
-enum LlamaCppAPIResponse {
-    Generation(LlamaCppGeneration),
-    Error(APIError),
+    pub fn post(url: String, body: Value) -> Self {
+        Self {
+            method: Method::POST,
+            url,
+            body: Some(body),
+        }
+    }
 }
 
-fn build_llamacpp_headers() -> HeaderMap {
-    HeaderMap::new()
+type BackendFactory = Box<dyn Fn(&Backend) -> Result<Arc<dyn CompletionBackend>> + Send + Sync>;
+
+/// The tags of the backends llm-ls implements, which extensions can't be named after.
+const BUILTIN_TAGS: &[&str] = &[
+    "azureopenai",
+    "custom",
+    "huggingface",
+    "llamacpp",
+    "ollama",
+    "openai",
+    "openaichat",
+    "tgi",
+];
+
+/// The completion backends available to the server, keyed by their `backend` tag.
+pub struct BackendRegistry {
+    backends: HashMap<String, BackendFactory>,
 }
 
-fn parse_llamacpp_text(text: &str) -> Result<Vec<Generation>> {
-    match serde_json::from_str(text)? {
-        LlamaCppAPIResponse::Generation(gen) => Ok(vec![gen.into()]),
-        LlamaCppAPIResponse::Error(err) => Err(Error::LlamaCpp(err)),
+impl BackendRegistry {
+    pub fn empty() -> Self {
+        Self {
+            backends: HashMap::new(),
+        }
+    }
+
+    /// Registers `backend` under `tag`, replacing any backend previously registered with it.
+    ///
+    /// Fails when `tag` is the tag of a builtin backend.
+    pub fn register(
+        &mut self,
+        tag: impl Into<String>,
+        backend: impl CompletionBackend + 'static,
+    ) -> Result<()> {
+        let backend: Arc<dyn CompletionBackend> = Arc::new(backend);
+        self.register_factory(tag, move |_| Ok(backend.clone()))
+    }
+
+    /// Registers a backend that is built from the fields of its `backend` variant for every
+    /// request, for backends whose behaviour depends on the request's configuration.
+    ///
+    /// Fails when `tag` is the tag of a builtin backend.
+    pub fn register_factory(
+        &mut self,
+        tag: impl Into<String>,
+        factory: impl Fn(&Backend) -> Result<Arc<dyn CompletionBackend>> + Send + Sync + 'static,
+    ) -> Result<()> {
+        let tag = tag.into();
+        if BUILTIN_TAGS.contains(&tag.as_str()) {
+            return Err(Error::ReservedBackendName(tag));
+        }
+        self.backends.insert(tag, Box::new(factory));
+        Ok(())
+    }
+
+    fn register_builtin(
+        &mut self,
+        tag: &'static str,
+        factory: impl Fn(&Backend) -> Result<Arc<dyn CompletionBackend>> + Send + Sync + 'static,
+    ) {
+        self.backends.insert(tag.to_owned(), Box::new(factory));
     }
-}
 
-#[derive(Debug, Serialize, Deserialize)]
-struct OllamaGeneration {
-    response: String,
+    pub(crate) fn get(&self, backend: &Backend) -> Result<Arc<dyn CompletionBackend>> {
+        let unknown = || Error::UnknownBackend(backend.tag().to_owned());
+        // an extension is never routed to a builtin backend
+        if matches!(backend, Backend::Extension { .. }) && BUILTIN_TAGS.contains(&backend.tag()) {
+            return Err(unknown());
+        }
+        let factory = self.backends.get(backend.tag()).ok_or_else(unknown)?;
+        factory(backend)
+    }
 }
 
-impl From<OllamaGeneration> for Generation {
-    fn from(value: OllamaGeneration) -> Self {
-        Generation {
-            generated_text: value.response,
+impl Default for BackendRegistry {
+    fn default() -> Self {
+        let mut registry = Self::empty();
+        registry.register_builtin("azureopenai", |backend| {
+            let backend: Arc<dyn CompletionBackend> =
+                Arc::new(azure::AzureOpenAi::from_backend(backend)?);
+            Ok(backend)
+        });
+        let parsed = custom::ParsedBackends::default();
+        registry.register_builtin("custom", move |backend| {
+            let backend: Arc<dyn CompletionBackend> = parsed.get(backend)?;
+            Ok(backend)
+        });
+        let builtins: [(&'static str, Arc<dyn CompletionBackend>); 6] = [
+            ("huggingface", Arc::new(huggingface::HuggingFace)),
+            ("llamacpp", Arc::new(llamacpp::LlamaCpp)),
+            ("ollama", Arc::new(ollama::Ollama)),
+            ("openai", Arc::new(openai::OpenAi)),
+            ("openaichat", Arc::new(openai::OpenAiChat)),
+            ("tgi", Arc::new(huggingface::Tgi)),
+        ];
+        for (tag, backend) in builtins {
+            registry.register_builtin(tag, move |_| Ok(backend.clone()));
         }
+        registry
     }
 }
 
-#[derive(Debug, Deserialize)]
-#[serde(untagged)]
-enum OllamaAPIResponse {
-    Generation(OllamaGeneration),
-    Error(APIError),
+/// Tokens left for the generation when the request doesn't bound its length.
+const DEFAULT_GENERATION_RESERVE: usize = 256;
+
+/// The generation length set in the request body, or the default reserve when it is unset or
+/// unbounded, e.g. `-1`.
+fn reserve_for(max_tokens: Option<&Value>) -> usize {
+    max_tokens
+        .and_then(Value::as_u64)
+        .filter(|tokens| *tokens > 0)
+        .map_or(DEFAULT_GENERATION_RESERVE, |tokens| tokens as usize)
 }
 
-fn build_ollama_headers() -> HeaderMap {
-    HeaderMap::new()
+/// Strips the first of `routes` the url ends with, as well as trailing slashes, to get the root
+/// of the API from a url that may point to the completion endpoint.
+fn base_url(url: &str, routes: &[&str]) -> String {
+    let url = url.trim_end_matches('/');
+    let url = routes
+        .iter()
+        .find_map(|route| url.strip_suffix(route))
+        .unwrap_or(url);
+    url.trim_end_matches('/').to_owned()
 }
 
-fn parse_ollama_text(text: &str) -> Result<Vec<Generation>> {
-    match serde_json::from_str(text)? {
-        OllamaAPIResponse::Generation(gen) => Ok(vec![gen.into()]),
-        OllamaAPIResponse::Error(err) => Err(Error::Ollama(err)),
+/// Adds the user configured headers, overriding the backend's ones.
+pub(crate) fn insert_extra_headers(
+    headers: &mut HeaderMap,
+    extra_headers: &HashMap<String, String>,
+) -> Result<()> {
+    for (name, value) in extra_headers {
+        headers.insert(
+            HeaderName::from_bytes(name.as_bytes())?,
+            HeaderValue::from_str(value)?,
+        );
+    }
+    Ok(())
+}
+
+/// Appends `route` to `url` unless it already ends with it.
+fn push_route(mut url: String, route: &str) -> String {
+    if url.ends_with(&format!("/{route}")) {
+        url
+    } else if url.ends_with('/') {
+        url.push_str(route);
+        url
+    } else {
+        url.push('/');
+        url.push_str(route);
+        url
     }
 }
 
-#[derive(Debug, Deserialize)]
-struct OpenAIGenerationChoice {
-    text: String,
+/// Converts a response with a non success status to an error, preferring the error returned by
+/// the backend when it can be parsed and the status has no dedicated error.
+pub(crate) fn status_error(
+    backend: &dyn CompletionBackend,
+    status: StatusCode,
+    body: &str,
+) -> Error {
+    if let Some(err) = Error::from_status(status, body) {
+        return err;
+    }
+    match backend.parse_generations(body) {
+        Ok(_) | Err(Error::SerdeJson(_)) => Error::UnexpectedStatus {
+            status,
+            body: body_excerpt(body),
+        },
+        Err(err) => err,
+    }
 }
 
-impl From<OpenAIGenerationChoice> for Generation {
-    fn from(value: OpenAIGenerationChoice) -> Self {
-        Generation {
-            generated_text: value.text,
-        }
-    }
+fn first_generated_text(generations: Vec<Generation>) -> Option<String> {
+    generations.into_iter().next().map(|g| g.generated_text)
 }
 
-#[derive(Debug, Deserialize)]
-struct OpenAIGeneration {
-    choices: Vec<OpenAIGenerationChoice>,
+/// Parses a single line of a streamed response, handling both SSE (`data: {...}`) and
+/// newline-delimited JSON framing.
+///
+/// Returns `None` for lines that do not carry generated text, e.g. SSE comments, keep-alives,
+/// special tokens or the `[DONE]` sentinel.
+pub(crate) fn parse_stream_line(
+    backend: &dyn CompletionBackend,
+    line: &str,
+) -> Result<Option<String>> {
+    let data = match line.strip_prefix("data:") {
+        Some(data) => data.trim_start(),
+        None if line.starts_with('{') => line,
+        None => return Ok(None),
+    };
+    if data.is_empty() || data == "[DONE]" {
+        return Ok(None);
+    }
+    backend.parse_stream_event(data)
 }
 
-#[derive(Debug, Deserialize)]
-#[serde(untagged)]
-enum OpenAIErrorLoc {
-    String(String),
-    Int(u32),
+/// Splits a streamed response into lines, whatever the boundaries of its chunks, and accumulates
+/// the text they carry.
+pub(crate) struct StreamReader<'a> {
+    backend: &'a dyn CompletionBackend,
+    /// The start of the line that wasn't terminated yet
+    buffer: Vec<u8>,
+    generated_text: String,
 }
 
-impl Display for OpenAIErrorLoc {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            OpenAIErrorLoc::String(s) => s.fmt(f),
-            OpenAIErrorLoc::Int(i) => i.fmt(f),
+impl<'a> StreamReader<'a> {
+    pub(crate) fn new(backend: &'a dyn CompletionBackend) -> Self {
+        Self {
+            backend,
+            buffer: vec![],
+            generated_text: String::new(),
         }
     }
-}
 
-#[derive(Debug, Deserialize)]
-struct OpenAIErrorDetail {
-    loc: OpenAIErrorLoc,
-    msg: String,
-    r#type: String,
-}
+    /// Reads the lines the chunk terminates.
+    pub(crate) fn push(&mut self, chunk: &[u8]) -> Result<()> {
+        self.buffer.extend_from_slice(chunk);
+        while let Some(idx) = self.buffer.iter().position(|b| *b == b'\n') {
+            let line = self.buffer.drain(..=idx).collect::<Vec<_>>();
+            self.read_line(&line)?;
+        }
+        Ok(())
+    }
 
-#[derive(Debug, Deserialize)]
-pub struct OpenAIError {
-    detail: Vec<OpenAIErrorDetail>,
-}
+    /// Reads the last line even if the backend did not terminate it.
+    pub(crate) fn finish(&mut self) -> Result<()> {
+        let line = std::mem::take(&mut self.buffer);
+        self.read_line(&line)
+    }
 
-impl Display for OpenAIError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        for (i, item) in self.detail.iter().enumerate() {
-            if i != 0 {
-                writeln!(f)?;
-            }
-            write!(f, "{}: {} ({})", item.loc, item.msg, item.r#type)?;
+    fn read_line(&mut self, line: &[u8]) -> Result<()> {
+        let line = String::from_utf8_lossy(line);
+        if let Some(delta) = parse_stream_line(self.backend, line.trim())? {
+            self.generated_text.push_str(&delta);
         }
         Ok(())
     }
-}
 
-#[derive(Debug, Deserialize)]
-#[serde(untagged)]
-enum OpenAIAPIResponse {
-    Generation(OpenAIGeneration),
-    Error(OpenAIError),
-}
+    /// The text generated so far to forward to the client.
+    pub(crate) fn preview(&self) -> &str {
+        self.backend.stream_preview(&self.generated_text)
+    }
 
-fn build_openai_headers(api_token: Option<&String>, ide: Ide) -> Result<HeaderMap> {
-    build_api_headers(api_token, ide)
+    pub(crate) fn into_generated_text(self) -> String {
+        self.backend.finish_stream(self.generated_text)
+    }
 }
 
-fn parse_openai_text(text: &str) -> Result<Vec<Generation>> {
-    match serde_json::from_str(text)? {
-        OpenAIAPIResponse::Generation(completion) => {
-            Ok(completion.choices.into_iter().map(|x| x.into()).collect())
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registry_covers_every_backend() {
+        let registry = BackendRegistry::default();
+        let url = "http://localhost:8080".to_owned();
+        let backends = [
+            Backend::AzureOpenAi {
+                url: url.clone(),
+                deployment: None,
+                api_version: "2024-02-01".to_owned(),
+                chat: false,
+            },
+            Backend::HuggingFace { url: url.clone() },
+            Backend::LlamaCpp { url: url.clone() },
+            Backend::Ollama { url: url.clone() },
+            Backend::OpenAi { url: url.clone() },
+            Backend::OpenAiChat { url: url.clone() },
+            Backend::Tgi { url },
+        ];
+        for backend in backends {
+            assert!(
+                registry.get(&backend).is_ok(),
+                "{} is not registered",
+                backend.tag()
+            );
         }
-        OpenAIAPIResponse::Error(err) => Err(Error::OpenAI(err)),
+        assert!(matches!(
+            BackendRegistry::empty().get(&Backend::default()),
+            Err(Error::UnknownBackend(tag)) if tag == "huggingface"
+        ));
     }
-}
 
-pub(crate) fn build_body(
-    backend: &Backend,
-    model: String,
-    prompt: String,
-    mut request_body: Map<String, Value>,
-) -> Map<String, Value> {
-    match backend {
-        Backend::HuggingFace { .. } | Backend::Tgi { .. } => {
-            request_body.insert("inputs".to_owned(), Value::String(prompt));
-            if let Some(Value::Object(params)) = request_body.get_mut("parameters") {
-                params.insert("return_full_text".to_owned(), Value::Bool(false));
-            } else {
-                let params = json!({ "parameters": { "return_full_text": false } });
-                request_body.insert("parameters".to_owned(), params);
-            }
+    /// A provider as it would be implemented outside of llm-ls.
+    struct Extension {
+        route: String,
+    }
+
+    impl CompletionBackend for Extension {
+        fn build_url(&self, url: String, _model: &str, _stream: bool, _native_fim: bool) -> String {
+            push_route(url, &self.route)
         }
-        Backend::LlamaCpp { .. } => {
-            request_body.insert("prompt".to_owned(), Value::String(prompt));
+
+        fn build_headers(&self, _api_token: Option<&String>, _ide: Ide) -> Result<HeaderMap> {
+            Ok(HeaderMap::new())
         }
-        Backend::Ollama { .. } | Backend::OpenAi { .. } => {
-            request_body.insert("prompt".to_owned(), Value::String(prompt));
-            request_body.insert("model".to_owned(), Value::String(model));
-            request_body.insert("stream".to_owned(), Value::Bool(false));
+
+        fn build_body(
+            &self,
+            _model: String,
+            prompt: &Prompt,
+            fim: &FimParams,
+            mut request_body: Map<String, Value>,
+            _stream: bool,
+        ) -> Map<String, Value> {
+            request_body.insert("input".to_owned(), Value::String(prompt.render(fim)));
+            request_body
         }
-    };
-    request_body
-}
 
-pub(crate) fn build_headers(
-    backend: &Backend,
-    api_token: Option<&String>,
-    ide: Ide,
-) -> Result<HeaderMap> {
-    match backend {
-        Backend::HuggingFace { .. } => build_api_headers(api_token, ide),
-        Backend::LlamaCpp { .. } => Ok(build_llamacpp_headers()),
-        Backend::Ollama { .. } => Ok(build_ollama_headers()),
-        Backend::OpenAi { .. } => build_openai_headers(api_token, ide),
-        Backend::Tgi { .. } => build_tgi_headers(api_token, ide),
+        fn parse_generations(&self, text: &str) -> Result<Vec<Generation>> {
+            Ok(vec![Generation {
+                generated_text: text.to_owned(),
+            }])
+        }
+    }
+
+    #[test]
+    fn test_registry_routes_extensions() {
+        let mut registry = BackendRegistry::default();
+        registry
+            .register_factory("acme", |backend| {
+                let Backend::Extension { config, .. } = backend else {
+                    return Err(Error::UnknownBackend(backend.tag().to_owned()));
+                };
+                let route = config["route"].as_str().unwrap_or("generate").to_owned();
+                let backend: Arc<dyn CompletionBackend> = Arc::new(Extension { route });
+                Ok(backend)
+            })
+            .unwrap();
+        let backend: Backend = serde_json::from_value(json!({
+            "backend": "extension",
+            "name": "acme",
+            "url": "http://localhost:8080",
+            "config": { "route": "v2/complete" },
+        }))
+        .unwrap();
+        assert_eq!(backend.tag(), "acme");
+        let extension = registry.get(&backend).unwrap();
+        assert_eq!(
+            extension.build_url(backend.url(), "model", false, false),
+            "http://localhost:8080/v2/complete"
+        );
+        let unknown: Backend = serde_json::from_value(json!({
+            "backend": "extension",
+            "name": "unknown",
+            "url": "http://localhost:8080",
+        }))
+        .unwrap();
+        assert!(matches!(
+            registry.get(&unknown),
+            Err(Error::UnknownBackend(tag)) if tag == "unknown"
+        ));
+    }
+
+    #[test]
+    fn test_registry_keeps_builtins() {
+        let mut registry = BackendRegistry::default();
+        let route = "generate".to_owned();
+        assert!(matches!(
+            registry.register("openai", Extension { route }),
+            Err(Error::ReservedBackendName(tag)) if tag == "openai"
+        ));
+        let backend: Backend = serde_json::from_value(json!({
+            "backend": "extension",
+            "name": "openai",
+            "url": "http://localhost:8080",
+        }))
+        .unwrap();
+        assert!(matches!(
+            registry.get(&backend),
+            Err(Error::UnknownBackend(tag)) if tag == "openai"
+        ));
+    }
+
+    #[test]
+    fn test_base_url() {
+        let routes = ["/v1/chat/completions", "/v1/completions", "/v1"];
+        assert_eq!(base_url("http://a/v1/completions", &routes), "http://a");
+        assert_eq!(base_url("http://a/v1/", &routes), "http://a");
+        assert_eq!(base_url("http://a/", &routes), "http://a");
+    }
+
+    #[test]
+    fn test_push_route() {
+        assert_eq!(
+            push_route("http://a".to_owned(), "infill"),
+            "http://a/infill"
+        );
+        assert_eq!(
+            push_route("http://a/".to_owned(), "infill"),
+            "http://a/infill"
+        );
+        assert_eq!(
+            push_route("http://a/infill".to_owned(), "infill"),
+            "http://a/infill"
+        );
     }
-}
 
-pub(crate) fn parse_generations(backend: &Backend, text: &str) -> Result<Vec<Generation>> {
-    match backend {
-        Backend::HuggingFace { .. } => parse_api_text(text),
-        Backend::LlamaCpp { .. } => parse_llamacpp_text(text),
-        Backend::Ollama { .. } => parse_ollama_text(text),
-        Backend::OpenAi { .. } => parse_openai_text(text),
-        Backend::Tgi { .. } => parse_tgi_text(text),
+    /// Reads the stream in chunks of one byte, then split in two at every byte, so that chunks
+    /// end in the middle of lines.
+    fn read_stream(backend: &dyn CompletionBackend, stream: &str) -> String {
+        let read = |chunks: Vec<&[u8]>| {
+            let mut reader = StreamReader::new(backend);
+            for chunk in chunks {
+                reader.push(chunk).unwrap();
+            }
+            reader.finish().unwrap();
+            reader.into_generated_text()
+        };
+        let bytes = stream.as_bytes();
+        let text = read(bytes.chunks(1).collect());
+        for split in 0..=bytes.len() {
+            let (first, second) = bytes.split_at(split);
+            assert_eq!(read(vec![first, second]), text, "split at {split}");
+        }
+        text
+    }
+
+    #[test]
+    fn test_stream_sse() {
+        let stream = concat!(
+            ": keep-alive\n",
+            "\n",
+            "data: {\"choices\":[{\"text\":\"fn \"}]}\n",
+            "\n",
+            "data:{\"choices\":[{\"text\":\"main()\"}]}\r\n",
+            "\r\n",
+            "data: [DONE]\n",
+            "\n",
+        );
+        assert_eq!(read_stream(&openai::OpenAi, stream), "fn main()");
+    }
+
+    #[test]
+    fn test_stream_ndjson() {
+        // the last line isn't terminated
+        let stream = concat!(
+            "{\"response\":\"fn \",\"done\":false}\n",
+            "{\"response\":\"café()\",\"done\":false}\n",
+            "{\"response\":\"\",\"done\":true}",
+        );
+        assert_eq!(read_stream(&ollama::Ollama, stream), "fn café()");
+
+        let stream = concat!(
+            "{\"content\":\"let \",\"stop\":false}\n",
+            "{\"content\":\"x\",\"stop\":false}\n",
+            "{\"content\":\"\",\"stop\":true}\n",
+        );
+        assert_eq!(read_stream(&llamacpp::LlamaCpp, stream), "let x");
+        let sse = concat!(
+            "data: {\"content\":\"let \",\"stop\":false}\n\n",
+            "data: {\"content\":\"x\",\"stop\":true}\n\n",
+        );
+        assert_eq!(read_stream(&llamacpp::LlamaCpp, sse), "let x");
+    }
+
+    #[test]
+    fn test_stream_tgi_special_tokens() {
+        let stream = concat!(
+            "data:{\"token\":{\"id\":1,\"text\":\"return\",\"special\":false}}\n\n",
+            "data:{\"token\":{\"id\":2,\"text\":\" 0\",\"special\":false}}\n\n",
+            "data:{\"token\":{\"id\":0,\"text\":\"<|endoftext|>\",\"special\":true}}\n\n",
+        );
+        assert_eq!(read_stream(&huggingface::Tgi, stream), "return 0");
     }
 }
@@ -0,0 +1,209 @@
+use custom_types::llm_ls::{Backend, FimParams, Ide};
+use reqwest::header::{HeaderMap, HeaderValue};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::fmt::Display;
+
+use super::openai::{OpenAi, OpenAiChat};
+use super::{build_api_headers, CompletionBackend, ProbeRequest};
+use crate::error::{Error, Result};
+use crate::{Generation, Prompt};
+
+const API_KEY: &str = "api-key";
+
+#[derive(Debug, Deserialize)]
+pub struct AzureOpenAIError {
+    #[serde(default)]
+    code: Option<String>,
+    message: String,
+}
+
+impl Display for AzureOpenAIError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.code {
+            Some(code) => write!(f, "{code}: {}", self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct AzureOpenAIErrorResponse {
+    error: AzureOpenAIError,
+}
+
+/// Azure has its own error format, every other response follows the OpenAI API.
+fn check_error(text: &str) -> Result<()> {
+    match serde_json::from_str::<AzureOpenAIErrorResponse>(text) {
+        Ok(res) => Err(Error::AzureOpenAI(res.error)),
+        Err(_) => Ok(()),
+    }
+}
+
+/// The resource endpoint, e.g. `https://<resource>.openai.azure.com`, of a url that may point to
+/// a deployment.
+fn resource_url(url: &str) -> &str {
+    url.split_once("/openai")
+        .map_or(url, |(resource, _)| resource)
+        .trim_end_matches('/')
+}
+
+/// An Azure OpenAI deployment, authenticated with the `api-key` header.
+pub(crate) struct AzureOpenAi {
+    deployment: Option<String>,
+    api_version: String,
+    chat: bool,
+}
+
+impl AzureOpenAi {
+    pub(crate) fn from_backend(backend: &Backend) -> Result<Self> {
+        let Backend::AzureOpenAi {
+            deployment,
+            api_version,
+            chat,
+            ..
+        } = backend
+        else {
+            return Err(Error::UnknownBackend(backend.tag().to_owned()));
+        };
+        Ok(Self {
+            deployment: deployment.clone(),
+            api_version: api_version.clone(),
+            chat: *chat,
+        })
+    }
+
+    /// Deployments serve the OpenAI API, only the url and the authentication differ.
+    fn openai(&self) -> &'static dyn CompletionBackend {
+        if self.chat {
+            &OpenAiChat
+        } else {
+            &OpenAi
+        }
+    }
+
+    fn push_api_version(&self, mut url: String) -> String {
+        if !url.contains("api-version=") {
+            url.push(if url.contains('?') { '&' } else { '?' });
+            url.push_str("api-version=");
+            url.push_str(&self.api_version);
+        }
+        url
+    }
+}
+
+impl CompletionBackend for AzureOpenAi {
+    fn build_url(&self, url: String, model: &str, _stream: bool, _native_fim: bool) -> String {
+        if url.contains("/openai/deployments/") {
+            return self.push_api_version(url);
+        }
+        let deployment = self.deployment.as_deref().unwrap_or(model);
+        let route = if self.chat {
+            "chat/completions"
+        } else {
+            "completions"
+        };
+        self.push_api_version(format!(
+            "{}/openai/deployments/{deployment}/{route}",
+            resource_url(&url)
+        ))
+    }
+
+    /// Azure rejects the requests without an `api-version`
+    fn verbatim_url(&self, url: String) -> String {
+        self.push_api_version(url)
+    }
+
+    fn build_headers(&self, api_token: Option<&String>, ide: Ide) -> Result<HeaderMap> {
+        let mut headers = build_api_headers(None, ide)?;
+        if let Some(api_token) = api_token {
+            headers.insert(API_KEY, HeaderValue::from_str(api_token)?);
+        }
+        Ok(headers)
+    }
+
+    fn build_body(
+        &self,
+        model: String,
+        prompt: &Prompt,
+        fim: &FimParams,
+        request_body: Map<String, Value>,
+        stream: bool,
+    ) -> Map<String, Value> {
+        self.openai()
+            .build_body(model, prompt, fim, request_body, stream)
+    }
+
+    fn parse_generations(&self, text: &str) -> Result<Vec<Generation>> {
+        check_error(text)?;
+        self.openai().parse_generations(text)
+    }
+
+    fn parse_stream_event(&self, data: &str) -> Result<Option<String>> {
+        check_error(data)?;
+        self.openai().parse_stream_event(data)
+    }
+
+    fn stream_preview<'a>(&self, generated_text: &'a str) -> &'a str {
+        self.openai().stream_preview(generated_text)
+    }
+
+    fn finish_stream(&self, generated_text: String) -> String {
+        self.openai().finish_stream(generated_text)
+    }
+
+    fn probe_request(&self, url: String, _model: &str) -> ProbeRequest {
+        ProbeRequest::get(self.push_api_version(format!("{}/openai/models", resource_url(&url))))
+    }
+
+    /// The probe lists the base models of the resource, which are not the deployments
+    fn model_metadata(&self, _response: Value, _model: &str) -> Option<Value> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_url() {
+        let backend = AzureOpenAi {
+            deployment: None,
+            api_version: "2024-02-01".to_owned(),
+            chat: false,
+        };
+        assert_eq!(
+            backend.build_url(
+                "https://r.openai.azure.com/".to_owned(),
+                "gpt",
+                false,
+                false
+            ),
+            "https://r.openai.azure.com/openai/deployments/gpt/completions?api-version=2024-02-01"
+        );
+        let url = "https://r.openai.azure.com/openai/deployments/d/chat/completions?api-version=2024-06-01";
+        assert_eq!(backend.build_url(url.to_owned(), "gpt", false, false), url);
+    }
+
+    #[test]
+    fn test_verbatim_url() {
+        let backend = AzureOpenAi {
+            deployment: None,
+            api_version: "2024-02-01".to_owned(),
+            chat: false,
+        };
+        assert_eq!(
+            backend.verbatim_url(
+                "https://gateway.example.com/openai/deployments/d/completions".to_owned()
+            ),
+            "https://gateway.example.com/openai/deployments/d/completions?api-version=2024-02-01"
+        );
+        assert_eq!(
+            backend.verbatim_url("https://gateway.example.com/completions?team=a".to_owned()),
+            "https://gateway.example.com/completions?team=a&api-version=2024-02-01"
+        );
+        let url = "https://gateway.example.com/completions?api-version=2024-06-01";
+        assert_eq!(backend.verbatim_url(url.to_owned()), url);
+    }
+}
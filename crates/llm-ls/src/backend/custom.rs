@@ -0,0 +1,439 @@
+use custom_types::llm_ls::{Backend, FimParams, Ide};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tracing::warn;
+
+use super::{build_api_headers, CompletionBackend};
+use crate::error::{Error, Result};
+use crate::{Generation, Prompt};
+
+const API_TOKEN_PLACEHOLDER: &str = "{{api_token}}";
+const MAX_PARSED_BACKENDS: usize = 16;
+
+/// The values the placeholders of a body template are replaced with.
+struct TemplateContext<'a> {
+    prompt: String,
+    prefix: &'a str,
+    suffix: &'a str,
+    model: &'a str,
+    stream: bool,
+    request_body: &'a Map<String, Value>,
+}
+
+impl TemplateContext<'_> {
+    /// Returns `None` for `request_body` fields that are not set.
+    fn value(&self, name: &str) -> Option<Value> {
+        match name {
+            "prompt" => Some(Value::String(self.prompt.clone())),
+            "prefix" => Some(Value::String(self.prefix.to_owned())),
+            "suffix" => Some(Value::String(self.suffix.to_owned())),
+            "model" => Some(Value::String(self.model.to_owned())),
+            "stream" => Some(Value::Bool(self.stream)),
+            "request_body" => Some(Value::Object(self.request_body.clone())),
+            _ => name
+                .strip_prefix("request_body.")
+                .and_then(|field| self.request_body.get(field).cloned()),
+        }
+    }
+}
+
+fn is_valid_placeholder(name: &str) -> bool {
+    matches!(
+        name,
+        "prompt" | "prefix" | "suffix" | "model" | "stream" | "request_body"
+    ) || name
+        .strip_prefix("request_body.")
+        .is_some_and(|field| !field.is_empty())
+}
+
+enum TemplatePart<'a> {
+    Text(&'a str),
+    /// The trimmed name of a `{{name}}` placeholder
+    Placeholder(&'a str),
+}
+
+fn split_placeholders(text: &str) -> Vec<TemplatePart> {
+    let mut parts = vec![];
+    let mut rest = text;
+    while let Some(start) = rest.find("{{") {
+        let Some(end) = rest[start..].find("}}") else {
+            break;
+        };
+        parts.push(TemplatePart::Text(&rest[..start]));
+        parts.push(TemplatePart::Placeholder(
+            rest[start + 2..start + end].trim(),
+        ));
+        rest = &rest[start + end + 2..];
+    }
+    parts.push(TemplatePart::Text(rest));
+    parts
+}
+
+fn validate_template(value: &Value) -> Result<()> {
+    match value {
+        Value::String(text) => {
+            let unknown = split_placeholders(text)
+                .into_iter()
+                .find_map(|part| match part {
+                    TemplatePart::Placeholder(name) if !is_valid_placeholder(name) => Some(name),
+                    _ => None,
+                });
+            match unknown {
+                Some(name) => Err(Error::InvalidCustomBackend(format!(
+                    "unknown placeholder `{{{{{name}}}}}` in body template"
+                ))),
+                None => Ok(()),
+            }
+        }
+        Value::Array(items) => items.iter().try_for_each(validate_template),
+        Value::Object(map) => map.values().try_for_each(validate_template),
+        _ => Ok(()),
+    }
+}
+
+/// Renders a template value, returning `None` when it is a lone placeholder of an unset
+/// `request_body` field so that the field is left out of the body.
+fn render_template(value: &Value, ctx: &TemplateContext) -> Option<Value> {
+    match value {
+        Value::String(text) => {
+            // a string made of a single placeholder keeps the type of the value it is replaced
+            // with, e.g. numbers or objects from `request_body`
+            if let Some(name) = text
+                .trim()
+                .strip_prefix("{{")
+                .and_then(|text| text.strip_suffix("}}"))
+                .filter(|name| !name.contains("{{"))
+            {
+                return ctx.value(name.trim());
+            }
+            let mut rendered = String::new();
+            for part in split_placeholders(text) {
+                match part {
+                    TemplatePart::Text(text) => rendered.push_str(text),
+                    TemplatePart::Placeholder(name) => match ctx.value(name) {
+                        Some(Value::String(value)) => rendered.push_str(&value),
+                        Some(Value::Null) | None => {}
+                        Some(value) => rendered.push_str(&value.to_string()),
+                    },
+                }
+            }
+            Some(Value::String(rendered))
+        }
+        Value::Array(items) => Some(Value::Array(
+            items
+                .iter()
+                .filter_map(|item| render_template(item, ctx))
+                .collect(),
+        )),
+        Value::Object(map) => Some(Value::Object(render_object(map, ctx))),
+        value => Some(value.clone()),
+    }
+}
+
+fn render_object(map: &Map<String, Value>, ctx: &TemplateContext) -> Map<String, Value> {
+    map.iter()
+        .filter_map(|(key, value)| Some((key.clone(), render_template(value, ctx)?)))
+        .collect()
+}
+
+/// A path to values of a JSON response, either a JSON pointer (`/choices/0/text`) or a dotted
+/// path where `*` matches every element (`choices.*.text` or `choices[*].text`).
+enum JsonPath {
+    Pointer(String),
+    Segments(Vec<String>),
+}
+
+impl JsonPath {
+    fn parse(path: &str) -> Self {
+        if path.starts_with('/') {
+            return Self::Pointer(path.to_owned());
+        }
+        let segments = path
+            .replace('[', ".")
+            .replace(']', "")
+            .split('.')
+            .filter(|segment| !segment.is_empty())
+            .map(ToOwned::to_owned)
+            .collect();
+        Self::Segments(segments)
+    }
+
+    fn select<'a>(&self, value: &'a Value) -> Vec<&'a Value> {
+        match self {
+            Self::Pointer(pointer) => value.pointer(pointer).into_iter().collect(),
+            Self::Segments(segments) => segments.iter().fold(vec![value], |values, segment| {
+                values
+                    .into_iter()
+                    .flat_map(|value| select_segment(value, segment))
+                    .collect()
+            }),
+        }
+    }
+}
+
+fn select_segment<'a>(value: &'a Value, segment: &str) -> Vec<&'a Value> {
+    match (value, segment) {
+        (Value::Array(items), "*") => items.iter().collect(),
+        (Value::Object(map), "*") => map.values().collect(),
+        (Value::Array(items), index) => index
+            .parse::<usize>()
+            .ok()
+            .and_then(|index| items.get(index))
+            .into_iter()
+            .collect(),
+        (Value::Object(map), key) => map.get(key).into_iter().collect(),
+        _ => vec![],
+    }
+}
+
+/// A backend for arbitrary HTTP APIs, described by the fields of [`Backend::Custom`].
+pub(crate) struct Custom {
+    body_template: Map<String, Value>,
+    headers: Vec<(HeaderName, String)>,
+    generated_text_path: JsonPath,
+    error_path: Option<JsonPath>,
+}
+
+impl Custom {
+    pub(crate) fn from_backend(backend: &Backend) -> Result<Self> {
+        let Backend::Custom {
+            body_template,
+            headers,
+            generated_text_path,
+            error_path,
+            ..
+        } = backend
+        else {
+            return Err(Error::InvalidCustomBackend(format!(
+                "expected a custom backend, got `{}`",
+                backend.tag()
+            )));
+        };
+        body_template.values().try_for_each(validate_template)?;
+        let headers = headers
+            .iter()
+            .map(|(name, value)| {
+                let name = HeaderName::from_bytes(name.as_bytes()).map_err(|err| {
+                    Error::InvalidCustomBackend(format!("invalid header name `{name}`: {err}"))
+                })?;
+                Ok((name, value.clone()))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            body_template: body_template.clone(),
+            headers,
+            generated_text_path: JsonPath::parse(generated_text_path),
+            error_path: error_path.as_deref().map(JsonPath::parse),
+        })
+    }
+
+    fn check_error(&self, response: &Value) -> Result<()> {
+        let Some(error_path) = &self.error_path else {
+            return Ok(());
+        };
+        match error_path
+            .select(response)
+            .into_iter()
+            .find(|v| !v.is_null())
+        {
+            Some(Value::String(message)) => Err(Error::Custom(message.clone())),
+            Some(value) => Err(Error::Custom(value.to_string())),
+            None => Ok(()),
+        }
+    }
+
+    fn generated_texts(&self, response: &Value) -> Vec<String> {
+        self.generated_text_path
+            .select(response)
+            .into_iter()
+            .filter_map(|value| value.as_str().map(ToOwned::to_owned))
+            .collect()
+    }
+}
+
+/// Custom backends already parsed, keyed by their serialized configuration, so that templates
+/// are validated and parsed once rather than for every request.
+#[derive(Default)]
+pub(crate) struct ParsedBackends {
+    parsed: Mutex<HashMap<String, Arc<Custom>>>,
+}
+
+impl ParsedBackends {
+    pub(crate) fn get(&self, backend: &Backend) -> Result<Arc<Custom>> {
+        // going through `Value` sorts the keys of the header map
+        let key = serde_json::to_value(backend)?.to_string();
+        let mut parsed = self.parsed.lock().expect("parsed backends lock poisoned");
+        if let Some(custom) = parsed.get(&key) {
+            return Ok(custom.clone());
+        }
+        let custom = Arc::new(Custom::from_backend(backend)?);
+        if parsed.len() >= MAX_PARSED_BACKENDS {
+            parsed.clear();
+        }
+        parsed.insert(key, custom.clone());
+        Ok(custom)
+    }
+}
+
+impl CompletionBackend for Custom {
+    fn build_url(&self, url: String, _model: &str, _stream: bool, _native_fim: bool) -> String {
+        url
+    }
+
+    fn build_headers(&self, api_token: Option<&String>, ide: Ide) -> Result<HeaderMap> {
+        // the token is only sent where the header template puts it, gateways don't all expect a
+        // bearer token and shouldn't receive it unasked
+        let mut headers = build_api_headers(None, ide)?;
+        for (name, value) in &self.headers {
+            let value = match api_token {
+                Some(api_token) => value.replace(API_TOKEN_PLACEHOLDER, api_token),
+                // an empty credential is rejected where no credential may be accepted
+                None if value.contains(API_TOKEN_PLACEHOLDER) => continue,
+                None => value.clone(),
+            };
+            headers.insert(name.clone(), HeaderValue::from_str(&value)?);
+        }
+        Ok(headers)
+    }
+
+    fn build_body(
+        &self,
+        model: String,
+        prompt: &Prompt,
+        fim: &FimParams,
+        request_body: Map<String, Value>,
+        stream: bool,
+    ) -> Map<String, Value> {
+        let prefix = prompt.full_prefix();
+        let ctx = TemplateContext {
+            prompt: prompt.render(fim),
+            prefix: &prefix,
+            suffix: prompt.suffix.as_deref().unwrap_or_default(),
+            model: &model,
+            stream,
+            request_body: &request_body,
+        };
+        render_object(&self.body_template, &ctx)
+    }
+
+    /// The body template decides where the stop sequences go, if anywhere
+    fn insert_stop(&self, _body: &mut Map<String, Value>, _stop: &[String]) {}
+
+    fn parse_generations(&self, text: &str) -> Result<Vec<Generation>> {
+        let response: Value = serde_json::from_str(text)?;
+        self.check_error(&response)?;
+        let generations = self.generated_texts(&response);
+        if generations.is_empty() {
+            warn!("no generated text found in the custom backend response");
+        }
+        Ok(generations
+            .into_iter()
+            .map(|generated_text| Generation { generated_text })
+            .collect())
+    }
+
+    fn parse_stream_event(&self, data: &str) -> Result<Option<String>> {
+        let event: Value = serde_json::from_str(data)?;
+        self.check_error(&event)?;
+        Ok(self.generated_texts(&event).into_iter().next())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_render_template() {
+        let request_body = json!({ "max_new_tokens": 60 });
+        let ctx = TemplateContext {
+            prompt: "<fim_prefix>a<fim_suffix>b<fim_middle>".to_owned(),
+            prefix: "a",
+            suffix: "b",
+            model: "bigcode/starcoder",
+            stream: false,
+            request_body: request_body.as_object().unwrap(),
+        };
+        let template = json!({
+            "input": { "before": "{{prefix}}", "after": "{{ suffix }}" },
+            "target": "models/{{model}}",
+            "max_tokens": "{{request_body.max_new_tokens}}",
+            "temperature": "{{request_body.temperature}}",
+            "stream": "{{stream}}",
+        });
+        assert!(validate_template(&template).is_ok());
+        assert_eq!(
+            render_template(&template, &ctx).unwrap(),
+            json!({
+                "input": { "before": "a", "after": "b" },
+                "target": "models/bigcode/starcoder",
+                "max_tokens": 60,
+                "stream": false,
+            })
+        );
+        assert!(validate_template(&json!({ "input": "{{cursor}}" })).is_err());
+    }
+
+    fn backend(headers: &[(&str, &str)]) -> Backend {
+        serde_json::from_value(json!({
+            "backend": "custom",
+            "url": "http://localhost:8080/generate",
+            "bodyTemplate": { "input": "{{prompt}}" },
+            "headers": headers.iter().copied().collect::<HashMap<_, _>>(),
+            "generatedTextPath": "text",
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_build_headers() {
+        let token = "secret".to_owned();
+        let custom = Custom::from_backend(&backend(&[])).unwrap();
+        let headers = custom.build_headers(Some(&token), Ide::default()).unwrap();
+        assert!(headers.get("authorization").is_none());
+        let custom = Custom::from_backend(&backend(&[("X-Api-Key", "{{api_token}}")])).unwrap();
+        let headers = custom.build_headers(Some(&token), Ide::default()).unwrap();
+        assert_eq!(headers.get("x-api-key").unwrap(), "secret");
+        assert!(headers.get("authorization").is_none());
+        let custom = Custom::from_backend(&backend(&[
+            ("Authorization", "Bearer {{api_token}}"),
+            ("X-Team", "a"),
+        ]))
+        .unwrap();
+        let headers = custom.build_headers(None, Ide::default()).unwrap();
+        assert!(headers.get("authorization").is_none());
+        assert_eq!(headers.get("x-team").unwrap(), "a");
+    }
+
+    #[test]
+    fn test_parsed_backends() {
+        let parsed = ParsedBackends::default();
+        let headers = [("X-Api-Key", "{{api_token}}"), ("X-Team", "a")];
+        let first = parsed.get(&backend(&headers)).unwrap();
+        let reversed = [headers[1], headers[0]];
+        assert!(Arc::ptr_eq(
+            &first,
+            &parsed.get(&backend(&reversed)).unwrap()
+        ));
+        assert!(!Arc::ptr_eq(&first, &parsed.get(&backend(&[])).unwrap()));
+    }
+
+    #[test]
+    fn test_json_path() {
+        let response = json!({ "outputs": [{ "text": "a" }, { "text": "b" }] });
+        let texts = |path| {
+            JsonPath::parse(path)
+                .select(&response)
+                .into_iter()
+                .filter_map(Value::as_str)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(texts("outputs.*.text"), ["a", "b"]);
+        assert_eq!(texts("outputs[1].text"), ["b"]);
+        assert_eq!(texts("/outputs/0/text"), ["a"]);
+        assert!(texts("outputs.*.missing").is_empty());
+    }
+}
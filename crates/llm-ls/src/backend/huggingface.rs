@@ -0,0 +1,202 @@
+use custom_types::llm_ls::{FimParams, Ide};
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION, USER_AGENT};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+
+use super::{base_url, push_route, APIError, APIResponse, CompletionBackend, ProbeRequest};
+use crate::error::{Error, Result};
+use crate::{Generation, Prompt, NAME, VERSION};
+
+pub(crate) fn build_api_headers(api_token: Option<&String>, ide: Ide) -> Result<HeaderMap> {
+    let mut headers = HeaderMap::new();
+    let user_agent = format!("{NAME}/{VERSION}; rust/unknown; ide/{ide:?}");
+    headers.insert(USER_AGENT, HeaderValue::from_str(&user_agent)?);
+
+    if let Some(api_token) = api_token {
+        headers.insert(
+            AUTHORIZATION,
+            HeaderValue::from_str(&format!("Bearer {api_token}"))?,
+        );
+    }
+
+    Ok(headers)
+}
+
+fn build_body(
+    prompt: &Prompt,
+    fim: &FimParams,
+    mut request_body: Map<String, Value>,
+    stream: bool,
+) -> Map<String, Value> {
+    request_body.insert("inputs".to_owned(), Value::String(prompt.render(fim)));
+    if let Some(Value::Object(params)) = request_body.get_mut("parameters") {
+        params.insert("return_full_text".to_owned(), Value::Bool(false));
+    } else {
+        let params = json!({ "parameters": { "return_full_text": false } });
+        request_body.insert("parameters".to_owned(), params);
+    }
+    if stream {
+        request_body.insert("stream".to_owned(), Value::Bool(true));
+    }
+    request_body
+}
+
+/// The generation parameters are nested under `parameters`, which `build_body` always adds.
+fn insert_stop(body: &mut Map<String, Value>, stop: &[String]) {
+    if let Some(Value::Object(params)) = body.get_mut("parameters") {
+        if !stop.is_empty() {
+            params.entry("stop").or_insert_with(|| json!(stop));
+        }
+    }
+}
+
+const TGI_ROUTES: &[&str] = &["/generate_stream", "/generate"];
+
+#[derive(Debug, Deserialize)]
+struct TgiInfo {
+    model_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct TgiStreamToken {
+    text: String,
+    special: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct TgiStreamResponse {
+    token: TgiStreamToken,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum TgiStreamAPIResponse {
+    Token(TgiStreamResponse),
+    Error(APIError),
+}
+
+/// Hugging Face's serverless Inference API.
+pub(crate) struct HuggingFace;
+
+impl CompletionBackend for HuggingFace {
+    fn build_url(&self, url: String, model: &str, _stream: bool, _native_fim: bool) -> String {
+        format!("{url}/models/{model}")
+    }
+
+    fn build_headers(&self, api_token: Option<&String>, ide: Ide) -> Result<HeaderMap> {
+        build_api_headers(api_token, ide)
+    }
+
+    fn build_body(
+        &self,
+        _model: String,
+        prompt: &Prompt,
+        fim: &FimParams,
+        request_body: Map<String, Value>,
+        stream: bool,
+    ) -> Map<String, Value> {
+        build_body(prompt, fim, request_body, stream)
+    }
+
+    fn insert_stop(&self, body: &mut Map<String, Value>, stop: &[String]) {
+        insert_stop(body, stop)
+    }
+
+    fn parse_generations(&self, text: &str) -> Result<Vec<Generation>> {
+        match serde_json::from_str(text)? {
+            APIResponse::Generation(gen) => Ok(vec![gen]),
+            APIResponse::Generations(gens) => Ok(gens),
+            APIResponse::Error(err) => Err(Error::InferenceApi(err)),
+        }
+    }
+
+    fn parse_stream_event(&self, data: &str) -> Result<Option<String>> {
+        match serde_json::from_str(data)? {
+            TgiStreamAPIResponse::Token(res) if res.token.special => Ok(None),
+            TgiStreamAPIResponse::Token(res) => Ok(Some(res.token.text)),
+            TgiStreamAPIResponse::Error(err) => Err(Error::InferenceApi(err)),
+        }
+    }
+
+    fn probe_request(&self, url: String, model: &str) -> ProbeRequest {
+        ProbeRequest::get(format!("{}/status/{model}", base_url(&url, &[])))
+    }
+}
+
+/// A text-generation-inference server.
+pub(crate) struct Tgi;
+
+impl CompletionBackend for Tgi {
+    fn build_url(&self, mut url: String, _model: &str, stream: bool, _native_fim: bool) -> String {
+        if stream && url.ends_with("/generate") {
+            url.push_str("_stream");
+            return url;
+        }
+        let route = if stream {
+            "generate_stream"
+        } else {
+            "generate"
+        };
+        push_route(url, route)
+    }
+
+    fn build_headers(&self, api_token: Option<&String>, ide: Ide) -> Result<HeaderMap> {
+        build_api_headers(api_token, ide)
+    }
+
+    fn build_body(
+        &self,
+        _model: String,
+        prompt: &Prompt,
+        fim: &FimParams,
+        request_body: Map<String, Value>,
+        stream: bool,
+    ) -> Map<String, Value> {
+        build_body(prompt, fim, request_body, stream)
+    }
+
+    fn insert_stop(&self, body: &mut Map<String, Value>, stop: &[String]) {
+        insert_stop(body, stop)
+    }
+
+    fn parse_generations(&self, text: &str) -> Result<Vec<Generation>> {
+        match serde_json::from_str(text)? {
+            APIResponse::Generation(gen) => Ok(vec![gen]),
+            APIResponse::Generations(_) => Err(Error::InvalidBackend),
+            APIResponse::Error(err) => Err(Error::Tgi(err)),
+        }
+    }
+
+    fn parse_stream_event(&self, data: &str) -> Result<Option<String>> {
+        match serde_json::from_str(data)? {
+            TgiStreamAPIResponse::Token(res) if res.token.special => Ok(None),
+            TgiStreamAPIResponse::Token(res) => Ok(Some(res.token.text)),
+            TgiStreamAPIResponse::Error(err) => Err(Error::Tgi(err)),
+        }
+    }
+
+    fn probe_request(&self, url: String, _model: &str) -> ProbeRequest {
+        ProbeRequest::get(format!("{}/info", base_url(&url, TGI_ROUTES)))
+    }
+
+    fn context_window(&self, metadata: &Value) -> Option<usize> {
+        // renamed to `max_input_tokens` in TGI 2.1
+        ["max_input_tokens", "max_input_length"]
+            .iter()
+            .find_map(|key| metadata.get(key)?.as_u64())
+            .map(|tokens| tokens as usize)
+    }
+
+    fn models_request(&self, url: String) -> Option<ProbeRequest> {
+        Some(ProbeRequest::get(format!(
+            "{}/info",
+            base_url(&url, TGI_ROUTES)
+        )))
+    }
+
+    /// TGI serves a single model
+    fn parse_models(&self, text: &str) -> Result<Vec<String>> {
+        let info: TgiInfo = serde_json::from_str(text)?;
+        Ok(vec![info.model_id])
+    }
+}
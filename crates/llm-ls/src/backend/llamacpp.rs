@@ -0,0 +1,145 @@
+use custom_types::llm_ls::{FimParams, Ide};
+use reqwest::header::HeaderMap;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use super::openai::parse_openai_models;
+use super::{base_url, push_route, reserve_for, APIError, CompletionBackend, ProbeRequest};
+use crate::error::{Error, Result};
+use crate::{Generation, Prompt};
+
+#[derive(Debug, Serialize, Deserialize)]
+struct LlamaCppGeneration {
+    content: String,
+}
+
+impl From<LlamaCppGeneration> for Generation {
+    fn from(value: LlamaCppGeneration) -> Self {
+        Generation {
+            generated_text: value.content,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum LlamaCppAPIResponse {
+    Generation(LlamaCppGeneration),
+    Error(APIError),
+}
+
+const LLAMACPP_ROUTES: &[&str] = &["/completions", "/completion", "/infill"];
+
+/// The root of the server, `/infill`, `/props` and `/v1/models` aren't served under the `/v1`
+/// prefix of its OpenAI compatible `/v1/completions`.
+fn server_url(url: &str) -> String {
+    let url = base_url(url, LLAMACPP_ROUTES);
+    match url.strip_suffix("/v1") {
+        Some(root) => root.to_owned(),
+        None => url,
+    }
+}
+
+/// A llama.cpp server, using `/infill` for native fill in the middle.
+pub(crate) struct LlamaCpp;
+
+impl CompletionBackend for LlamaCpp {
+    fn build_url(&self, url: String, _model: &str, _stream: bool, native_fim: bool) -> String {
+        if native_fim {
+            push_route(server_url(&url), "infill")
+        } else {
+            push_route(base_url(&url, LLAMACPP_ROUTES), "completions")
+        }
+    }
+
+    fn build_headers(&self, _api_token: Option<&String>, _ide: Ide) -> Result<HeaderMap> {
+        Ok(HeaderMap::new())
+    }
+
+    fn build_body(
+        &self,
+        _model: String,
+        prompt: &Prompt,
+        fim: &FimParams,
+        mut request_body: Map<String, Value>,
+        stream: bool,
+    ) -> Map<String, Value> {
+        if let Some(suffix) = prompt.native_fim_suffix(fim) {
+            request_body.insert(
+                "input_prefix".to_owned(),
+                Value::String(prompt.full_prefix()),
+            );
+            request_body.insert("input_suffix".to_owned(), Value::String(suffix.to_owned()));
+        } else {
+            request_body.insert("prompt".to_owned(), Value::String(prompt.render(fim)));
+        }
+        request_body.insert("stream".to_owned(), Value::Bool(stream));
+        request_body
+    }
+
+    fn parse_generations(&self, text: &str) -> Result<Vec<Generation>> {
+        match serde_json::from_str(text)? {
+            LlamaCppAPIResponse::Generation(gen) => Ok(vec![gen.into()]),
+            LlamaCppAPIResponse::Error(err) => Err(Error::LlamaCpp(err)),
+        }
+    }
+
+    fn probe_request(&self, url: String, _model: &str) -> ProbeRequest {
+        ProbeRequest::get(format!("{}/props", server_url(&url)))
+    }
+
+    fn context_window(&self, metadata: &Value) -> Option<usize> {
+        let n_ctx = metadata
+            .get("n_ctx")
+            .or_else(|| metadata.get("default_generation_settings")?.get("n_ctx"))?;
+        n_ctx.as_u64().map(|tokens| tokens as usize)
+    }
+
+    /// `n_ctx` is the size of the KV cache, which holds the generated tokens too
+    fn generation_reserve(&self, request_body: &Map<String, Value>) -> usize {
+        reserve_for(request_body.get("n_predict"))
+    }
+
+    fn models_request(&self, url: String) -> Option<ProbeRequest> {
+        Some(ProbeRequest::get(format!("{}/v1/models", server_url(&url))))
+    }
+
+    fn parse_models(&self, text: &str) -> Result<Vec<String>> {
+        parse_openai_models(text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_url() {
+        let url = "http://localhost:8080/completions".to_owned();
+        assert_eq!(
+            LlamaCpp.build_url(url.clone(), "", false, true),
+            "http://localhost:8080/infill"
+        );
+        assert_eq!(
+            LlamaCpp.build_url(url, "", false, false),
+            "http://localhost:8080/completions"
+        );
+        assert_eq!(
+            LlamaCpp.build_url("http://localhost:8080/infill/".to_owned(), "", false, false),
+            "http://localhost:8080/completions"
+        );
+        let url = "http://localhost:8080/v1/completions".to_owned();
+        assert_eq!(
+            LlamaCpp.build_url(url.clone(), "", false, true),
+            "http://localhost:8080/infill"
+        );
+        assert_eq!(
+            LlamaCpp.build_url(url.clone(), "", false, false),
+            "http://localhost:8080/v1/completions"
+        );
+        assert_eq!(
+            LlamaCpp.models_request(url).unwrap().url,
+            "http://localhost:8080/v1/models"
+        );
+    }
+}
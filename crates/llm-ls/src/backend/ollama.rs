@@ -0,0 +1,185 @@
+use custom_types::llm_ls::{FimParams, Ide};
+use reqwest::header::HeaderMap;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+use super::{base_url, reserve_for, APIError, CompletionBackend, ProbeRequest};
+use crate::error::{Error, Result};
+use crate::{Generation, Prompt};
+
+#[derive(Debug, Serialize, Deserialize)]
+struct OllamaGeneration {
+    response: String,
+}
+
+impl From<OllamaGeneration> for Generation {
+    fn from(value: OllamaGeneration) -> Self {
+        Generation {
+            generated_text: value.response,
+        }
+    }
+}
+
+const OLLAMA_ROUTES: &[&str] = &["/api/generate", "/api"];
+
+#[derive(Debug, Deserialize)]
+struct OllamaModel {
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct OllamaModels {
+    models: Vec<OllamaModel>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum OllamaAPIResponse {
+    Generation(OllamaGeneration),
+    Error(APIError),
+}
+
+/// Sets the prompt of the completion request, sending the suffix separately for native fill in
+/// the middle. Shared with the OpenAI completions API, which uses the same field names.
+pub(super) fn insert_prompt(
+    request_body: &mut Map<String, Value>,
+    prompt: &Prompt,
+    fim: &FimParams,
+) {
+    if let Some(suffix) = prompt.native_fim_suffix(fim) {
+        request_body.insert("prompt".to_owned(), Value::String(prompt.full_prefix()));
+        request_body.insert("suffix".to_owned(), Value::String(suffix.to_owned()));
+    } else {
+        request_body.insert("prompt".to_owned(), Value::String(prompt.render(fim)));
+    }
+}
+
+/// An ollama server.
+pub(crate) struct Ollama;
+
+impl CompletionBackend for Ollama {
+    fn build_url(&self, mut url: String, _model: &str, _stream: bool, _native_fim: bool) -> String {
+        if url.ends_with("/api/generate") {
+            url
+        } else if url.ends_with("/api/") {
+            url.push_str("generate");
+            url
+        } else if url.ends_with("/api") {
+            url.push_str("/generate");
+            url
+        } else if url.ends_with('/') {
+            url.push_str("api/generate");
+            url
+        } else {
+            url.push_str("/api/generate");
+            url
+        }
+    }
+
+    fn build_headers(&self, _api_token: Option<&String>, _ide: Ide) -> Result<HeaderMap> {
+        Ok(HeaderMap::new())
+    }
+
+    fn build_body(
+        &self,
+        model: String,
+        prompt: &Prompt,
+        fim: &FimParams,
+        mut request_body: Map<String, Value>,
+        stream: bool,
+    ) -> Map<String, Value> {
+        insert_prompt(&mut request_body, prompt, fim);
+        request_body.insert("model".to_owned(), Value::String(model));
+        request_body.insert("stream".to_owned(), Value::Bool(stream));
+        request_body
+    }
+
+    fn insert_stop(&self, body: &mut Map<String, Value>, stop: &[String]) {
+        if stop.is_empty() {
+            return;
+        }
+        let options = body
+            .entry("options")
+            .or_insert_with(|| Value::Object(Map::new()));
+        if let Value::Object(options) = options {
+            options.entry("stop").or_insert_with(|| json!(stop));
+        }
+    }
+
+    fn parse_generations(&self, text: &str) -> Result<Vec<Generation>> {
+        match serde_json::from_str(text)? {
+            OllamaAPIResponse::Generation(gen) => Ok(vec![gen.into()]),
+            OllamaAPIResponse::Error(err) => Err(Error::Ollama(err)),
+        }
+    }
+
+    fn probe_request(&self, url: String, model: &str) -> ProbeRequest {
+        let base_url = base_url(&url, OLLAMA_ROUTES);
+        if model.is_empty() {
+            ProbeRequest::get(format!("{base_url}/api/tags"))
+        } else {
+            ProbeRequest::post(format!("{base_url}/api/show"), json!({ "name": model }))
+        }
+    }
+
+    /// Only known when `num_ctx` is set in the modelfile, ollama does not report its default.
+    fn context_window(&self, metadata: &Value) -> Option<usize> {
+        metadata
+            .get("parameters")?
+            .as_str()?
+            .lines()
+            .find_map(|line| match line.split_once(char::is_whitespace)? {
+                ("num_ctx", tokens) => tokens.trim().parse().ok(),
+                _ => None,
+            })
+    }
+
+    /// `num_ctx` is the size of the KV cache, which holds the generated tokens too
+    fn generation_reserve(&self, request_body: &Map<String, Value>) -> usize {
+        reserve_for(
+            request_body
+                .get("options")
+                .and_then(|options| options.get("num_predict")),
+        )
+    }
+
+    fn models_request(&self, url: String) -> Option<ProbeRequest> {
+        Some(ProbeRequest::get(format!(
+            "{}/api/tags",
+            base_url(&url, OLLAMA_ROUTES)
+        )))
+    }
+
+    fn parse_models(&self, text: &str) -> Result<Vec<String>> {
+        let models: OllamaModels = serde_json::from_str(text)?;
+        Ok(models.models.into_iter().map(|model| model.name).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_context_window() {
+        let metadata =
+            json!({ "parameters": "stop \"<|endoftext|>\"\nnum_ctx                        8192" });
+        assert_eq!(Ollama.context_window(&metadata), Some(8192));
+        let metadata = json!({ "parameters": "stop \"<|endoftext|>\"" });
+        assert_eq!(Ollama.context_window(&metadata), None);
+    }
+
+    #[test]
+    fn test_generation_reserve() {
+        let body = |value: Value| value.as_object().unwrap().clone();
+        assert_eq!(
+            Ollama.generation_reserve(&body(json!({ "options": { "num_predict": 60 } }))),
+            60
+        );
+        assert_eq!(
+            Ollama.generation_reserve(&body(json!({ "options": { "num_predict": -1 } }))),
+            256
+        );
+        assert_eq!(Ollama.generation_reserve(&Map::new()), 256);
+    }
+}
@@ -0,0 +1,424 @@
+use custom_types::llm_ls::{FimParams, Ide};
+use reqwest::header::HeaderMap;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::fmt::Display;
+
+use super::ollama::insert_prompt;
+use super::{base_url, build_api_headers, CompletionBackend, ProbeRequest};
+use crate::error::{Error, Result};
+use crate::{Generation, Prompt};
+
+const OPENAI_ROUTES: &[&str] = &[
+    "/v1/chat/completions",
+    "/v1/fim/completions",
+    "/v1/completions",
+    "/v1",
+];
+
+#[derive(Debug, Deserialize)]
+struct OpenAIModel {
+    id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct OpenAIModels {
+    data: Vec<OpenAIModel>,
+}
+
+/// Parses the response of `/v1/models`, which llama.cpp implements as well.
+pub(super) fn parse_openai_models(text: &str) -> Result<Vec<String>> {
+    let models: OpenAIModels = serde_json::from_str(text)?;
+    Ok(models.data.into_iter().map(|model| model.id).collect())
+}
+
+fn models_request(url: &str) -> ProbeRequest {
+    ProbeRequest::get(format!("{}/v1/models", base_url(url, OPENAI_ROUTES)))
+}
+
+/// Only keeps the entry of `model` from the `/v1/models` response.
+fn model_metadata(response: Value, model: &str) -> Option<Value> {
+    let Value::Object(mut response) = response else {
+        return None;
+    };
+    match response.remove("data") {
+        Some(Value::Array(models)) => models
+            .into_iter()
+            .find(|entry| entry.get("id").and_then(Value::as_str) == Some(model)),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum OpenAIGenerationChoice {
+    Text { text: String },
+    // Mistral style `/v1/fim/completions` endpoints reply with chat-like choices
+    Message { message: OpenAIChatMessage },
+    Delta { delta: OpenAIChatDelta },
+}
+
+impl From<OpenAIGenerationChoice> for Generation {
+    fn from(value: OpenAIGenerationChoice) -> Self {
+        let generated_text = match value {
+            OpenAIGenerationChoice::Text { text } => text,
+            OpenAIGenerationChoice::Message { message } => message.content.unwrap_or_default(),
+            OpenAIGenerationChoice::Delta { delta } => delta.content.unwrap_or_default(),
+        };
+        Generation { generated_text }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct OpenAIGeneration {
+    choices: Vec<OpenAIGenerationChoice>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum OpenAIErrorLoc {
+    String(String),
+    Int(u32),
+}
+
+impl Display for OpenAIErrorLoc {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OpenAIErrorLoc::String(s) => s.fmt(f),
+            OpenAIErrorLoc::Int(i) => i.fmt(f),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct OpenAIErrorDetail {
+    loc: OpenAIErrorLoc,
+    msg: String,
+    r#type: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct OpenAIError {
+    detail: Vec<OpenAIErrorDetail>,
+}
+
+impl Display for OpenAIError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, item) in self.detail.iter().enumerate() {
+            if i != 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}: {} ({})", item.loc, item.msg, item.r#type)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum OpenAIAPIResponse {
+    Generation(OpenAIGeneration),
+    Error(OpenAIError),
+}
+
+#[derive(Debug, Deserialize)]
+struct OpenAIChatMessage {
+    content: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OpenAIChatGenerationChoice {
+    message: OpenAIChatMessage,
+}
+
+impl From<OpenAIChatGenerationChoice> for Generation {
+    fn from(value: OpenAIChatGenerationChoice) -> Self {
+        Generation {
+            generated_text: strip_code_fences(&value.message.content.unwrap_or_default()),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct OpenAIChatGeneration {
+    choices: Vec<OpenAIChatGenerationChoice>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum OpenAIChatAPIResponse {
+    Generation(OpenAIChatGeneration),
+    Error(OpenAIError),
+}
+
+#[derive(Debug, Deserialize)]
+struct OpenAIChatDelta {
+    #[serde(default)]
+    content: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OpenAIChatStreamChoice {
+    delta: OpenAIChatDelta,
+}
+
+#[derive(Debug, Deserialize)]
+struct OpenAIChatStreamResponse {
+    choices: Vec<OpenAIChatStreamChoice>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum OpenAIChatStreamAPIResponse {
+    Delta(OpenAIChatStreamResponse),
+    Error(OpenAIError),
+}
+
+fn build_openai_chat_messages(prompt: String, fim: &FimParams) -> Value {
+    let system_prompt = if fim.enabled {
+        format!(
+            "You are a code completion engine. The user message contains source code where the \
+            text after `{}` comes before the cursor, the text after `{}` comes after the cursor \
+            and `{}` marks the end of the code. Reply only with the code to insert at the cursor, \
+            without any explanation or markdown formatting.",
+            fim.prefix, fim.suffix, fim.middle
+        )
+    } else {
+        "You are a code completion engine. Reply only with the code that directly continues the \
+        user message, without repeating it, without any explanation or markdown formatting."
+            .to_owned()
+    };
+    json!([
+        { "role": "system", "content": system_prompt },
+        { "role": "user", "content": prompt },
+    ])
+}
+
+/// Chat models tend to wrap their answer in a markdown code block, only keep the code of the
+/// first block when there is one.
+fn strip_code_fences(text: &str) -> String {
+    let Some((_, fenced)) = text.split_once("```") else {
+        return text.to_owned();
+    };
+    // skip the info string, e.g. `rust` in ```rust, a single line block has none
+    let code = fenced.split_once('\n').map_or(fenced, |(_, code)| code);
+    let code = match code.split_once("```") {
+        Some((code, _)) => code,
+        None => code,
+    };
+    code.strip_suffix('\n').unwrap_or(code).to_owned()
+}
+
+/// The part of a streamed answer that is known to be code, holding back what may still turn out
+/// to be a code fence. It is always a prefix of what [`strip_code_fences`] returns for an answer
+/// starting with the streamed text, unless the model writes some text ahead of the code block.
+fn code_preview(text: &str) -> &str {
+    let code = match text.trim_start().strip_prefix("```") {
+        Some(fenced) => match fenced.split_once('\n') {
+            Some((_, code)) => code,
+            // the info string may not be complete yet
+            None => return "",
+        },
+        None if "```".starts_with(text.trim_start()) => return "",
+        None => text,
+    };
+    let code = code.split("```").next().unwrap_or_default();
+    let code = code.trim_end_matches('`');
+    code.strip_suffix('\n').unwrap_or(code)
+}
+
+/// Appends `route` to the url, adding the `/v1` prefix when it is missing.
+fn push_v1_route(mut url: String, route: &str) -> String {
+    if url.ends_with(&format!("/v1/{route}")) {
+        url
+    } else if url.ends_with("/v1/") {
+        url.push_str(route);
+        url
+    } else if url.ends_with("/v1") {
+        url.push('/');
+        url.push_str(route);
+        url
+    } else if url.ends_with('/') {
+        url.push_str("v1/");
+        url.push_str(route);
+        url
+    } else {
+        url.push_str("/v1/");
+        url.push_str(route);
+        url
+    }
+}
+
+/// An OpenAI compatible `/v1/completions` API.
+pub(crate) struct OpenAi;
+
+impl CompletionBackend for OpenAi {
+    fn build_url(&self, url: String, _model: &str, _stream: bool, _native_fim: bool) -> String {
+        if url.ends_with("/fim/completions") {
+            url
+        } else {
+            push_v1_route(url, "completions")
+        }
+    }
+
+    fn build_headers(&self, api_token: Option<&String>, ide: Ide) -> Result<HeaderMap> {
+        build_api_headers(api_token, ide)
+    }
+
+    fn build_body(
+        &self,
+        model: String,
+        prompt: &Prompt,
+        fim: &FimParams,
+        mut request_body: Map<String, Value>,
+        stream: bool,
+    ) -> Map<String, Value> {
+        insert_prompt(&mut request_body, prompt, fim);
+        request_body.insert("model".to_owned(), Value::String(model));
+        request_body.insert("stream".to_owned(), Value::Bool(stream));
+        request_body
+    }
+
+    fn parse_generations(&self, text: &str) -> Result<Vec<Generation>> {
+        match serde_json::from_str(text)? {
+            OpenAIAPIResponse::Generation(completion) => {
+                Ok(completion.choices.into_iter().map(|x| x.into()).collect())
+            }
+            OpenAIAPIResponse::Error(err) => Err(Error::OpenAI(err)),
+        }
+    }
+
+    fn probe_request(&self, url: String, _model: &str) -> ProbeRequest {
+        models_request(&url)
+    }
+
+    fn model_metadata(&self, response: Value, model: &str) -> Option<Value> {
+        model_metadata(response, model)
+    }
+
+    fn models_request(&self, url: String) -> Option<ProbeRequest> {
+        Some(models_request(&url))
+    }
+
+    fn parse_models(&self, text: &str) -> Result<Vec<String>> {
+        parse_openai_models(text)
+    }
+}
+
+/// An OpenAI compatible `/v1/chat/completions` API, prompted to do fill in the middle.
+pub(crate) struct OpenAiChat;
+
+impl CompletionBackend for OpenAiChat {
+    fn build_url(&self, url: String, _model: &str, _stream: bool, _native_fim: bool) -> String {
+        push_v1_route(url, "chat/completions")
+    }
+
+    fn build_headers(&self, api_token: Option<&String>, ide: Ide) -> Result<HeaderMap> {
+        build_api_headers(api_token, ide)
+    }
+
+    fn build_body(
+        &self,
+        model: String,
+        prompt: &Prompt,
+        fim: &FimParams,
+        mut request_body: Map<String, Value>,
+        stream: bool,
+    ) -> Map<String, Value> {
+        let messages = build_openai_chat_messages(prompt.render(fim), fim);
+        request_body.insert("messages".to_owned(), messages);
+        request_body.insert("model".to_owned(), Value::String(model));
+        request_body.insert("stream".to_owned(), Value::Bool(stream));
+        request_body
+    }
+
+    fn parse_generations(&self, text: &str) -> Result<Vec<Generation>> {
+        match serde_json::from_str(text)? {
+            OpenAIChatAPIResponse::Generation(completion) => {
+                Ok(completion.choices.into_iter().map(|x| x.into()).collect())
+            }
+            OpenAIChatAPIResponse::Error(err) => Err(Error::OpenAI(err)),
+        }
+    }
+
+    fn parse_stream_event(&self, data: &str) -> Result<Option<String>> {
+        match serde_json::from_str(data)? {
+            OpenAIChatStreamAPIResponse::Delta(res) => Ok(res
+                .choices
+                .into_iter()
+                .next()
+                .and_then(|choice| choice.delta.content)),
+            OpenAIChatStreamAPIResponse::Error(err) => Err(Error::OpenAI(err)),
+        }
+    }
+
+    fn stream_preview<'a>(&self, generated_text: &'a str) -> &'a str {
+        code_preview(generated_text)
+    }
+
+    fn finish_stream(&self, generated_text: String) -> String {
+        strip_code_fences(&generated_text)
+    }
+
+    fn probe_request(&self, url: String, _model: &str) -> ProbeRequest {
+        models_request(&url)
+    }
+
+    fn model_metadata(&self, response: Value, model: &str) -> Option<Value> {
+        model_metadata(response, model)
+    }
+
+    fn models_request(&self, url: String) -> Option<ProbeRequest> {
+        Some(models_request(&url))
+    }
+
+    fn parse_models(&self, text: &str) -> Result<Vec<String>> {
+        parse_openai_models(text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strip_code_fences() {
+        assert_eq!(strip_code_fences("let a = 1;"), "let a = 1;");
+        assert_eq!(
+            strip_code_fences("```rust\nlet a = 1;\nlet b = 2;\n```"),
+            "let a = 1;\nlet b = 2;"
+        );
+        assert_eq!(
+            strip_code_fences("Here is the code:\n```\nreturn x\n```\nHope it helps!"),
+            "return x"
+        );
+        assert_eq!(strip_code_fences("```python\nprint(1)"), "print(1)");
+        assert_eq!(strip_code_fences("```x = 1```"), "x = 1");
+    }
+
+    #[test]
+    fn test_code_preview() {
+        let answer = "```rust\nlet a = 1;\nlet b = 2;\n```\n";
+        let mut sent = "";
+        for end in 0..=answer.len() {
+            let preview = code_preview(&answer[..end]);
+            assert!(
+                preview.starts_with(sent),
+                "{preview:?} doesn't extend {sent:?}"
+            );
+            sent = preview;
+        }
+        assert_eq!(sent, strip_code_fences(answer));
+        assert_eq!(code_preview("``"), "");
+        assert_eq!(code_preview("```pyth"), "");
+        assert_eq!(code_preview("return x\n``"), "return x");
+    }
+
+    #[test]
+    fn test_parse_openai_chat_text() {
+        let text = r#"{"choices":[{"index":0,"message":{"role":"assistant","content":"```py\nx = 1\n```"}}]}"#;
+        let generations = OpenAiChat.parse_generations(text).unwrap();
+        assert_eq!(generations.len(), 1);
+        assert_eq!(generations[0].generated_text, "x = 1");
+    }
+}
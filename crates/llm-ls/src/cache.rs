@@ -0,0 +1,398 @@
+use custom_types::llm_ls::{CacheConfig, GetCompletionsParams, ServedBy};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+use tracing::{debug, error, info};
+
+use crate::error::Result;
+use crate::{Generation, Prompt};
+
+const CACHE_FILE_NAME: &str = "completion_cache.jsonl";
+
+/// Hashes the JSON representation of `value`, going through `Value` so that the keys of maps
+/// are sorted.
+fn hash_json(value: &impl Serialize, hasher: &mut impl Hasher) {
+    serde_json::to_value(value)
+        .map(|value| value.to_string())
+        .unwrap_or_default()
+        .hash(hasher);
+}
+
+/// Hashes everything that changes the generations of a completion request.
+///
+/// API tokens are left out, they don't change the generations and may be rotated. The hash is
+/// only stable for a given build of llm-ls, a persisted cache written by another version just
+/// misses.
+pub(crate) fn cache_key(prompt: &Prompt, params: &GetCompletionsParams) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    prompt.render(&params.fim).hash(&mut hasher);
+    prompt.native_fim_suffix(&params.fim).hash(&mut hasher);
+    params.model.hash(&mut hasher);
+    params.stop.hash(&mut hasher);
+    params.disable_url_path_completion.hash(&mut hasher);
+    hash_json(&params.backend, &mut hasher);
+    hash_json(&params.fim, &mut hasher);
+    hash_json(&params.extra_headers, &mut hasher);
+    hash_json(&params.request_body, &mut hasher);
+    for config in &params.fallback_backends {
+        hash_json(
+            &(&config.model, &config.backend, &config.extra_headers),
+            &mut hasher,
+        );
+    }
+    hasher.finish()
+}
+
+fn is_sampling(request_body: &Map<String, Value>) -> bool {
+    let do_sample = request_body.get("do_sample").and_then(Value::as_bool);
+    let temperature = request_body.get("temperature").and_then(Value::as_f64);
+    do_sample == Some(true) || temperature.is_some_and(|t| t > 0.0)
+}
+
+/// Sampled generations are expected to differ from one request to the next, they are never
+/// cached.
+pub(crate) fn is_cacheable(params: &GetCompletionsParams) -> bool {
+    if !params.cache.enabled || is_sampling(&params.request_body) {
+        return false;
+    }
+    // Hugging Face backends nest the generation parameters
+    match params.request_body.get("parameters") {
+        Some(Value::Object(parameters)) => !is_sampling(parameters),
+        _ => true,
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+struct CacheEntry {
+    generations: Vec<Generation>,
+    served_by: ServedBy,
+    created_at: SystemTime,
+    #[serde(skip)]
+    last_used: u64,
+}
+
+/// A line of the persisted cache, entries are appended as they are inserted and the later lines
+/// of a key replace the earlier ones.
+#[derive(Deserialize, Serialize)]
+struct CacheLine {
+    key: u64,
+    entry: CacheEntry,
+}
+
+#[derive(Default)]
+struct CacheState {
+    entries: HashMap<u64, CacheEntry>,
+    /// Incremented on every access, the entry with the lowest `last_used` is evicted first
+    tick: u64,
+    loaded: bool,
+    /// Lines of the persisted cache, it is compacted once it has twice as many lines as entries
+    persisted_lines: usize,
+}
+
+impl CacheState {
+    fn evict(&mut self, max_entries: usize) {
+        while self.entries.len() > max_entries {
+            let Some(lru) = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| *key)
+            else {
+                break;
+            };
+            self.entries.remove(&lru);
+        }
+    }
+}
+
+/// An LRU cache of the generations returned by the backends.
+pub(crate) struct CompletionCache {
+    path: PathBuf,
+    state: Mutex<CacheState>,
+    persist_lock: Mutex<()>,
+}
+
+impl CompletionCache {
+    pub(crate) fn new(cache_dir: impl Into<PathBuf>) -> Self {
+        Self {
+            path: cache_dir.into().join(CACHE_FILE_NAME),
+            state: Mutex::new(CacheState::default()),
+            persist_lock: Mutex::new(()),
+        }
+    }
+
+    /// Loads the persisted entries the first time a request enables persistence.
+    async fn load(&self, state: &mut CacheState, config: &CacheConfig) {
+        state.loaded = true;
+        let content = match tokio::fs::read_to_string(&self.path).await {
+            Ok(content) => content,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return,
+            Err(err) => {
+                error!("failed to read the completion cache: {err}");
+                return;
+            }
+        };
+        let mut invalid_lines = 0;
+        for line in content.lines() {
+            state.persisted_lines += 1;
+            let Ok(CacheLine { key, mut entry }) = serde_json::from_str(line) else {
+                // e.g. a line cut short by a crash while appending
+                invalid_lines += 1;
+                continue;
+            };
+            state.tick += 1;
+            entry.last_used = state.tick;
+            state.entries.insert(key, entry);
+        }
+        if invalid_lines > 0 {
+            error!(
+                invalid_lines,
+                "skipped invalid lines of the completion cache"
+            );
+        }
+        state.evict(config.max_entries);
+        info!(
+            entries = state.entries.len(),
+            "loaded completion cache from disk"
+        );
+    }
+
+    pub(crate) async fn get(
+        &self,
+        key: u64,
+        config: &CacheConfig,
+    ) -> Option<(Vec<Generation>, ServedBy)> {
+        let mut state = self.state.lock().await;
+        if config.persist && !state.loaded {
+            self.load(&mut state, config).await;
+        }
+        state.tick += 1;
+        let tick = state.tick;
+        let ttl = Duration::from_secs(config.ttl_secs);
+        let entry = state.entries.get_mut(&key)?;
+        if entry.created_at.elapsed().unwrap_or_default() > ttl {
+            debug!(key, "completion cache entry expired");
+            state.entries.remove(&key);
+            return None;
+        }
+        entry.last_used = tick;
+        Some((entry.generations.clone(), entry.served_by.clone()))
+    }
+
+    pub(crate) async fn insert(
+        &self,
+        key: u64,
+        generations: Vec<Generation>,
+        served_by: ServedBy,
+        config: &CacheConfig,
+    ) -> Result<()> {
+        let mut state = self.state.lock().await;
+        if config.persist && !state.loaded {
+            self.load(&mut state, config).await;
+        }
+        state.tick += 1;
+        let entry = CacheEntry {
+            generations,
+            served_by,
+            created_at: SystemTime::now(),
+            last_used: state.tick,
+        };
+        let line = config
+            .persist
+            .then(|| {
+                serde_json::to_string(&CacheLine {
+                    key,
+                    entry: entry.clone(),
+                })
+            })
+            .transpose()?;
+        state.entries.insert(key, entry);
+        state.evict(config.max_entries);
+        let Some(mut line) = line else {
+            return Ok(());
+        };
+        line.push('\n');
+        state.persisted_lines += 1;
+        if state.persisted_lines <= 2 * state.entries.len().max(1) {
+            drop(state);
+            return self.append(line).await;
+        }
+        // evicted and replaced entries pile up in the file, rewrite it with the live ones
+        let mut content = String::new();
+        for (key, entry) in &state.entries {
+            content.push_str(&serde_json::to_string(&CacheLine {
+                key: *key,
+                entry: entry.clone(),
+            })?);
+            content.push('\n');
+        }
+        state.persisted_lines = state.entries.len();
+        drop(state);
+        self.rewrite(content).await
+    }
+
+    async fn append(&self, line: String) -> Result<()> {
+        let _guard = self.persist_lock.lock().await;
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await?;
+        file.write_all(line.as_bytes()).await?;
+        Ok(())
+    }
+
+    async fn rewrite(&self, content: String) -> Result<()> {
+        let _guard = self.persist_lock.lock().await;
+        let mut part_path = self.path.as_os_str().to_owned();
+        part_path.push(".part");
+        tokio::fs::write(&part_path, content).await?;
+        tokio::fs::rename(&part_path, &self.path).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use custom_types::llm_ls::Backend;
+    use serde_json::json;
+
+    fn config(max_entries: usize, ttl_secs: u64, persist: bool) -> CacheConfig {
+        CacheConfig {
+            enabled: true,
+            max_entries,
+            ttl_secs,
+            persist,
+        }
+    }
+
+    fn generations(text: &str) -> (Vec<Generation>, ServedBy) {
+        let generations = vec![Generation {
+            generated_text: text.to_owned(),
+        }];
+        let served_by = ServedBy::new("bigcode/starcoder".to_owned(), &Backend::default());
+        (generations, served_by)
+    }
+
+    async fn insert(cache: &CompletionCache, key: u64, text: &str, config: &CacheConfig) {
+        let (generations, served_by) = generations(text);
+        cache
+            .insert(key, generations, served_by, config)
+            .await
+            .unwrap();
+    }
+
+    async fn get(cache: &CompletionCache, key: u64, config: &CacheConfig) -> Option<String> {
+        let (generations, _) = cache.get(key, config).await?;
+        Some(generations[0].generated_text.clone())
+    }
+
+    fn params(value: Value) -> GetCompletionsParams {
+        let mut params = json!({
+            "textDocument": { "uri": "file:///a.py" },
+            "position": { "line": 0, "character": 0 },
+            "fim": { "enabled": true, "prefix": "<p>", "middle": "<m>", "suffix": "<s>" },
+            "apiToken": null,
+            "model": "bigcode/starcoder",
+            "backend": "custom",
+            "url": "http://localhost:8080/generate",
+            "bodyTemplate": { "input": "{{prompt}}" },
+            "generatedTextPath": "text",
+            "tokenizerConfig": null,
+            "contextWindow": 1024,
+            "tlsSkipVerifyInsecure": false,
+        });
+        let fields = value.as_object().unwrap().clone();
+        params.as_object_mut().unwrap().extend(fields);
+        serde_json::from_value(params).unwrap()
+    }
+
+    #[test]
+    fn test_cache_key() {
+        let prompt = Prompt {
+            context: String::new(),
+            prefix: "def".to_owned(),
+            suffix: Some("\n".to_owned()),
+            template: None,
+        };
+        let key = cache_key(&prompt, &params(json!({})));
+        assert_eq!(
+            cache_key(&prompt, &params(json!({ "apiToken": "secret" }))),
+            key
+        );
+        for changed in [
+            json!({ "bodyTemplate": { "inputs": "{{prompt}}" } }),
+            json!({ "generatedTextPath": "outputs.*.text" }),
+            json!({ "headers": { "X-Model-Version": "2" } }),
+            json!({ "extraHeaders": { "X-Route": "canary" } }),
+            json!({ "fallbackBackends": [{ "model": "m", "backend": "ollama", "url": "http://localhost:11434" }] }),
+        ] {
+            assert_ne!(cache_key(&prompt, &params(changed)), key);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_lru_eviction() {
+        let cache = CompletionCache::new(std::env::temp_dir());
+        let config = config(2, 600, false);
+        insert(&cache, 1, "a", &config).await;
+        insert(&cache, 2, "b", &config).await;
+        assert_eq!(get(&cache, 1, &config).await.as_deref(), Some("a"));
+        insert(&cache, 3, "c", &config).await;
+        assert_eq!(get(&cache, 2, &config).await, None);
+        assert_eq!(get(&cache, 1, &config).await.as_deref(), Some("a"));
+        assert_eq!(get(&cache, 3, &config).await.as_deref(), Some("c"));
+    }
+
+    #[tokio::test]
+    async fn test_ttl() {
+        let cache = CompletionCache::new(std::env::temp_dir());
+        insert(&cache, 1, "a", &config(16, 0, false)).await;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(
+            get(&cache, 1, &config(16, 600, false)).await.as_deref(),
+            Some("a")
+        );
+        assert_eq!(get(&cache, 1, &config(16, 0, false)).await, None);
+        assert_eq!(get(&cache, 1, &config(16, 600, false)).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_persistence() {
+        let dir = std::env::temp_dir().join(format!("llm-ls-cache-{}", uuid::Uuid::new_v4()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        let config = config(2, 600, true);
+        let cache = CompletionCache::new(&dir);
+        for (key, text) in [(1, "a"), (2, "b"), (1, "c"), (3, "d"), (4, "e"), (4, "f")] {
+            insert(&cache, key, text, &config).await;
+        }
+        let lines = tokio::fs::read_to_string(dir.join(CACHE_FILE_NAME))
+            .await
+            .unwrap()
+            .lines()
+            .count();
+        assert!(lines <= 4, "the cache file wasn't compacted: {lines} lines");
+
+        let reloaded = CompletionCache::new(&dir);
+        assert_eq!(get(&reloaded, 1, &config).await, None);
+        assert_eq!(get(&reloaded, 3, &config).await.as_deref(), Some("d"));
+        assert_eq!(get(&reloaded, 4, &config).await.as_deref(), Some("f"));
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[test]
+    fn test_is_sampling() {
+        let body = |value: Value| value.as_object().unwrap().clone();
+        assert!(!is_sampling(&body(json!({ "max_new_tokens": 60 }))));
+        assert!(!is_sampling(&body(json!({ "temperature": 0 }))));
+        assert!(is_sampling(&body(json!({ "temperature": 0.2 }))));
+        assert!(is_sampling(&body(json!({ "do_sample": true }))));
+    }
+}
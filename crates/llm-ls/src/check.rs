@@ -0,0 +1,130 @@
+use custom_types::llm_ls::{AuthStatus, BackendParams, CheckBackendResult};
+use reqwest::header::HeaderMap;
+use reqwest::StatusCode;
+use std::time::{Duration, Instant};
+use tracing::{debug, info};
+
+use crate::backend::{insert_extra_headers, status_error, CompletionBackend, ProbeRequest};
+use crate::error::{Error, Result};
+
+/// Probes are expected to be cheap, don't let an unresponsive backend hang the client.
+const DEFAULT_PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub(crate) async fn send_probe(
+    http_client: &reqwest::Client,
+    probe: ProbeRequest,
+    headers: HeaderMap,
+    request_timeout_ms: Option<u64>,
+) -> Result<reqwest::Response> {
+    let timeout = request_timeout_ms
+        .map(Duration::from_millis)
+        .unwrap_or(DEFAULT_PROBE_TIMEOUT);
+    debug!(method = %probe.method, url = probe.url, "probing backend");
+    let mut req = http_client
+        .request(probe.method, probe.url.as_str())
+        .headers(headers)
+        .timeout(timeout);
+    if let Some(body) = &probe.body {
+        req = req.json(body);
+    }
+    Ok(req.send().await?)
+}
+
+/// Sends the probe and returns the body of its response, failing on non success statuses.
+pub(crate) async fn fetch_probe(
+    http_client: &reqwest::Client,
+    backend: &dyn CompletionBackend,
+    probe: ProbeRequest,
+    headers: HeaderMap,
+    request_timeout_ms: Option<u64>,
+) -> Result<String> {
+    let res = send_probe(http_client, probe, headers, request_timeout_ms).await?;
+    let status = res.status();
+    let body = res.text().await?;
+    if !status.is_success() {
+        return Err(status_error(backend, status, &body));
+    }
+    Ok(body)
+}
+
+fn probe_headers(backend: &dyn CompletionBackend, params: &BackendParams) -> Result<HeaderMap> {
+    let mut headers = backend.build_headers(params.api_token.as_ref(), params.ide)?;
+    insert_extra_headers(&mut headers, &params.extra_headers)?;
+    Ok(headers)
+}
+
+/// Checks that the backend is reachable and accepts the credentials, reporting what it says
+/// about the model. Failures are part of the result rather than errors.
+pub(crate) async fn check_backend(
+    http_client: &reqwest::Client,
+    backend: &dyn CompletionBackend,
+    params: &BackendParams,
+) -> CheckBackendResult {
+    let probe = backend.probe_request(params.backend.clone().url(), &params.model);
+    let start = Instant::now();
+    let res = match probe_headers(backend, params) {
+        Ok(headers) => send_probe(http_client, probe, headers, params.request_timeout_ms).await,
+        Err(err) => Err(err),
+    };
+    let latency_ms = start.elapsed().as_millis() as u64;
+    let res = match res {
+        Ok(res) => res,
+        Err(err) => {
+            info!("backend check failed: {err}");
+            return CheckBackendResult {
+                reachable: false,
+                auth: AuthStatus::Unknown,
+                latency_ms: None,
+                status: None,
+                error: Some(err.to_string()),
+                model_metadata: None,
+            };
+        }
+    };
+    let status = res.status();
+    let body = res.text().await.unwrap_or_default();
+    let auth = match status {
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => AuthStatus::Rejected,
+        status if status.is_success() => AuthStatus::Valid,
+        _ => AuthStatus::Unknown,
+    };
+    let (error, model_metadata) = if status.is_success() {
+        let metadata = serde_json::from_str(&body)
+            .ok()
+            .and_then(|response| backend.model_metadata(response, &params.model));
+        (None, metadata)
+    } else {
+        (Some(status_error(backend, status, &body).to_string()), None)
+    };
+    info!(%status, latency_ms, "checked backend");
+    CheckBackendResult {
+        reachable: true,
+        auth,
+        latency_ms: Some(latency_ms),
+        status: Some(status.as_u16()),
+        error,
+        model_metadata,
+    }
+}
+
+pub(crate) async fn list_models(
+    http_client: &reqwest::Client,
+    backend: &dyn CompletionBackend,
+    params: &BackendParams,
+) -> Result<Vec<String>> {
+    let Some(probe) = backend.models_request(params.backend.clone().url()) else {
+        return Err(Error::ModelListingUnsupported(
+            params.backend.tag().to_owned(),
+        ));
+    };
+    let headers = probe_headers(backend, params)?;
+    let body = fetch_probe(
+        http_client,
+        backend,
+        probe,
+        headers,
+        params.request_timeout_ms,
+    )
+    .await?;
+    backend.parse_models(&body)
+}
@@ -0,0 +1,400 @@
+use custom_types::llm_ls::{
+    Backend, BackendScope, CredentialHelper, GetCompletionsParams, InitializationOptions,
+    TokenizerConfig,
+};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::time::{Duration, Instant};
+use tokio::process::Command;
+use tokio::sync::RwLock;
+use tower_lsp::lsp_types::Url;
+use tracing::{debug, warn};
+
+const HF_TOKEN_ENV_VARS: &[&str] = &["HF_TOKEN", "HUGGING_FACE_HUB_TOKEN"];
+const OPENAI_TOKEN_ENV_VARS: &[&str] = &["OPENAI_API_KEY"];
+const AZURE_OPENAI_TOKEN_ENV_VARS: &[&str] = &["AZURE_OPENAI_API_KEY"];
+const CREDENTIAL_HELPER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Looks up an environment variable.
+type Env = Box<dyn Fn(&str) -> Option<String> + Send + Sync>;
+
+/// Whether the backend accepts Hugging Face tokens, the token file and the Hugging Face env vars
+/// are only used for those.
+fn uses_hf_token(backend: &Backend) -> bool {
+    matches!(backend, Backend::HuggingFace { .. } | Backend::Tgi { .. })
+}
+
+fn token_env_vars(backend: &Backend) -> &'static [&'static str] {
+    match backend {
+        Backend::AzureOpenAi { .. } => AZURE_OPENAI_TOKEN_ENV_VARS,
+        Backend::HuggingFace { .. } | Backend::Tgi { .. } => HF_TOKEN_ENV_VARS,
+        Backend::OpenAi { .. } | Backend::OpenAiChat { .. } => OPENAI_TOKEN_ENV_VARS,
+        _ => &[],
+    }
+}
+
+/// Whether the backend is served by the provider the env vars are meant for. OpenAI compatible
+/// and TGI servers are often self-hosted or third party proxies, which must not get the tokens
+/// found in the environment.
+fn is_official_host(backend: &Backend) -> bool {
+    let Some(host) = Url::parse(&backend.clone().url())
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_owned))
+    else {
+        return false;
+    };
+    let is_domain = |domain: &str| host == domain || host.ends_with(&format!(".{domain}"));
+    match backend {
+        Backend::AzureOpenAi { .. } => {
+            is_domain("openai.azure.com") || is_domain("cognitiveservices.azure.com")
+        }
+        Backend::HuggingFace { .. } | Backend::Tgi { .. } => {
+            is_domain("huggingface.co") || is_domain("endpoints.huggingface.cloud")
+        }
+        Backend::OpenAi { .. } | Backend::OpenAiChat { .. } => host == "api.openai.com",
+        _ => false,
+    }
+}
+
+/// Where `huggingface-cli login` stores the token, following the `huggingface_hub` resolution.
+fn hf_token_path(env: &Env) -> Option<PathBuf> {
+    if let Some(path) = env("HF_TOKEN_PATH") {
+        return Some(PathBuf::from(path));
+    }
+    let hf_home = match env("HF_HOME") {
+        Some(hf_home) => PathBuf::from(hf_home),
+        None => match env("XDG_CACHE_HOME") {
+            Some(cache_home) => PathBuf::from(cache_home).join("huggingface"),
+            None => home::home_dir()?.join(".cache").join("huggingface"),
+        },
+    };
+    Some(hf_home.join("token"))
+}
+
+fn non_empty(token: String) -> Option<String> {
+    let token = token.trim();
+    (!token.is_empty()).then(|| token.to_owned())
+}
+
+/// Resolves the API tokens that were not sent by the client.
+///
+/// The chain is: the token from the request, the credential helper configured for the backend,
+/// the backend's env vars and finally the Hugging Face token file. The last two are only used for
+/// the official hosts and the backends the editor opted in.
+pub(crate) struct Credentials {
+    /// Configured by the editor when initializing the server, never by the requests
+    helpers: RwLock<Vec<CredentialHelper>>,
+    env_token_backends: RwLock<Vec<BackendScope>>,
+    helper_tokens: RwLock<HashMap<CredentialHelper, (String, Instant)>>,
+    env: Env,
+}
+
+impl Default for Credentials {
+    fn default() -> Self {
+        Self::with_env(Box::new(|var: &str| std::env::var(var).ok()))
+    }
+}
+
+impl Credentials {
+    fn with_env(env: Env) -> Self {
+        Self {
+            helpers: RwLock::default(),
+            env_token_backends: RwLock::default(),
+            helper_tokens: RwLock::default(),
+            env,
+        }
+    }
+
+    pub(crate) async fn configure(&self, options: InitializationOptions) {
+        *self.helpers.write().await = options.credential_helpers;
+        *self.env_token_backends.write().await = options.env_token_backends;
+        self.helper_tokens.write().await.clear();
+    }
+
+    async fn uses_env_tokens(&self, backend: &Backend) -> bool {
+        is_official_host(backend)
+            || self
+                .env_token_backends
+                .read()
+                .await
+                .iter()
+                .any(|scope| scope.applies_to(backend))
+    }
+
+    /// Fills in the API tokens of every backend of the request as well as of the tokenizer
+    /// download.
+    pub(crate) async fn resolve_params(&self, params: &mut GetCompletionsParams) {
+        params.api_token = self.resolve(params.api_token.take(), &params.backend).await;
+        for config in &mut params.fallback_backends {
+            config.api_token = self.resolve(config.api_token.take(), &config.backend).await;
+        }
+        if let Some(TokenizerConfig::HuggingFace { api_token, .. }) = &mut params.tokenizer_config {
+            // tokenizers are downloaded from the hub, the credentials of the backend must not be
+            // used for it
+            *api_token = self.resolve(api_token.take(), &Backend::default()).await;
+        }
+    }
+
+    pub(crate) async fn resolve(
+        &self,
+        api_token: Option<String>,
+        backend: &Backend,
+    ) -> Option<String> {
+        if api_token.is_some() {
+            return api_token;
+        }
+        let helper = self
+            .helpers
+            .read()
+            .await
+            .iter()
+            .find(|helper| helper.applies_to(backend))
+            .cloned();
+        if let Some(helper) = helper {
+            if let Some(token) = self.helper_token(&helper).await {
+                return Some(token);
+            }
+        }
+        if !self.uses_env_tokens(backend).await {
+            return None;
+        }
+        for var in token_env_vars(backend) {
+            if let Some(token) = (self.env)(var).and_then(non_empty) {
+                debug!("using the api token from ${var}");
+                return Some(token);
+            }
+        }
+        if uses_hf_token(backend) {
+            let path = hf_token_path(&self.env)?;
+            if let Some(token) = tokio::fs::read_to_string(&path)
+                .await
+                .ok()
+                .and_then(non_empty)
+            {
+                debug!("using the api token from {}", path.display());
+                return Some(token);
+            }
+        }
+        None
+    }
+
+    /// Runs the credential helper, reusing its last token until `cache_ttl_secs` elapsed.
+    async fn helper_token(&self, helper: &CredentialHelper) -> Option<String> {
+        if let Some((token, expires_at)) = self.helper_tokens.read().await.get(helper) {
+            if Instant::now() < *expires_at {
+                return Some(token.clone());
+            }
+        }
+        let mut command = Command::new(&helper.command);
+        command
+            .args(&helper.args)
+            // stdin is the LSP transport, it must not be inherited
+            .stdin(Stdio::null())
+            .kill_on_drop(true);
+        let output = match tokio::time::timeout(CREDENTIAL_HELPER_TIMEOUT, command.output()).await {
+            Ok(Ok(output)) if output.status.success() => output,
+            Ok(Ok(output)) => {
+                warn!(
+                    status = %output.status,
+                    stderr = %String::from_utf8_lossy(&output.stderr),
+                    "credential helper failed"
+                );
+                return None;
+            }
+            Ok(Err(err)) => {
+                warn!("failed to run the credential helper: {err}");
+                return None;
+            }
+            Err(_) => {
+                warn!("credential helper timed out");
+                return None;
+            }
+        };
+        let Some(token) = non_empty(String::from_utf8_lossy(&output.stdout).into_owned()) else {
+            warn!("credential helper returned an empty token");
+            return None;
+        };
+        let expires_at = Instant::now() + Duration::from_secs(helper.cache_ttl_secs);
+        self.helper_tokens
+            .write()
+            .await
+            .insert(helper.clone(), (token.clone(), expires_at));
+        Some(token)
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn helper(backend: &str, script: &str, cache_ttl_secs: u64) -> CredentialHelper {
+        CredentialHelper {
+            backend: backend.to_owned(),
+            url: None,
+            command: "sh".to_owned(),
+            args: vec!["-c".to_owned(), script.to_owned()],
+            cache_ttl_secs,
+        }
+    }
+
+    fn azure() -> Backend {
+        Backend::AzureOpenAi {
+            url: "https://r.openai.azure.com".to_owned(),
+            deployment: None,
+            api_version: "2024-02-01".to_owned(),
+            chat: false,
+        }
+    }
+
+    /// Credentials reading these env vars only, without a Hugging Face token file.
+    fn credentials(vars: &'static [(&'static str, &'static str)]) -> Credentials {
+        let token_path = std::env::temp_dir()
+            .join(format!("llm-ls-hf-token-{}", uuid::Uuid::new_v4()))
+            .display()
+            .to_string();
+        Credentials::with_env(Box::new(move |var: &str| match var {
+            "HF_TOKEN_PATH" => Some(token_path.clone()),
+            var => vars
+                .iter()
+                .find(|(name, _)| *name == var)
+                .map(|(_, value)| value.to_string()),
+        }))
+    }
+
+    async fn set_helpers(credentials: &Credentials, helpers: Vec<CredentialHelper>) {
+        credentials
+            .configure(InitializationOptions {
+                credential_helpers: helpers,
+                ..Default::default()
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_resolution_order() {
+        let credentials = credentials(&[("AZURE_OPENAI_API_KEY", "from-env")]);
+        set_helpers(
+            &credentials,
+            vec![helper("openai", "echo from-helper", 300)],
+        )
+        .await;
+        assert_eq!(
+            credentials.resolve(None, &azure()).await.as_deref(),
+            Some("from-env")
+        );
+        set_helpers(
+            &credentials,
+            vec![helper("azureopenai", "echo from-helper", 300)],
+        )
+        .await;
+        assert_eq!(
+            credentials
+                .resolve(Some("from-request".to_owned()), &azure())
+                .await
+                .as_deref(),
+            Some("from-request")
+        );
+        assert_eq!(
+            credentials.resolve(None, &azure()).await.as_deref(),
+            Some("from-helper")
+        );
+        set_helpers(&credentials, vec![helper("azureopenai", "exit 1", 300)]).await;
+        assert_eq!(
+            credentials.resolve(None, &azure()).await.as_deref(),
+            Some("from-env")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_env_tokens_scope() {
+        let credentials = credentials(&[("OPENAI_API_KEY", "sk-env"), ("HF_TOKEN", "hf-env")]);
+        let openai = |url: &str| Backend::OpenAi {
+            url: url.to_owned(),
+        };
+        let tgi = |url: &str| Backend::Tgi {
+            url: url.to_owned(),
+        };
+        assert_eq!(
+            credentials
+                .resolve(None, &openai("https://api.openai.com/v1"))
+                .await
+                .as_deref(),
+            Some("sk-env")
+        );
+        assert_eq!(
+            credentials
+                .resolve(
+                    None,
+                    &tgi("https://x.us-east-1.aws.endpoints.huggingface.cloud")
+                )
+                .await
+                .as_deref(),
+            Some("hf-env")
+        );
+        assert_eq!(
+            credentials
+                .resolve(None, &openai("http://localhost:8000/v1"))
+                .await,
+            None
+        );
+        assert_eq!(
+            credentials
+                .resolve(None, &openai("https://api.openai.com.evil.dev/v1"))
+                .await,
+            None
+        );
+        assert_eq!(
+            credentials
+                .resolve(None, &tgi("http://tgi.internal:8080"))
+                .await,
+            None
+        );
+
+        credentials
+            .configure(InitializationOptions {
+                env_token_backends: vec![BackendScope {
+                    backend: "openai".to_owned(),
+                    url: Some("http://localhost:8000/v1".to_owned()),
+                }],
+                ..Default::default()
+            })
+            .await;
+        assert_eq!(
+            credentials
+                .resolve(None, &openai("http://localhost:8000/v1"))
+                .await
+                .as_deref(),
+            Some("sk-env")
+        );
+        assert_eq!(
+            credentials
+                .resolve(None, &tgi("http://tgi.internal:8080"))
+                .await,
+            None
+        );
+    }
+
+    #[test]
+    fn test_helper_scope() {
+        let mut helper = helper("azureopenai", "echo token", 300);
+        assert!(helper.applies_to(&azure()));
+        assert!(!helper.applies_to(&Backend::default()));
+        helper.url = Some("https://r.openai.azure.com/".to_owned());
+        assert!(helper.applies_to(&azure()));
+        helper.url = Some("https://other.openai.azure.com".to_owned());
+        assert!(!helper.applies_to(&azure()));
+    }
+
+    #[tokio::test]
+    async fn test_helper_ttl() {
+        // prints a different token every time it runs
+        let cached = helper("azureopenai", "echo $$", 300);
+        let credentials = Credentials::default();
+        let first = credentials.helper_token(&cached).await.unwrap();
+        assert_eq!(credentials.helper_token(&cached).await.unwrap(), first);
+        let expired = helper("azureopenai", "echo $$", 0);
+        let first = credentials.helper_token(&expired).await.unwrap();
+        assert_ne!(credentials.helper_token(&expired).await.unwrap(), first);
+    }
+}
@@ -0,0 +1,145 @@
+use custom_types::llm_ls::{FimParams, GetCompletionsParams};
+use std::collections::HashSet;
+use tokenizers::Tokenizer;
+
+use crate::backend::{insert_extra_headers, CompletionBackend};
+use crate::check::fetch_probe;
+use crate::error::Result;
+
+/// The FIM tokens of popular code models, as prefix, suffix and middle.
+const KNOWN_FIM_TOKENS: &[[&str; 3]] = &[
+    // StarCoder, SantaCoder, Granite
+    ["<fim_prefix>", "<fim_suffix>", "<fim_middle>"],
+    // Qwen2.5-Coder, CodeGemma
+    ["<|fim_prefix|>", "<|fim_suffix|>", "<|fim_middle|>"],
+    // DeepSeek-Coder
+    ["<｜fim▁begin｜>", "<｜fim▁hole｜>", "<｜fim▁end｜>"],
+];
+
+/// Reads the context window from the model metadata the backend reports, `None` when it
+/// reports none.
+pub(crate) async fn discover_context_window(
+    http_client: &reqwest::Client,
+    backend: &dyn CompletionBackend,
+    params: &GetCompletionsParams,
+) -> Result<Option<usize>> {
+    let probe = backend.probe_request(params.backend.clone().url(), &params.model);
+    let mut headers = backend.build_headers(params.api_token.as_ref(), params.ide)?;
+    insert_extra_headers(&mut headers, &params.extra_headers)?;
+    let body = fetch_probe(
+        http_client,
+        backend,
+        probe,
+        headers,
+        params.request_timeout_ms,
+    )
+    .await?;
+    let metadata = backend.model_metadata(serde_json::from_str(&body)?, &params.model);
+    Ok(metadata.and_then(|metadata| backend.context_window(&metadata)))
+}
+
+/// Replaces the configured FIM tokens with the tokenizer's when they follow a known convention.
+///
+/// Returns whether the tokens were found.
+pub(crate) fn infer_fim_tokens(fim: &mut FimParams, tokenizer: &Tokenizer) -> bool {
+    let added_tokens: HashSet<String> = tokenizer
+        .get_added_tokens_decoder()
+        .into_values()
+        .map(|token| token.content)
+        .collect();
+    let Some([prefix, suffix, middle]) = KNOWN_FIM_TOKENS
+        .iter()
+        .find(|tokens| tokens.iter().all(|token| added_tokens.contains(*token)))
+    else {
+        return false;
+    };
+    fim.prefix = prefix.to_string();
+    fim.suffix = suffix.to_string();
+    fim.middle = middle.to_string();
+    true
+}
+
+/// Returns the FIM tokens that are not encoded as a single special token, which means the model
+/// won't see them as FIM markers.
+pub(crate) fn invalid_fim_tokens(fim: &FimParams, tokenizer: &Tokenizer) -> Vec<String> {
+    let added_tokens = tokenizer.get_added_tokens_decoder();
+    [&fim.prefix, &fim.suffix, &fim.middle]
+        .into_iter()
+        .filter(|token| {
+            // templates commonly pad the tokens with spaces, e.g. `<PRE> `
+            let Ok(encoding) = tokenizer.encode(token.trim(), false) else {
+                return true;
+            };
+            !matches!(
+                encoding.get_ids(),
+                [id] if added_tokens.get(id).is_some_and(|token| token.special)
+            )
+        })
+        .cloned()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use tokenizers::AddedToken;
+
+    use super::*;
+    use crate::tokens::byte_tokenizer;
+
+    fn fim(prefix: &str, suffix: &str, middle: &str) -> FimParams {
+        FimParams {
+            enabled: true,
+            prefix: prefix.to_owned(),
+            middle: middle.to_owned(),
+            suffix: suffix.to_owned(),
+            mode: Default::default(),
+            order: None,
+            suffix_ratio: None,
+            max_suffix_lines: None,
+            infer_tokens: true,
+        }
+    }
+
+    fn with_special_tokens(special_tokens: &[&str]) -> Tokenizer {
+        let mut tokenizer = byte_tokenizer();
+        let special_tokens = special_tokens
+            .iter()
+            .map(|token| AddedToken::from(*token, true))
+            .collect::<Vec<_>>();
+        tokenizer.add_special_tokens(&special_tokens);
+        tokenizer
+    }
+
+    #[test]
+    fn test_infer_fim_tokens() {
+        let tokenizer =
+            with_special_tokens(&["<|fim_prefix|>", "<|fim_suffix|>", "<|fim_middle|>"]);
+        let mut params = fim("<PRE> ", " <SUF>", " <MID>");
+        assert!(infer_fim_tokens(&mut params, &tokenizer));
+        assert_eq!(
+            [params.prefix, params.suffix, params.middle],
+            ["<|fim_prefix|>", "<|fim_suffix|>", "<|fim_middle|>"]
+        );
+
+        // all three tokens of a convention must be present
+        let tokenizer = with_special_tokens(&["<fim_prefix>", "<fim_suffix>"]);
+        let mut params = fim("<PRE> ", " <SUF>", " <MID>");
+        assert!(!infer_fim_tokens(&mut params, &tokenizer));
+        assert_eq!(params.prefix, "<PRE> ");
+    }
+
+    #[test]
+    fn test_invalid_fim_tokens() {
+        let mut tokenizer = with_special_tokens(&["<PRE>", "<SUF>"]);
+        // added but not special, the model doesn't see it as a marker
+        tokenizer.add_tokens(&[AddedToken::from("<MID>", false)]);
+        assert_eq!(
+            invalid_fim_tokens(&fim("<PRE> ", " <SUF>", " <MID>"), &tokenizer),
+            [" <MID>"]
+        );
+        assert_eq!(
+            invalid_fim_tokens(&fim("<fim_prefix>", "<SUF>", "<PRE>"), &tokenizer),
+            ["<fim_prefix>"]
+        );
+    }
+}
@@ -5,7 +5,7 @@ use tree_sitter::{InputEdit, Parser, Point, Tree};
 use crate::error::{Error, Result};
 use crate::language_id::LanguageId;
 
-fn get_parser(language_id: LanguageId) -> Result<Parser> {
+pub(crate) fn get_parser(language_id: LanguageId) -> Result<Parser> {
     match language_id {
         LanguageId::Bash => {
             let mut parser = Parser::new();
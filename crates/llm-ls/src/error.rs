@@ -1,8 +1,22 @@
 use std::fmt::Display;
 
+use reqwest::StatusCode;
+use serde_json::json;
+use tower_lsp::jsonrpc::ErrorCode;
 use tower_lsp::{jsonrpc::Error as LspError, lsp_types::Range};
 use tracing::error;
 
+const BODY_EXCERPT_MAX_CHARS: usize = 256;
+
+// JSON-RPC codes reserved for implementation-defined server errors go from -32000 to -32099
+const UNAUTHORIZED_ERROR_CODE: i64 = -32001;
+const FORBIDDEN_ERROR_CODE: i64 = -32002;
+const NOT_FOUND_ERROR_CODE: i64 = -32003;
+const PAYLOAD_TOO_LARGE_ERROR_CODE: i64 = -32004;
+const RATE_LIMITED_ERROR_CODE: i64 = -32005;
+const SERVER_ERROR_CODE: i64 = -32006;
+const UNEXPECTED_STATUS_ERROR_CODE: i64 = -32007;
+
 pub(crate) fn internal_error<E: Display>(err: E) -> LspError {
     let err_msg = err.to_string();
     error!(err_msg);
@@ -15,8 +29,20 @@ pub(crate) fn internal_error<E: Display>(err: E) -> LspError {
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
+    #[error("azure openai error: {0}")]
+    AzureOpenAI(crate::backend::AzureOpenAIError),
+    #[error("too many requests queued for the backend")]
+    BackendQueueFull,
+    #[error("request cancelled")]
+    Cancelled,
+    #[error("{0}")]
+    Coalesced(String),
+    #[error("custom backend error: {0}")]
+    Custom(String),
     #[error("no encoding kind provided by the client")]
     EncodingKindMissing,
+    #[error("backend denied access ({status}): {body}")]
+    Forbidden { status: StatusCode, body: String },
     #[error("http error: {0}")]
     Http(#[from] reqwest::Error),
     #[error("io error: {0}")]
@@ -25,8 +51,16 @@ pub enum Error {
     InferenceApi(crate::backend::APIError),
     #[error("You are attempting to parse a result in the API inference format when using the `tgi` backend")]
     InvalidBackend,
+    #[error("invalid custom backend configuration: {0}")]
+    InvalidCustomBackend(String),
+    #[error("invalid header name: {0}")]
+    InvalidHeaderName(#[from] reqwest::header::InvalidHeaderName),
     #[error("invalid header value: {0}")]
     InvalidHeaderValue(#[from] reqwest::header::InvalidHeaderValue),
+    #[error("invalid params: {0}")]
+    InvalidParams(String),
+    #[error("invalid prompt template: {0}")]
+    InvalidPromptTemplate(String),
     #[error("range out of bounds: {0:?}")]
     InvalidRange(Range),
     #[error("invalid repository id")]
@@ -35,6 +69,10 @@ pub enum Error {
     InvalidTokenizerPath,
     #[error("llama.cpp error: {0}")]
     LlamaCpp(crate::backend::APIError),
+    #[error("listing models is not supported by the {0} backend")]
+    ModelListingUnsupported(String),
+    #[error("backend resource not found, check the url and model ({status}): {body}")]
+    NotFound { status: StatusCode, body: String },
     #[error("ollama error: {0}")]
     Ollama(crate::backend::APIError),
     #[error("openai error: {0}")]
@@ -45,10 +83,25 @@ pub enum Error {
     OutOfBoundLine(usize, usize),
     #[error("slice out of bounds: {0}..{1}")]
     OutOfBoundSlice(usize, usize),
+    #[error("prompt is too large for the backend ({status}): {body}")]
+    PayloadTooLarge { status: StatusCode, body: String },
+    #[error(
+        "the prompt takes {tokens} tokens, more than the context window of {context_window} tokens"
+    )]
+    PromptTooLarge {
+        tokens: usize,
+        context_window: usize,
+    },
+    #[error("{0} is the name of a builtin backend")]
+    ReservedBackendName(String),
+    #[error("backend is rate limiting requests ({status}): {body}")]
+    RateLimited { status: StatusCode, body: String },
     #[error("rope error: {0}")]
     Rope(#[from] ropey::Error),
     #[error("serde json error: {0}")]
     SerdeJson(#[from] serde_json::Error),
+    #[error("backend server error ({status}): {body}")]
+    ServerError { status: StatusCode, body: String },
     #[error("tgi error: {0}")]
     Tgi(crate::backend::APIError),
     #[error("tree-sitter parse error: timeout possibly exceeded")]
@@ -59,16 +112,194 @@ pub enum Error {
     Tokenizer(#[from] tokenizers::Error),
     #[error("tokio join error: {0}")]
     TokioJoin(#[from] tokio::task::JoinError),
+    #[error("backend rejected the credentials ({status}): {body}")]
+    Unauthorized { status: StatusCode, body: String },
+    #[error("backend replied with an unexpected status ({status}): {body}")]
+    UnexpectedStatus { status: StatusCode, body: String },
     #[error("unknown backend: {0}")]
     UnknownBackend(String),
     #[error("unknown encoding kind: {0}")]
     UnknownEncodingKind(String),
 }
 
-pub(crate) type Result<T> = std::result::Result<T, Error>;
+impl Error {
+    /// Maps a non success HTTP status to its dedicated error, keeping an excerpt of the body since
+    /// error pages can be arbitrarily large.
+    ///
+    /// Returns `None` for statuses that the backend specific error parsing should handle.
+    pub(crate) fn from_status(status: StatusCode, body: &str) -> Option<Self> {
+        let body = body_excerpt(body);
+        match status {
+            StatusCode::UNAUTHORIZED => Some(Error::Unauthorized { status, body }),
+            StatusCode::FORBIDDEN => Some(Error::Forbidden { status, body }),
+            StatusCode::NOT_FOUND => Some(Error::NotFound { status, body }),
+            StatusCode::PAYLOAD_TOO_LARGE => Some(Error::PayloadTooLarge { status, body }),
+            StatusCode::TOO_MANY_REQUESTS => Some(Error::RateLimited { status, body }),
+            status if status.is_server_error() => Some(Error::ServerError { status, body }),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn status(&self) -> Option<StatusCode> {
+        match self {
+            Error::Forbidden { status, .. }
+            | Error::NotFound { status, .. }
+            | Error::PayloadTooLarge { status, .. }
+            | Error::RateLimited { status, .. }
+            | Error::ServerError { status, .. }
+            | Error::Unauthorized { status, .. }
+            | Error::UnexpectedStatus { status, .. } => Some(*status),
+            _ => None,
+        }
+    }
+
+    /// Builds an equivalent error for the requests that were coalesced with the failed one, only
+    /// keeping the message of errors that cannot be cloned.
+    pub(crate) fn duplicate(&self) -> Self {
+        match self {
+            Error::BackendQueueFull => Error::BackendQueueFull,
+            Error::Cancelled => Error::Cancelled,
+            Error::Forbidden { status, body } => Error::Forbidden {
+                status: *status,
+                body: body.clone(),
+            },
+            Error::NotFound { status, body } => Error::NotFound {
+                status: *status,
+                body: body.clone(),
+            },
+            Error::PayloadTooLarge { status, body } => Error::PayloadTooLarge {
+                status: *status,
+                body: body.clone(),
+            },
+            Error::RateLimited { status, body } => Error::RateLimited {
+                status: *status,
+                body: body.clone(),
+            },
+            Error::ServerError { status, body } => Error::ServerError {
+                status: *status,
+                body: body.clone(),
+            },
+            Error::Unauthorized { status, body } => Error::Unauthorized {
+                status: *status,
+                body: body.clone(),
+            },
+            Error::UnexpectedStatus { status, body } => Error::UnexpectedStatus {
+                status: *status,
+                body: body.clone(),
+            },
+            err => Error::Coalesced(err.to_string()),
+        }
+    }
+
+    /// Whether sending the same request to the same backend again may succeed.
+    pub(crate) fn should_retry(&self) -> bool {
+        match self {
+            Error::Http(err) => err.is_connect() || err.is_timeout(),
+            Error::RateLimited { status, .. } | Error::ServerError { status, .. } => {
+                crate::retry::is_retryable_status(*status)
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether the backend failed in a way another backend may not, i.e. it is unreachable, too
+    /// slow, overloaded or rate limiting us.
+    pub(crate) fn should_fall_back(&self) -> bool {
+        match self {
+            Error::Http(err) => err.is_connect() || err.is_timeout(),
+            Error::RateLimited { .. } | Error::ServerError { .. } => true,
+            _ => false,
+        }
+    }
+}
+
+pub(crate) fn body_excerpt(body: &str) -> String {
+    let body = body.trim();
+    match body.char_indices().nth(BODY_EXCERPT_MAX_CHARS) {
+        Some((idx, _)) => format!("{}…", &body[..idx]),
+        None => body.to_owned(),
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
 
 impl From<Error> for LspError {
     fn from(err: Error) -> Self {
-        internal_error(err)
+        let code = match err {
+            Error::Cancelled => {
+                return LspError {
+                    code: ErrorCode::RequestCancelled,
+                    message: err.to_string().into(),
+                    data: None,
+                }
+            }
+            Error::InvalidParams(_) => {
+                return LspError {
+                    code: ErrorCode::InvalidParams,
+                    message: err.to_string().into(),
+                    data: None,
+                }
+            }
+            Error::Forbidden { .. } => FORBIDDEN_ERROR_CODE,
+            Error::NotFound { .. } => NOT_FOUND_ERROR_CODE,
+            Error::PayloadTooLarge { .. } => PAYLOAD_TOO_LARGE_ERROR_CODE,
+            Error::RateLimited { .. } => RATE_LIMITED_ERROR_CODE,
+            Error::ServerError { .. } => SERVER_ERROR_CODE,
+            Error::Unauthorized { .. } => UNAUTHORIZED_ERROR_CODE,
+            Error::UnexpectedStatus { .. } => UNEXPECTED_STATUS_ERROR_CODE,
+            err => return internal_error(err),
+        };
+        let message = err.to_string();
+        error!(err_msg = message);
+        let data = match &err {
+            Error::Forbidden { status, body }
+            | Error::NotFound { status, body }
+            | Error::PayloadTooLarge { status, body }
+            | Error::RateLimited { status, body }
+            | Error::ServerError { status, body }
+            | Error::Unauthorized { status, body }
+            | Error::UnexpectedStatus { status, body } => {
+                Some(json!({ "status": status.as_u16(), "body": body }))
+            }
+            _ => None,
+        };
+        LspError {
+            code: ErrorCode::ServerError(code),
+            message: message.into(),
+            data,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_status() {
+        let err = Error::from_status(StatusCode::UNAUTHORIZED, "<html>401</html>").unwrap();
+        assert!(matches!(err, Error::Unauthorized { .. }));
+        let err = Error::from_status(StatusCode::BAD_GATEWAY, "bad gateway").unwrap();
+        assert!(err.should_retry());
+        assert!(err.should_fall_back());
+        assert!(Error::from_status(StatusCode::UNPROCESSABLE_ENTITY, "{}").is_none());
+    }
+
+    #[test]
+    fn test_lsp_error_code() {
+        let err = Error::from_status(StatusCode::NOT_FOUND, "not found").unwrap();
+        let lsp_err = LspError::from(err);
+        assert_eq!(lsp_err.code, ErrorCode::ServerError(NOT_FOUND_ERROR_CODE));
+        assert_eq!(
+            lsp_err.data,
+            Some(json!({ "status": 404, "body": "not found" }))
+        );
+    }
+
+    #[test]
+    fn test_body_excerpt() {
+        let body = "é".repeat(BODY_EXCERPT_MAX_CHARS + 10);
+        let excerpt = body_excerpt(&body);
+        assert_eq!(excerpt.chars().count(), BODY_EXCERPT_MAX_CHARS + 1);
     }
 }
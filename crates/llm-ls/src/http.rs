@@ -0,0 +1,93 @@
+use custom_types::llm_ls::{BackendParams, GetCompletionsParams};
+use reqwest::{Certificate, Identity, Proxy};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use tokio::sync::RwLock;
+use tracing::info;
+
+use crate::error::Result;
+
+/// The settings a [`reqwest::Client`] is built with. Requests sharing the same settings reuse the
+/// same client, and with it its connection pool.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub(crate) struct ClientConfig {
+    tls_skip_verify_insecure: bool,
+    proxy: Option<String>,
+    ca_cert_path: Option<PathBuf>,
+    client_cert_path: Option<PathBuf>,
+    client_key_path: Option<PathBuf>,
+}
+
+impl From<&GetCompletionsParams> for ClientConfig {
+    fn from(params: &GetCompletionsParams) -> Self {
+        Self {
+            tls_skip_verify_insecure: params.tls_skip_verify_insecure,
+            proxy: params.proxy.clone(),
+            ca_cert_path: params.ca_cert_path.clone(),
+            client_cert_path: params.client_cert_path.clone(),
+            client_key_path: params.client_key_path.clone(),
+        }
+    }
+}
+
+impl From<&BackendParams> for ClientConfig {
+    fn from(params: &BackendParams) -> Self {
+        Self {
+            tls_skip_verify_insecure: params.tls_skip_verify_insecure,
+            proxy: params.proxy.clone(),
+            ca_cert_path: params.ca_cert_path.clone(),
+            client_cert_path: params.client_cert_path.clone(),
+            client_key_path: params.client_key_path.clone(),
+        }
+    }
+}
+
+impl ClientConfig {
+    async fn build(&self) -> Result<reqwest::Client> {
+        info!(config = ?self, "building http client");
+        let mut builder = reqwest::Client::builder();
+        if self.tls_skip_verify_insecure {
+            info!("tls verification is disabled");
+            builder = builder.danger_accept_invalid_certs(true);
+        }
+        if let Some(proxy) = &self.proxy {
+            builder = builder.proxy(Proxy::all(proxy)?);
+        }
+        if let Some(path) = &self.ca_cert_path {
+            // every certificate of the bundle is added when using rustls
+            let pem = tokio::fs::read(path).await?;
+            builder = builder.add_root_certificate(Certificate::from_pem(&pem)?);
+        }
+        if let Some(path) = &self.client_cert_path {
+            let mut pem = match &self.client_key_path {
+                Some(key_path) => tokio::fs::read(key_path).await?,
+                None => vec![],
+            };
+            pem.extend(tokio::fs::read(path).await?);
+            builder = builder.identity(Identity::from_pem(&pem)?);
+        }
+        Ok(builder.build()?)
+    }
+}
+
+/// HTTP clients built so far, keyed by their configuration.
+#[derive(Default)]
+pub(crate) struct HttpClients {
+    clients: RwLock<HashMap<ClientConfig, reqwest::Client>>,
+}
+
+impl HttpClients {
+    pub(crate) async fn get(&self, config: &ClientConfig) -> Result<reqwest::Client> {
+        if let Some(client) = self.clients.read().await.get(config) {
+            return Ok(client.clone());
+        }
+        let client = config.build().await?;
+        Ok(self
+            .clients
+            .write()
+            .await
+            .entry(config.clone())
+            .or_insert(client)
+            .clone())
+    }
+}
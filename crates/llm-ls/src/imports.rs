@@ -0,0 +1,762 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use tower_lsp::lsp_types::WorkspaceFolder;
+use tree_sitter::{Node, Tree};
+
+use crate::document::get_parser;
+use crate::language_id::LanguageId;
+use crate::snippets::{identifiers, relative_file_path, Snippet};
+
+const TYPESCRIPT_EXTENSIONS: &[&str] = &[
+    "ts",
+    "tsx",
+    "d.ts",
+    "js",
+    "jsx",
+    "index.ts",
+    "index.tsx",
+    "index.js",
+];
+
+/// A workspace file the current document imports from.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Import {
+    pub(crate) path: PathBuf,
+    /// The imported symbols, `None` when the whole module is imported
+    pub(crate) names: Option<Vec<String>>,
+}
+
+fn node_text<'a>(node: Node, src: &'a str) -> &'a str {
+    &src[node.byte_range()]
+}
+
+fn named_children<'a>(node: Node<'a>) -> Vec<Node<'a>> {
+    let mut cursor = node.walk();
+    node.named_children(&mut cursor).collect()
+}
+
+fn push_import(imports: &mut Vec<Import>, path: PathBuf, names: Option<Vec<String>>) {
+    match imports.iter_mut().find(|import| import.path == path) {
+        Some(import) => match (&mut import.names, names) {
+            (Some(existing), Some(names)) => existing.extend(names),
+            (existing, _) => *existing = None,
+        },
+        None => imports.push(Import { path, names }),
+    }
+}
+
+fn python_module(
+    module: &str,
+    file: &Path,
+    workspace_folders: &[WorkspaceFolder],
+) -> Option<PathBuf> {
+    let dots = module.chars().take_while(|c| *c == '.').count();
+    let relative: PathBuf = module[dots..]
+        .split('.')
+        .filter(|s| !s.is_empty())
+        .collect();
+    let roots: Vec<PathBuf> = if dots > 0 {
+        let mut base = file.parent()?;
+        for _ in 1..dots {
+            base = base.parent()?;
+        }
+        vec![base.to_path_buf()]
+    } else {
+        workspace_folders
+            .iter()
+            .filter_map(|folder| folder.uri.to_file_path().ok())
+            .flat_map(|root| [root.join("src"), root])
+            .collect()
+    };
+    roots.into_iter().find_map(|root| {
+        let path = root.join(&relative);
+        [path.with_extension("py"), path.join("__init__.py")]
+            .into_iter()
+            .find(|candidate| candidate.is_file())
+    })
+}
+
+fn python_imports(
+    root: Node,
+    src: &str,
+    file: &Path,
+    workspace_folders: &[WorkspaceFolder],
+    imports: &mut Vec<Import>,
+) {
+    for node in named_children(root) {
+        match node.kind() {
+            "import_statement" => {
+                let mut cursor = node.walk();
+                for name in node.children_by_field_name("name", &mut cursor) {
+                    let name = name.child_by_field_name("name").unwrap_or(name);
+                    if let Some(path) = python_module(node_text(name, src), file, workspace_folders)
+                    {
+                        push_import(imports, path, None);
+                    }
+                }
+            }
+            "import_from_statement" => {
+                let Some(module) = node.child_by_field_name("module_name") else {
+                    continue;
+                };
+                let module = node_text(module, src);
+                let mut cursor = node.walk();
+                let names: Vec<&str> = node
+                    .children_by_field_name("name", &mut cursor)
+                    .map(|name| node_text(name.child_by_field_name("name").unwrap_or(name), src))
+                    .collect();
+                let Some(path) = python_module(module, file, workspace_folders) else {
+                    continue;
+                };
+                let mut symbols = vec![];
+                for name in &names {
+                    // `from package import module`
+                    let submodule = if module.ends_with('.') {
+                        format!("{module}{name}")
+                    } else {
+                        format!("{module}.{name}")
+                    };
+                    match python_module(&submodule, file, workspace_folders) {
+                        Some(path) => push_import(imports, path, None),
+                        None => symbols.push(name.to_string()),
+                    }
+                }
+                if names.is_empty() {
+                    push_import(imports, path, None);
+                } else if !symbols.is_empty() {
+                    push_import(imports, path, Some(symbols));
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The paths of a use tree, as segments, e.g. `a::{b, c::d}` is `[a, b]` and `[a, c, d]`.
+fn rust_use_paths(node: Node, src: &str, prefix: &[String], paths: &mut Vec<Vec<String>>) {
+    let segments = |node: Node| -> Vec<String> {
+        prefix
+            .iter()
+            .cloned()
+            .chain(
+                node_text(node, src)
+                    .split("::")
+                    .map(|s| s.trim().to_owned()),
+            )
+            .collect()
+    };
+    match node.kind() {
+        "use_as_clause" => {
+            if let Some(path) = node.child_by_field_name("path") {
+                rust_use_paths(path, src, prefix, paths);
+            }
+        }
+        "scoped_use_list" => {
+            let prefix = match node.child_by_field_name("path") {
+                Some(path) => segments(path),
+                None => prefix.to_vec(),
+            };
+            if let Some(list) = node.child_by_field_name("list") {
+                rust_use_paths(list, src, &prefix, paths);
+            }
+        }
+        "use_list" => {
+            for child in named_children(node) {
+                rust_use_paths(child, src, prefix, paths);
+            }
+        }
+        _ => paths.push(segments(node)),
+    }
+}
+
+/// The directory of the submodules of the module defined in `file`.
+fn rust_module_dir(file: &Path) -> Option<PathBuf> {
+    let parent = file.parent()?;
+    match file.file_stem()?.to_str()? {
+        "lib" | "main" | "mod" => Some(parent.to_path_buf()),
+        stem => Some(parent.join(stem)),
+    }
+}
+
+/// The file of the submodule `name` of the module whose submodules are in `dir`.
+fn rust_submodule(dir: &Path, name: &str) -> Option<PathBuf> {
+    [
+        dir.join(name).with_extension("rs"),
+        dir.join(name).join("mod.rs"),
+    ]
+    .into_iter()
+    .find(|candidate| candidate.is_file())
+}
+
+/// The file defining the module whose submodules are in `dir`.
+fn rust_module_file(dir: &Path) -> Option<PathBuf> {
+    [
+        dir.join("mod.rs"),
+        dir.with_extension("rs"),
+        dir.join("lib.rs"),
+        dir.join("main.rs"),
+    ]
+    .into_iter()
+    .find(|candidate| candidate.is_file())
+}
+
+fn rust_import(segments: &[String], file: &Path) -> Option<Import> {
+    let (first, mut rest) = segments.split_first()?;
+    let mut dir = match first.as_str() {
+        "crate" => {
+            let manifest_dir = file
+                .ancestors()
+                .find(|dir| dir.join("Cargo.toml").is_file())?;
+            manifest_dir.join("src")
+        }
+        "self" => rust_module_dir(file)?,
+        "super" => {
+            let mut dir = rust_module_dir(file)?.parent()?.to_path_buf();
+            while rest.first().is_some_and(|segment| segment == "super") {
+                dir = dir.parent()?.to_path_buf();
+                rest = &rest[1..];
+            }
+            dir
+        }
+        // external crates
+        _ => return None,
+    };
+    let mut path = if first == "self" {
+        file.to_path_buf()
+    } else {
+        rust_module_file(&dir)?
+    };
+    while let Some((segment, remaining)) = rest.split_first() {
+        let Some(module) = rust_submodule(&dir, segment) else {
+            break;
+        };
+        dir = dir.join(segment);
+        path = module;
+        rest = remaining;
+    }
+    let names = match rest.first().map(String::as_str) {
+        None | Some("*" | "self") => None,
+        Some(name) => Some(vec![name.to_owned()]),
+    };
+    Some(Import { path, names })
+}
+
+fn rust_imports(root: Node, src: &str, file: &Path, imports: &mut Vec<Import>) {
+    for node in named_children(root) {
+        if node.kind() != "use_declaration" {
+            continue;
+        }
+        let Some(argument) = node.child_by_field_name("argument") else {
+            continue;
+        };
+        let mut paths = vec![];
+        rust_use_paths(argument, src, &[], &mut paths);
+        for segments in paths {
+            if let Some(import) = rust_import(&segments, file) {
+                push_import(imports, import.path, import.names);
+            }
+        }
+    }
+}
+
+fn typescript_imports(root: Node, src: &str, file: &Path, imports: &mut Vec<Import>) {
+    for node in named_children(root) {
+        if node.kind() != "import_statement" {
+            continue;
+        }
+        let Some(source) = node.child_by_field_name("source") else {
+            continue;
+        };
+        let source = node_text(source, src).trim_matches(|c| c == '"' || c == '\'' || c == '`');
+        // packages resolve to node_modules, which aren't part of the workspace
+        if !source.starts_with('.') {
+            continue;
+        }
+        let Some(base) = file.parent().map(|parent| parent.join(source)) else {
+            continue;
+        };
+        let Some(path) = TYPESCRIPT_EXTENSIONS
+            .iter()
+            .map(|extension| match extension.strip_prefix("index.") {
+                Some(extension) => base.join("index").with_extension(extension),
+                None => PathBuf::from(format!("{}.{extension}", base.display())),
+            })
+            .find(|candidate| candidate.is_file())
+        else {
+            continue;
+        };
+        let mut names = Some(vec![]);
+        for clause in named_children(node)
+            .into_iter()
+            .filter(|n| n.kind() == "import_clause")
+        {
+            for child in named_children(clause) {
+                match child.kind() {
+                    "named_imports" => {
+                        let Some(names) = &mut names else {
+                            continue;
+                        };
+                        for specifier in named_children(child) {
+                            if let Some(name) = specifier.child_by_field_name("name") {
+                                names.push(node_text(name, src).to_owned());
+                            }
+                        }
+                    }
+                    // default and namespace imports are renamed by the importer
+                    _ => names = None,
+                }
+            }
+        }
+        push_import(imports, path, names);
+    }
+}
+
+/// The module path declared in the `go.mod` closest to `file`, with its directory.
+fn go_module(file: &Path) -> Option<(String, PathBuf)> {
+    file.ancestors().find_map(|dir| {
+        let go_mod = std::fs::read_to_string(dir.join("go.mod")).ok()?;
+        let module = go_mod
+            .lines()
+            .find_map(|line| line.trim().strip_prefix("module "))?;
+        Some((
+            module.trim().trim_matches('"').to_owned(),
+            dir.to_path_buf(),
+        ))
+    })
+}
+
+fn go_imports(root: Node, src: &str, file: &Path, imports: &mut Vec<Import>) {
+    let mut specs = vec![];
+    for node in named_children(root) {
+        if node.kind() != "import_declaration" {
+            continue;
+        }
+        for child in named_children(node) {
+            match child.kind() {
+                "import_spec" => specs.push(child),
+                "import_spec_list" => specs.extend(named_children(child)),
+                _ => {}
+            }
+        }
+    }
+    if specs.is_empty() {
+        return;
+    }
+    let Some((module, module_dir)) = go_module(file) else {
+        return;
+    };
+    for spec in specs {
+        let Some(path) = spec.child_by_field_name("path") else {
+            continue;
+        };
+        let path = node_text(path, src).trim_matches(|c| c == '"' || c == '`');
+        let Some(package) = path
+            .strip_prefix(module.as_str())
+            .filter(|package| package.is_empty() || package.starts_with('/'))
+        else {
+            continue;
+        };
+        let Ok(entries) = std::fs::read_dir(module_dir.join(package.trim_start_matches('/')))
+        else {
+            continue;
+        };
+        let mut files: Vec<PathBuf> = entries
+            .filter_map(|entry| Some(entry.ok()?.path()))
+            .filter(|path| {
+                path.extension().is_some_and(|extension| extension == "go")
+                    && !path.to_string_lossy().ends_with("_test.go")
+            })
+            .collect();
+        files.sort();
+        for path in files {
+            push_import(imports, path, None);
+        }
+    }
+}
+
+/// Resolves the imports of the document to the workspace files they refer to, for Python, Rust,
+/// TypeScript and Go.
+///
+/// Relative imports may point anywhere, only the files inside the workspace folders are kept.
+pub(crate) fn find_imports(
+    language_id: LanguageId,
+    tree: &Tree,
+    src: &str,
+    file: &Path,
+    workspace_folders: &[WorkspaceFolder],
+) -> Vec<Import> {
+    let root = tree.root_node();
+    let mut imports = vec![];
+    match language_id {
+        LanguageId::Python => python_imports(root, src, file, workspace_folders, &mut imports),
+        LanguageId::Rust => rust_imports(root, src, file, &mut imports),
+        LanguageId::JavaScript
+        | LanguageId::JavaScriptReact
+        | LanguageId::TypeScript
+        | LanguageId::TypeScriptReact => typescript_imports(root, src, file, &mut imports),
+        LanguageId::Go => go_imports(root, src, file, &mut imports),
+        _ => {}
+    }
+    let roots: Vec<PathBuf> = workspace_folders
+        .iter()
+        .filter_map(|folder| folder.uri.to_file_path().ok()?.canonicalize().ok())
+        .collect();
+    let file = file.canonicalize().unwrap_or_else(|_| file.to_path_buf());
+    imports
+        .into_iter()
+        .filter_map(|import| {
+            // resolves the `..` of relative imports as well as symlinks
+            let path = import.path.canonicalize().ok()?;
+            (path != file && roots.iter().any(|root| path.starts_with(root))).then_some(Import {
+                path,
+                names: import.names,
+            })
+        })
+        .collect()
+}
+
+/// The text of `node` up to its body, e.g. a function's signature.
+fn signature(node: Node, src: &str) -> String {
+    match node.child_by_field_name("body") {
+        Some(body) => src[node.start_byte()..body.start_byte()]
+            .trim_end()
+            .to_owned(),
+        None => node_text(node, src).to_owned(),
+    }
+}
+
+/// A definition with the bodies of its functions left out.
+struct Definition {
+    name: String,
+    text: String,
+}
+
+impl Definition {
+    fn new(node: Node, src: &str, text: String) -> Option<Self> {
+        let name = node.child_by_field_name("name")?;
+        Some(Self {
+            name: node_text(name, src).to_owned(),
+            text,
+        })
+    }
+}
+
+fn function_signature(node: Node, src: &str, language_id: LanguageId) -> String {
+    let signature = signature(node, src);
+    match language_id {
+        LanguageId::Python => format!("{signature} ..."),
+        LanguageId::Go => signature,
+        _ => format!("{signature};"),
+    }
+}
+
+/// A class, trait or impl header followed by the signatures of its members.
+fn container(node: Node, src: &str, language_id: LanguageId) -> String {
+    let mut text = signature(node, src);
+    let python = language_id == LanguageId::Python;
+    text.push_str(if python { "\n" } else { " {\n" });
+    if let Some(body) = node.child_by_field_name("body") {
+        for member in named_children(body) {
+            let member = match member.kind() {
+                "decorated_definition" => match member.child_by_field_name("definition") {
+                    Some(definition) => definition,
+                    None => continue,
+                },
+                _ => member,
+            };
+            let member = match member.kind() {
+                "function_definition" | "function_item" | "method_definition" => {
+                    function_signature(member, src, language_id)
+                }
+                "expression_statement" if python => match member.named_child(0) {
+                    Some(child) if child.kind() == "assignment" => {
+                        node_text(member, src).to_owned()
+                    }
+                    _ => continue,
+                },
+                "function_signature_item"
+                | "associated_type"
+                | "const_item"
+                | "type_item"
+                | "public_field_definition"
+                | "method_signature"
+                | "abstract_method_signature"
+                | "property_signature" => node_text(member, src).to_owned(),
+                _ => continue,
+            };
+            text.push_str("    ");
+            text.push_str(&member);
+            text.push('\n');
+        }
+    }
+    if python {
+        text.truncate(text.trim_end().len());
+    } else {
+        text.push('}');
+    }
+    text
+}
+
+/// The name of the type a Go method is declared on.
+fn go_receiver(node: Node, src: &str) -> Option<String> {
+    let receiver = node.child_by_field_name("receiver")?;
+    let parameter = receiver.named_child(0)?;
+    let receiver_type = node_text(parameter.child_by_field_name("type")?, src);
+    let receiver_type = receiver_type.trim_start_matches('*');
+    Some(
+        receiver_type
+            .split('[')
+            .next()
+            .unwrap_or(receiver_type)
+            .to_owned(),
+    )
+}
+
+fn definition(node: Node, src: &str, language_id: LanguageId) -> Option<Definition> {
+    match node.kind() {
+        "decorated_definition" => {
+            definition(node.child_by_field_name("definition")?, src, language_id)
+        }
+        "export_statement" => {
+            definition(node.child_by_field_name("declaration")?, src, language_id)
+        }
+        "function_definition" | "function_item" | "function_declaration" => {
+            Definition::new(node, src, function_signature(node, src, language_id))
+        }
+        "class_definition" | "class_declaration" | "abstract_class_declaration" | "trait_item" => {
+            Definition::new(node, src, container(node, src, language_id))
+        }
+        "impl_item" => {
+            let impl_type = node.child_by_field_name("type")?;
+            let impl_type = impl_type.child_by_field_name("type").unwrap_or(impl_type);
+            Some(Definition {
+                name: node_text(impl_type, src).to_owned(),
+                text: container(node, src, language_id),
+            })
+        }
+        "struct_item"
+        | "enum_item"
+        | "union_item"
+        | "type_item"
+        | "const_item"
+        | "static_item"
+        | "interface_declaration"
+        | "type_alias_declaration"
+        | "enum_declaration" => Definition::new(node, src, node_text(node, src).to_owned()),
+        "lexical_declaration" => {
+            let declarator = node.named_child(0)?;
+            let text = match declarator.child_by_field_name("value") {
+                Some(value) => format!(
+                    "{}{};",
+                    &src[node.start_byte()..declarator.start_byte()],
+                    src[declarator.start_byte()..value.start_byte()]
+                        .trim_end()
+                        .trim_end_matches('=')
+                        .trim_end()
+                ),
+                None => node_text(node, src).to_owned(),
+            };
+            Definition::new(declarator, src, text)
+        }
+        "method_declaration" => Some(Definition {
+            name: go_receiver(node, src)?,
+            text: function_signature(node, src, language_id),
+        }),
+        "type_declaration" => {
+            let spec = node.named_child(0)?;
+            Definition::new(spec, src, node_text(node, src).to_owned())
+        }
+        _ => None,
+    }
+}
+
+/// Extracts the signatures of the definitions of `path` that are imported or used in the current
+/// document, returning `None` when there are none.
+fn imported_snippet(
+    import: &Import,
+    used: &HashSet<&str>,
+    workspace_folders: &[WorkspaceFolder],
+) -> Option<Snippet> {
+    let extension = import.path.extension()?.to_str()?;
+    let language_id = LanguageId::from_extension(extension);
+    let src = std::fs::read_to_string(&import.path).ok()?;
+    let mut parser = get_parser(language_id).ok()?;
+    let tree = parser.parse(&src, None)?;
+    let definitions: Vec<String> = named_children(tree.root_node())
+        .into_iter()
+        .filter_map(|node| definition(node, &src, language_id))
+        .filter(|definition| match &import.names {
+            Some(names) => names.contains(&definition.name),
+            None => used.contains(definition.name.as_str()),
+        })
+        .map(|definition| definition.text)
+        .collect();
+    if definitions.is_empty() {
+        return None;
+    }
+    Some(Snippet {
+        path: relative_file_path(&import.path, workspace_folders),
+        text: definitions.join("\n\n"),
+        // the symbols are known to be used, rank them above the similarity based snippets
+        score: 1.0,
+    })
+}
+
+/// Reads the imported files and keeps the signatures of the symbols the current document uses,
+/// one snippet per file.
+pub(crate) fn imported_definitions(
+    imports: &[Import],
+    text: &str,
+    workspace_folders: &[WorkspaceFolder],
+    max_snippets: usize,
+) -> Vec<Snippet> {
+    let used: HashSet<&str> = identifiers(text).collect();
+    imports
+        .iter()
+        .filter_map(|import| imported_snippet(import, &used, workspace_folders))
+        .take(max_snippets)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use tower_lsp::lsp_types::Url;
+
+    use super::*;
+
+    /// Writes `files` under a new directory, the workspace folder being its `ws` subdirectory.
+    fn workspace(files: &[(&str, &str)]) -> (PathBuf, Vec<WorkspaceFolder>) {
+        let dir = std::env::temp_dir().join(format!("llm-ls-imports-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        // imports are resolved to canonical paths
+        let dir = dir.canonicalize().unwrap();
+        for (path, text) in files {
+            let path = dir.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, text).unwrap();
+        }
+        let folder = WorkspaceFolder {
+            uri: Url::from_file_path(dir.join("ws")).unwrap(),
+            name: "ws".to_owned(),
+        };
+        (dir, vec![folder])
+    }
+
+    fn imports(
+        dir: &Path,
+        file: &str,
+        language_id: LanguageId,
+        folders: &[WorkspaceFolder],
+    ) -> Vec<(String, Option<Vec<String>>)> {
+        let file = dir.join(file);
+        let src = std::fs::read_to_string(&file).unwrap();
+        let tree = get_parser(language_id).unwrap().parse(&src, None).unwrap();
+        find_imports(language_id, &tree, &src, &file, folders)
+            .into_iter()
+            .map(|import| {
+                let path = import.path.strip_prefix(dir).unwrap();
+                (path.display().to_string(), import.names)
+            })
+            .collect()
+    }
+
+    fn names(names: &[&str]) -> Option<Vec<String>> {
+        Some(names.iter().map(|name| name.to_string()).collect())
+    }
+
+    #[test]
+    fn test_typescript_imports() {
+        let (dir, folders) = workspace(&[
+            (
+                "ws/src/app.ts",
+                "import { a, b } from \"./util\";\nimport * as m from '../lib/mod';\nimport React from \"react\";\nimport { secret } from \"../../outside\";\n",
+            ),
+            ("ws/src/util.ts", "export const a = 1;\n"),
+            ("ws/lib/mod/index.ts", "export function f() {}\n"),
+            ("outside.ts", "export const secret = 1;\n"),
+        ]);
+        assert_eq!(
+            imports(&dir, "ws/src/app.ts", LanguageId::TypeScript, &folders),
+            [
+                ("ws/src/util.ts".to_owned(), names(&["a", "b"])),
+                ("ws/lib/mod/index.ts".to_owned(), None),
+            ]
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_go_imports() {
+        let (dir, folders) = workspace(&[
+            ("ws/go.mod", "module example.com/app\n\ngo 1.21\n"),
+            (
+                "ws/main.go",
+                "package main\n\nimport (\n\t\"fmt\"\n\t\"example.com/app/store\"\n)\n",
+            ),
+            ("ws/store/store.go", "package store\n"),
+            ("ws/store/store_test.go", "package store\n"),
+            ("ws/store/cache.go", "package store\n"),
+        ]);
+        assert_eq!(
+            imports(&dir, "ws/main.go", LanguageId::Go, &folders),
+            [
+                ("ws/store/cache.go".to_owned(), None),
+                ("ws/store/store.go".to_owned(), None),
+            ]
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_python_imports() {
+        let (dir, folders) = workspace(&[
+            (
+                "ws/pkg/a.py",
+                "import os\nfrom .b import f, g\nfrom . import c\nfrom ... import secret\n",
+            ),
+            ("ws/pkg/__init__.py", ""),
+            ("ws/pkg/b.py", "def f(): pass\n"),
+            ("ws/pkg/c.py", ""),
+            ("__init__.py", ""),
+            ("secret.py", "TOKEN = 1\n"),
+        ]);
+        assert_eq!(
+            imports(&dir, "ws/pkg/a.py", LanguageId::Python, &folders),
+            [
+                ("ws/pkg/b.py".to_owned(), names(&["f", "g"])),
+                ("ws/pkg/c.py".to_owned(), None),
+            ]
+        );
+        // nothing is outside of the workspace without workspace folders
+        assert!(imports(&dir, "ws/pkg/a.py", LanguageId::Python, &[]).is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn definitions(src: &str, language_id: LanguageId) -> Vec<String> {
+        let mut parser = get_parser(language_id).unwrap();
+        let tree = parser.parse(src, None).unwrap();
+        named_children(tree.root_node())
+            .into_iter()
+            .filter_map(|node| definition(node, src, language_id))
+            .map(|definition| definition.text)
+            .collect()
+    }
+
+    #[test]
+    fn test_definitions() {
+        let src = "pub struct A {\n    pub b: u32,\n}\n\nimpl A {\n    pub fn new(b: u32) -> Self {\n        Self { b }\n    }\n}\n";
+        assert_eq!(
+            definitions(src, LanguageId::Rust),
+            [
+                "pub struct A {\n    pub b: u32,\n}",
+                "impl A {\n    pub fn new(b: u32) -> Self;\n}"
+            ]
+        );
+        let src =
+            "class A(B):\n    x: int = 0\n\n    def f(self, y: int) -> int:\n        return y\n";
+        assert_eq!(
+            definitions(src, LanguageId::Python),
+            ["class A(B):\n    x: int = 0\n    def f(self, y: int) -> int: ..."]
+        );
+    }
+}
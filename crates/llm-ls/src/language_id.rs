@@ -1,7 +1,7 @@
 use serde::{Deserialize, Serialize};
 use std::fmt;
 
-#[derive(Clone, Copy, Serialize, Deserialize)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub(crate) enum LanguageId {
     Bash,
     C,
@@ -30,6 +30,63 @@ pub(crate) enum LanguageId {
     Unknown,
 }
 
+impl LanguageId {
+    /// Guesses the language of a file that isn't open from its extension.
+    pub(crate) fn from_extension(extension: &str) -> Self {
+        match extension {
+            "sh" | "bash" => Self::Bash,
+            "c" | "h" => Self::C,
+            "cc" | "cpp" | "cxx" | "hh" | "hpp" | "hxx" => Self::Cpp,
+            "cs" => Self::CSharp,
+            "ex" | "exs" => Self::Elixir,
+            "erl" | "hrl" => Self::Erlang,
+            "go" => Self::Go,
+            "htm" | "html" => Self::Html,
+            "java" => Self::Java,
+            "cjs" | "js" | "mjs" => Self::JavaScript,
+            "jsx" => Self::JavaScriptReact,
+            "json" => Self::Json,
+            "kt" | "kts" => Self::Kotlin,
+            "lua" => Self::Lua,
+            "md" => Self::Markdown,
+            "m" | "mm" => Self::ObjectiveC,
+            "py" | "pyi" => Self::Python,
+            "R" | "r" => Self::R,
+            "rb" => Self::Ruby,
+            "rs" => Self::Rust,
+            "sc" | "scala" => Self::Scala,
+            "swift" => Self::Swift,
+            "cts" | "mts" | "ts" => Self::TypeScript,
+            "tsx" => Self::TypeScriptReact,
+            _ => Self::Unknown,
+        }
+    }
+
+    /// The token starting a line comment, `None` for languages without line comments.
+    pub(crate) fn line_comment(self) -> Option<&'static str> {
+        match self {
+            Self::Bash | Self::Elixir | Self::Python | Self::R | Self::Ruby => Some("#"),
+            Self::C
+            | Self::Cpp
+            | Self::CSharp
+            | Self::Go
+            | Self::Java
+            | Self::JavaScript
+            | Self::JavaScriptReact
+            | Self::Kotlin
+            | Self::ObjectiveC
+            | Self::Rust
+            | Self::Scala
+            | Self::Swift
+            | Self::TypeScript
+            | Self::TypeScriptReact => Some("//"),
+            Self::Erlang => Some("%"),
+            Self::Lua => Some("--"),
+            Self::Html | Self::Json | Self::Markdown | Self::Unknown => None,
+        }
+    }
+}
+
 impl fmt::Display for LanguageId {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
@@ -0,0 +1,1820 @@
+use clap::Parser;
+use custom_types::llm_ls::{
+    AcceptCompletionParams, BackendConfig, BackendParams, CheckBackendResult, Completion,
+    CompletionDeltaParams, FimMode, FimOrder, FimParams, GetCompletionsParams,
+    GetCompletionsResult, Ide, InitializationOptions, ListModelsResult, MetadataFormat,
+    RejectCompletionParams, RetryConfig, ServedBy, TokenizerConfig,
+};
+use custom_types::notification::CompletionDelta;
+use ropey::Rope;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tokenizers::Tokenizer;
+use tokio::net::TcpListener;
+use tokio::sync::{oneshot, watch, RwLock};
+use tower_lsp::jsonrpc::Result as LspResult;
+use tower_lsp::lsp_types::*;
+use tower_lsp::{Client, LanguageServer, LspService, Server};
+use tracing::{debug, error, info, info_span, warn, Instrument, Span};
+use tracing_appender::rolling;
+use tracing_subscriber::EnvFilter;
+use tree_sitter::Point;
+use uuid::Uuid;
+
+use crate::backend::{
+    build_api_headers, insert_extra_headers, status_error, BackendRegistry, CompletionBackend,
+    StreamReader,
+};
+use crate::cache::{cache_key, is_cacheable, CompletionCache};
+use crate::credentials::Credentials;
+use crate::discovery::{discover_context_window, infer_fim_tokens, invalid_fim_tokens};
+use crate::document::Document;
+use crate::error::{internal_error, Error, Result};
+use crate::http::{ClientConfig, HttpClients};
+use crate::imports::{find_imports, imported_definitions};
+use crate::scheduler::{coalesce_key, BackendLimiter, Coalescer, Permit};
+use crate::snippets::{
+    metadata_comment, neighboring_tabs, query_text, relative_path, render_snippets, repo_name,
+};
+use crate::template::{PromptTemplate, TemplateValues};
+use crate::tokens::{count_tokens, TokenizedLines};
+use crate::truncation::Syntax;
+use crate::workspace_index::WorkspaceIndex;
+
+pub mod backend;
+mod cache;
+mod check;
+mod credentials;
+mod discovery;
+mod document;
+pub mod error;
+mod http;
+mod imports;
+mod language_id;
+mod presets;
+mod retry;
+mod scheduler;
+mod snippets;
+mod template;
+mod tokens;
+mod truncation;
+mod workspace_index;
+
+const MAX_WARNING_REPEAT: Duration = Duration::from_secs(3_600);
+const DISCOVERY_RETRY_INTERVAL: Duration = Duration::from_secs(60);
+pub const NAME: &str = "llm-ls";
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+fn get_position_idx(rope: &Rope, row: usize, col: usize) -> Result<usize> {
+    Ok(rope.try_line_to_char(row)?
+        + col.min(
+            rope.get_line(row.min(rope.len_lines().saturating_sub(1)))
+                .ok_or(Error::OutOfBoundLine(row, rope.len_lines()))?
+                .len_chars()
+                .saturating_sub(1),
+        ))
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+enum CompletionType {
+    Empty,
+    SingleLine,
+    MultiLine,
+}
+
+impl Display for CompletionType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CompletionType::Empty => write!(f, "empty"),
+            CompletionType::SingleLine => write!(f, "single_line"),
+            CompletionType::MultiLine => write!(f, "multi_line"),
+        }
+    }
+}
+
+fn should_complete(document: &Document, position: Position) -> Result<CompletionType> {
+    let row = position.line as usize;
+    let column = position.character as usize;
+    if document.text.len_chars() == 0 {
+        warn!("Document is empty");
+        return Ok(CompletionType::Empty);
+    }
+    if let Some(tree) = &document.tree {
+        let current_node = tree.root_node().descendant_for_point_range(
+            tree_sitter::Point { row, column },
+            tree_sitter::Point {
+                row,
+                column: column + 1,
+            },
+        );
+        if let Some(node) = current_node {
+            if node == tree.root_node() {
+                return Ok(CompletionType::MultiLine);
+            }
+            let start = node.start_position();
+            let end = node.end_position();
+            let mut start_offset = get_position_idx(&document.text, start.row, start.column)?;
+            let mut end_offset = get_position_idx(&document.text, end.row, end.column)? - 1;
+            let start_char = document
+                .text
+                .get_char(start_offset.min(document.text.len_chars().saturating_sub(1)))
+                .ok_or(Error::OutOfBoundIndexing(start_offset))?;
+            let end_char = document
+                .text
+                .get_char(end_offset.min(document.text.len_chars().saturating_sub(1)))
+                .ok_or(Error::OutOfBoundIndexing(end_offset))?;
+            if !start_char.is_whitespace() {
+                start_offset += 1;
+            }
+            if !end_char.is_whitespace() {
+                end_offset -= 1;
+            }
+            if start_offset >= end_offset {
+                return Ok(CompletionType::SingleLine);
+            }
+            let slice = document
+                .text
+                .get_slice(start_offset..end_offset)
+                .ok_or(Error::OutOfBoundSlice(start_offset, end_offset))?;
+            if slice.to_string().trim().is_empty() {
+                return Ok(CompletionType::MultiLine);
+            }
+        }
+    }
+    let start_idx = document.text.try_line_to_char(row)?;
+    // XXX: We treat the end of a document as a newline
+    let next_char = document.text.get_char(start_idx + column).unwrap_or('\n');
+    if next_char.is_whitespace() {
+        Ok(CompletionType::SingleLine)
+    } else {
+        Ok(CompletionType::Empty)
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Generation {
+    pub generated_text: String,
+}
+
+struct LlmService {
+    cache_dir: PathBuf,
+    client: Client,
+    document_map: Arc<RwLock<HashMap<String, Document>>>,
+    http_clients: HttpClients,
+    credentials: Credentials,
+    /// Whether the server listens on a TCP socket rather than on stdio
+    over_tcp: bool,
+    completion_cache: CompletionCache,
+    limiter: BackendLimiter,
+    coalescer: Coalescer,
+    backends: BackendRegistry,
+    workspace_folders: Arc<RwLock<Option<Vec<WorkspaceFolder>>>>,
+    workspace_index: WorkspaceIndex,
+    /// Whether the client can watch files for us, reset once it was asked to
+    can_watch_files: AtomicBool,
+    tokenizer_map: Arc<RwLock<HashMap<String, Arc<Tokenizer>>>>,
+    /// Context windows reported by the backends, keyed by backend, url and model, or when the
+    /// discovery last failed
+    context_windows: RwLock<HashMap<String, std::result::Result<Option<usize>, Instant>>>,
+    /// Models and FIM tokens the user was already warned about
+    fim_token_warnings: RwLock<HashSet<String>>,
+    unauthenticated_warn_at: Arc<RwLock<SystemTime>>,
+    position_encoding: Arc<RwLock<document::PositionEncodingKind>>,
+    backend_error_warn_at: Arc<RwLock<HashMap<reqwest::StatusCode, SystemTime>>>,
+    /// The latest completion request per document uri, with the sender used to cancel it
+    in_flight_requests: Arc<RwLock<HashMap<String, (Uuid, oneshot::Sender<()>)>>>,
+}
+
+/// The text surrounding the cursor that fits in the context window.
+pub struct Prompt {
+    /// Snippets of other files, placed ahead of the prefix
+    pub context: String,
+    pub prefix: String,
+    /// `None` when fill in the middle is disabled
+    pub suffix: Option<String>,
+    /// Replaces the FIM tokens when the request configures a prompt template
+    pub(crate) template: Option<Arc<PromptTemplate>>,
+}
+
+impl Prompt {
+    /// Renders the prompt as a single string, wrapping the prefix and suffix with the FIM tokens
+    /// when fill in the middle is enabled.
+    pub fn render(&self, fim: &FimParams) -> String {
+        if let Some(template) = &self.template {
+            return template.render(self);
+        }
+        match (&self.suffix, fim.order.unwrap_or_default()) {
+            (Some(suffix), FimOrder::Psm) => format!(
+                "{}{}{}{}{}{}",
+                self.context, fim.prefix, self.prefix, fim.suffix, suffix, fim.middle
+            ),
+            (Some(suffix), FimOrder::Spm) => format!(
+                "{}{}{}{}{}{}",
+                self.context, fim.prefix, fim.suffix, suffix, fim.middle, self.prefix
+            ),
+            (None, _) => format!("{}{}", self.context, self.prefix),
+        }
+    }
+
+    /// The prefix with the context ahead of it, for backends getting the suffix separately.
+    pub fn full_prefix(&self) -> String {
+        format!("{}{}", self.context, self.prefix)
+    }
+
+    /// Returns the suffix when it should be sent separately from the prefix, which a prompt
+    /// template never does.
+    pub fn native_fim_suffix(&self, fim: &FimParams) -> Option<&str> {
+        if self.template.is_some() {
+            return None;
+        }
+        match fim.mode {
+            FimMode::Native => self.suffix.as_deref(),
+            FimMode::Template => None,
+        }
+    }
+}
+
+fn build_prompt(
+    pos: Position,
+    text: &Rope,
+    fim: &FimParams,
+    tokenizer: Option<&Tokenizer>,
+    context_window: usize,
+    syntax: Option<Syntax>,
+) -> Result<Prompt> {
+    let t = Instant::now();
+    let cursor_line = pos.line as usize;
+    let col = pos.character as usize;
+    let mut before_lines = TokenizedLines::new(
+        text.lines_at(cursor_line + 1)
+            .reversed()
+            .enumerate()
+            .map(|(i, line)| match i {
+                0 => line.slice(0..col.min(line.len_chars())).to_string(),
+                _ => line.to_string(),
+            }),
+        tokenizer,
+        true,
+    );
+    if fim.enabled {
+        let fim_tokens = count_tokens(
+            tokenizer,
+            &format!("{}{}{}", fim.prefix, fim.suffix, fim.middle),
+        )?;
+        let mut remaining_token_count = context_window.saturating_sub(fim_tokens);
+        let mut after_lines = TokenizedLines::new(
+            text.lines_at(cursor_line)
+                .enumerate()
+                .map(|(i, line)| match i {
+                    0 => line.slice(col.min(line.len_chars())..).to_string(),
+                    _ => line.to_string(),
+                }),
+            tokenizer,
+            false,
+        );
+        let max_suffix_lines = fim.max_suffix_lines.unwrap_or(usize::MAX);
+        let mut before = vec![];
+        let mut after = vec![];
+        match fim.suffix_ratio {
+            Some(ratio) => {
+                let mut pending_before = None;
+                let mut pending_after = None;
+                let suffix_budget = (remaining_token_count as f32 * ratio.clamp(0.0, 1.0)) as usize;
+                let mut remaining_suffix = suffix_budget;
+                take_lines(
+                    &mut after_lines,
+                    &mut pending_after,
+                    &mut after,
+                    &mut remaining_suffix,
+                    max_suffix_lines,
+                )?;
+                // the prefix gets the budget the suffix didn't use
+                let mut remaining_prefix = remaining_token_count - suffix_budget + remaining_suffix;
+                take_lines(
+                    &mut before_lines,
+                    &mut pending_before,
+                    &mut before,
+                    &mut remaining_prefix,
+                    usize::MAX,
+                )?;
+                // and the other way around when the prefix is the start of the file
+                take_lines(
+                    &mut after_lines,
+                    &mut pending_after,
+                    &mut after,
+                    &mut remaining_prefix,
+                    max_suffix_lines,
+                )?;
+            }
+            None => {
+                let mut before_line = before_lines.next()?;
+                let mut after_line = after_lines.next()?;
+                while before_line.is_some() || after_line.is_some() {
+                    if let Some((line, tokens)) = before_line {
+                        if tokens > remaining_token_count {
+                            break;
+                        }
+                        remaining_token_count -= tokens;
+                        before.push((line, tokens));
+                    }
+                    if let Some((line, tokens)) = after_line {
+                        if tokens > remaining_token_count {
+                            break;
+                        }
+                        remaining_token_count -= tokens;
+                        after.push((line, tokens));
+                    }
+                    before_line = before_lines.next()?;
+                    after_line = if after.len() < max_suffix_lines {
+                        after_lines.next()?
+                    } else {
+                        None
+                    };
+                }
+            }
+        }
+        if let Some(syntax) = &syntax {
+            // the suffix was cut, stop it at the end of a unit
+            if cursor_line + after.len() < text.len_lines() && after.len() > 1 {
+                let last_line = cursor_line + after.len() - 1;
+                let unit_end = syntax.unit_end(cursor_line, last_line);
+                after.truncate(unit_end - cursor_line + 1);
+            }
+        }
+        let prompt = Prompt {
+            context: String::new(),
+            prefix: join_prefix(before, pos, syntax, tokenizer)?,
+            suffix: Some(after.into_iter().map(|(line, _)| line).collect()),
+            template: None,
+        };
+        let time = t.elapsed().as_millis();
+        info!(
+            prompt = prompt.render(fim),
+            build_prompt_ms = time,
+            "built prompt in {time} ms"
+        );
+        Ok(prompt)
+    } else {
+        let mut remaining_token_count = context_window;
+        let mut before = vec![];
+        while let Some((line, tokens)) = before_lines.next()? {
+            if tokens > remaining_token_count {
+                break;
+            }
+            remaining_token_count -= tokens;
+            before.push((line, tokens));
+        }
+        let prompt = Prompt {
+            context: String::new(),
+            prefix: join_prefix(before, pos, syntax, tokenizer)?,
+            suffix: None,
+            template: None,
+        };
+        let time = t.elapsed().as_millis();
+        info!(
+            prompt = prompt.prefix,
+            build_prompt_ms = time,
+            "built prompt in {time} ms"
+        );
+        Ok(prompt)
+    }
+}
+
+/// Builds the prompt with `budget` tokens for the document, rebuilding it with a smaller budget
+/// until it fits in the context window: its parts are counted separately and tokens can merge
+/// across them.
+#[allow(clippy::too_many_arguments)]
+fn fit_prompt(
+    pos: Position,
+    text: &Rope,
+    fim: &FimParams,
+    tokenizer: Option<&Tokenizer>,
+    context_window: usize,
+    mut budget: usize,
+    syntax: Option<Syntax>,
+    context: &str,
+    template: Option<&Arc<PromptTemplate>>,
+) -> Result<Prompt> {
+    loop {
+        let mut prompt = build_prompt(pos, text, fim, tokenizer, budget, syntax)?;
+        prompt.context = context.to_owned();
+        prompt.template = template.cloned();
+        let tokens = count_tokens(tokenizer, &prompt.render(fim))?;
+        if tokens <= context_window {
+            return Ok(prompt);
+        }
+        if budget == 0 {
+            return Err(Error::PromptTooLarge {
+                tokens,
+                context_window,
+            });
+        }
+        debug!(tokens, context_window, "prompt too long, building it again");
+        budget = budget.saturating_sub(tokens - context_window);
+    }
+}
+
+/// Keeps the next lines while they fit in `budget`, up to `max_lines` lines in total. The line
+/// that doesn't fit is left in `pending` for the next call.
+fn take_lines<I: Iterator<Item = String>>(
+    lines: &mut TokenizedLines<I>,
+    pending: &mut Option<(String, usize)>,
+    kept: &mut Vec<(String, usize)>,
+    budget: &mut usize,
+    max_lines: usize,
+) -> Result<()> {
+    while kept.len() < max_lines {
+        let next = match pending.take() {
+            Some(line) => Some(line),
+            None => lines.next()?,
+        };
+        let Some((line, tokens)) = next else {
+            break;
+        };
+        if tokens > *budget {
+            *pending = Some((line, tokens));
+            break;
+        }
+        *budget -= tokens;
+        kept.push((line, tokens));
+    }
+    Ok(())
+}
+
+/// Joins the lines kept before the cursor, nearest first, eliding the parts of the document that
+/// don't fit along syntactic units when its tree is known.
+fn join_prefix(
+    before: Vec<(String, usize)>,
+    pos: Position,
+    syntax: Option<Syntax>,
+    tokenizer: Option<&Tokenizer>,
+) -> Result<String> {
+    match syntax {
+        Some(syntax) if before.len() <= pos.line as usize => syntax.elide_prefix(
+            before,
+            Point::new(pos.line as usize, pos.character as usize),
+            |text| count_tokens(tokenizer, text),
+        ),
+        _ => Ok(before.into_iter().rev().map(|(line, _)| line).collect()),
+    }
+}
+
+fn primary_backend(params: &GetCompletionsParams) -> BackendConfig {
+    BackendConfig {
+        model: params.model.clone(),
+        api_token: params.api_token.clone(),
+        backend: params.backend.clone(),
+        request_timeout_ms: params.request_timeout_ms,
+        extra_headers: params.extra_headers.clone(),
+    }
+}
+
+/// Sends the completion request to a single backend, retrying according to `params.retry`.
+/// `retries` counts the retries made across the whole backend chain.
+///
+/// A slot of the backend is taken for every attempt, and given back while waiting to retry.
+#[allow(clippy::too_many_arguments)]
+async fn send_request(
+    http_client: &reqwest::Client,
+    backend: &dyn CompletionBackend,
+    limiter: &BackendLimiter,
+    prompt: &Prompt,
+    params: &GetCompletionsParams,
+    config: &BackendConfig,
+    retries: &mut u32,
+) -> Result<(reqwest::Response, Permit)> {
+    let mut json = backend.build_body(
+        config.model.clone(),
+        prompt,
+        &params.fim,
+        params.request_body.clone(),
+        params.stream,
+    );
+    backend.insert_stop(&mut json, &params.stop);
+    let mut headers = backend.build_headers(config.api_token.as_ref(), params.ide)?;
+    insert_extra_headers(&mut headers, &config.extra_headers)?;
+    let url = if params.disable_url_path_completion {
+        backend.verbatim_url(config.backend.clone().url())
+    } else {
+        backend.build_url(
+            config.backend.clone().url(),
+            &config.model,
+            params.stream,
+            params.fim.enabled && params.fim.mode == FimMode::Native,
+        )
+    };
+    info!(?headers, url, "sending request to backend");
+    debug!(?headers, body = ?json, url, "sending request to backend");
+    let uri = params.text_document_position.text_document.uri.as_str();
+    let mut attempt = 1;
+    loop {
+        let permit = limiter.acquire(config, uri, &params.concurrency).await?;
+        let mut req = http_client
+            .post(url.as_str())
+            .json(&json)
+            .headers(headers.clone());
+        if let Some(timeout) = config.request_timeout_ms {
+            req = req.timeout(Duration::from_millis(timeout));
+        }
+        let (err, hint) = match req.send().await {
+            Ok(res) if res.status().is_success() => return Ok((res, permit)),
+            Ok(res) => {
+                let status = res.status();
+                let retry_after = retry::retry_after(res.headers());
+                let body = res.text().await.unwrap_or_default();
+                let hint = retry_after.or_else(|| retry::estimated_time(&body));
+                (status_error(backend, status, &body), hint)
+            }
+            Err(err) => (err.into(), None),
+        };
+        if attempt >= params.retry.max_attempts || !err.should_retry() {
+            return Err(err);
+        }
+        drop(permit);
+        let delay = retry::backoff(&params.retry, attempt, hint);
+        warn!(
+            attempt,
+            ?delay,
+            "request to backend failed, retrying: {err}"
+        );
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+        *retries += 1;
+        Span::current().record("retries", *retries);
+    }
+}
+
+/// Requests the generations from the backend chain, the text of streamed responses is
+/// published to `streamed` as it arrives.
+async fn request_completion(
+    http_client: &reqwest::Client,
+    backends: &BackendRegistry,
+    limiter: &BackendLimiter,
+    streamed: &watch::Sender<String>,
+    prompt: Prompt,
+    params: &GetCompletionsParams,
+) -> Result<(Vec<Generation>, ServedBy)> {
+    let t = Instant::now();
+
+    let mut fallback_backends = params.fallback_backends.iter();
+    let mut config = primary_backend(params);
+    let mut retries = 0;
+    // the permit is held until the response is fully read
+    let (backend, res, _permit) = loop {
+        let backend = backends.get(&config.backend)?;
+        match send_request(
+            http_client,
+            backend.as_ref(),
+            limiter,
+            &prompt,
+            params,
+            &config,
+            &mut retries,
+        )
+        .await
+        {
+            Ok((res, permit)) => break (backend, res, permit),
+            Err(err) if err.should_fall_back() => match fallback_backends.next() {
+                Some(next) => {
+                    warn!(
+                        model = config.model,
+                        backend = ?config.backend,
+                        next_model = next.model,
+                        next_backend = ?next.backend,
+                        "backend unavailable, falling back to the next one: {err}"
+                    );
+                    config = next.clone();
+                }
+                None => return Err(err),
+            },
+            Err(err) => return Err(err),
+        }
+    };
+
+    let model = &config.model;
+    let generations = if params.stream {
+        stream_generations(streamed, backend.as_ref(), res).await?
+    } else {
+        backend.parse_generations(res.text().await?.as_str())?
+    };
+    let time = t.elapsed().as_millis();
+    info!(
+        model,
+        backend = ?config.backend,
+        compute_generations_ms = time,
+        generations = serde_json::to_string(&generations)?,
+        "{model} computed generations in {time} ms"
+    );
+    Ok((generations, ServedBy::new(config.model, &config.backend)))
+}
+
+/// Reads a streamed backend response line by line, publishing the text generated so far to
+/// `streamed`.
+async fn stream_generations(
+    streamed: &watch::Sender<String>,
+    backend: &dyn CompletionBackend,
+    mut res: reqwest::Response,
+) -> Result<Vec<Generation>> {
+    let mut reader = StreamReader::new(backend);
+    let mut done = false;
+    while !done {
+        match res.chunk().await? {
+            Some(chunk) => reader.push(&chunk)?,
+            None => {
+                reader.finish()?;
+                done = true;
+            }
+        }
+        let preview = reader.preview();
+        streamed.send_if_modified(|streamed| {
+            if streamed.len() >= preview.len() {
+                return false;
+            }
+            preview.clone_into(streamed);
+            true
+        });
+    }
+    Ok(vec![Generation {
+        generated_text: reader.into_generated_text(),
+    }])
+}
+
+/// The text streamed past the `sent` bytes already forwarded to the client.
+fn next_delta(streamed: &mut watch::Receiver<String>, sent: &mut usize) -> Option<String> {
+    let streamed = streamed.borrow_and_update();
+    let delta = streamed.get(*sent..).filter(|delta| !delta.is_empty())?;
+    *sent = streamed.len();
+    Some(delta.to_owned())
+}
+
+/// Forwards the streamed text to the client as `llm-ls/completionDelta` notifications until the
+/// stream is closed.
+async fn forward_deltas(
+    client: &Client,
+    request_id: Uuid,
+    streamed: &mut watch::Receiver<String>,
+    sent: &mut usize,
+) {
+    loop {
+        if let Some(delta) = next_delta(streamed, sent) {
+            client
+                .send_notification::<CompletionDelta>(CompletionDeltaParams { request_id, delta })
+                .await;
+        }
+        if streamed.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+fn format_generations(
+    generations: Vec<Generation>,
+    tokens_to_clear: &[String],
+    completion_type: CompletionType,
+) -> Vec<Completion> {
+    generations
+        .into_iter()
+        .map(|g| {
+            let mut generated_text = g.generated_text;
+            for token in tokens_to_clear {
+                generated_text = generated_text.replace(token, "")
+            }
+            match completion_type {
+                CompletionType::Empty => {
+                    warn!("completion type should not be empty when post processing completions");
+                    Completion { generated_text }
+                }
+                CompletionType::SingleLine => Completion {
+                    generated_text: generated_text
+                        .split_once('\n')
+                        .unwrap_or((&generated_text, ""))
+                        .0
+                        .to_owned(),
+                },
+                CompletionType::MultiLine => Completion { generated_text },
+            }
+        })
+        .collect()
+}
+
+async fn download_tokenizer_file(
+    http_client: &reqwest::Client,
+    url: &str,
+    api_token: Option<&String>,
+    to: impl AsRef<Path>,
+    ide: Ide,
+    retry: &RetryConfig,
+) -> Result<()> {
+    if to.as_ref().exists() {
+        return Ok(());
+    }
+    tokio::fs::create_dir_all(to.as_ref().parent().ok_or(Error::InvalidTokenizerPath)?).await?;
+    let headers = build_api_headers(api_token, ide)?;
+    // the download is dropped along with the completion request when it gets cancelled, write to a
+    // temporary file first so that we never leave a truncated tokenizer file behind
+    let mut part_path = to.as_ref().as_os_str().to_owned();
+    part_path.push(".part");
+    let mut attempt = 1;
+    let bytes = loop {
+        let hint;
+        match http_client.get(url).headers(headers.clone()).send().await {
+            Ok(res) if retry::is_retryable_status(res.status()) && attempt < retry.max_attempts => {
+                warn!(
+                    attempt,
+                    "API replied with status {} to the tokenizer file download, retrying",
+                    res.status()
+                );
+                hint = retry::retry_after(res.headers());
+            }
+            Ok(res) => {
+                let res = match res.error_for_status() {
+                    Ok(res) => res,
+                    Err(err) => {
+                        error!("API replied with error to the tokenizer file download: {err}");
+                        return Ok(());
+                    }
+                };
+                match res.bytes().await {
+                    Ok(bytes) => break bytes,
+                    Err(err) => {
+                        error!("error while streaming tokenizer file bytes: {err}");
+                        return Ok(());
+                    }
+                }
+            }
+            Err(err) if (err.is_connect() || err.is_timeout()) && attempt < retry.max_attempts => {
+                warn!(
+                    attempt,
+                    "error sending download request for the tokenizer file, retrying: {err}"
+                );
+                hint = None;
+            }
+            Err(err) => {
+                error!("error sending download request for the tokenzier file: {err}");
+                return Ok(());
+            }
+        }
+        tokio::time::sleep(retry::backoff(retry, attempt, hint)).await;
+        attempt += 1;
+    };
+    if let Err(err) = tokio::fs::write(&part_path, &bytes).await {
+        error!("error writing the tokenizer file to disk: {err}");
+        return Ok(());
+    }
+    tokio::fs::rename(&part_path, to).await?;
+    Ok(())
+}
+
+async fn get_tokenizer(
+    model: &str,
+    tokenizer_map: &mut HashMap<String, Arc<Tokenizer>>,
+    tokenizer_config: Option<&TokenizerConfig>,
+    http_client: &reqwest::Client,
+    cache_dir: impl AsRef<Path>,
+    ide: Ide,
+    retry: &RetryConfig,
+) -> Result<Option<Arc<Tokenizer>>> {
+    if let Some(tokenizer) = tokenizer_map.get(model) {
+        return Ok(Some(tokenizer.clone()));
+    }
+    if let Some(config) = tokenizer_config {
+        let tokenizer = match config {
+            TokenizerConfig::Local { path } => match Tokenizer::from_file(path) {
+                Ok(tokenizer) => Some(Arc::new(tokenizer)),
+                Err(err) => {
+                    error!("error loading tokenizer from file: {err}");
+                    None
+                }
+            },
+            TokenizerConfig::HuggingFace {
+                repository,
+                api_token,
+            } => {
+                let (org, repo) = repository
+                    .split_once('/')
+                    .ok_or(Error::InvalidRepositoryId)?;
+                let path = cache_dir
+                    .as_ref()
+                    .join(org)
+                    .join(repo)
+                    .join("tokenizer.json");
+                let url =
+                    format!("https://huggingface.co/{repository}/resolve/main/tokenizer.json");
+                download_tokenizer_file(http_client, &url, api_token.as_ref(), &path, ide, retry)
+                    .await?;
+                match Tokenizer::from_file(path) {
+                    Ok(tokenizer) => Some(Arc::new(tokenizer)),
+                    Err(err) => {
+                        error!("error loading tokenizer from file: {err}");
+                        None
+                    }
+                }
+            }
+            TokenizerConfig::Download { url, to } => {
+                download_tokenizer_file(http_client, url, None, &to, ide, retry).await?;
+                match Tokenizer::from_file(to) {
+                    Ok(tokenizer) => Some(Arc::new(tokenizer)),
+                    Err(err) => {
+                        error!("error loading tokenizer from file: {err}");
+                        None
+                    }
+                }
+            }
+        };
+        if let Some(tokenizer) = tokenizer.clone() {
+            tokenizer_map.insert(model.to_owned(), tokenizer.clone());
+        }
+        Ok(tokenizer)
+    } else {
+        Ok(None)
+    }
+}
+
+impl LlmService {
+    async fn get_completions(
+        &self,
+        params: GetCompletionsParams,
+    ) -> LspResult<GetCompletionsResult> {
+        let request_id = Uuid::new_v4();
+        let span = info_span!("completion_request", %request_id, retries = 0);
+        let uri = params.text_document_position.text_document.uri.to_string();
+        let superseded = self.register_in_flight_request(&uri, request_id).await;
+
+        let result = async move {
+            tokio::select! {
+                result = self.complete(request_id, params) => result,
+                _ = superseded => {
+                    info!("completion request superseded by a newer one for the same document");
+                    Err(Error::Cancelled.into())
+                }
+            }
+        }
+        .instrument(span)
+        .await;
+        self.unregister_in_flight_request(&uri, request_id).await;
+        result
+    }
+
+    /// Registers a completion request for `uri`, cancelling the previous one still in flight for
+    /// the same document. The returned receiver resolves once this request is superseded in turn.
+    async fn register_in_flight_request(
+        &self,
+        uri: &str,
+        request_id: Uuid,
+    ) -> oneshot::Receiver<()> {
+        let (cancel_tx, cancel_rx) = oneshot::channel();
+        let previous = self
+            .in_flight_requests
+            .write()
+            .await
+            .insert(uri.to_owned(), (request_id, cancel_tx));
+        if let Some((previous_id, cancel)) = previous {
+            debug!(%previous_id, "cancelling superseded completion request");
+            // the previous request may already be done, in which case there is nothing to cancel
+            let _ = cancel.send(());
+        }
+        cancel_rx
+    }
+
+    async fn unregister_in_flight_request(&self, uri: &str, request_id: Uuid) {
+        let mut in_flight_requests = self.in_flight_requests.write().await;
+        if in_flight_requests
+            .get(uri)
+            .is_some_and(|(id, _)| *id == request_id)
+        {
+            in_flight_requests.remove(uri);
+        }
+    }
+
+    async fn complete(
+        &self,
+        request_id: Uuid,
+        mut params: GetCompletionsParams,
+    ) -> LspResult<GetCompletionsResult> {
+        self.credentials.resolve_params(&mut params).await;
+        let document_map = self.document_map.read().await;
+
+        let document =
+            match document_map.get(params.text_document_position.text_document.uri.as_str()) {
+                Some(doc) => doc,
+                None => {
+                    debug!("failed to find document");
+                    return Ok(GetCompletionsResult {
+                        request_id,
+                        completions: vec![],
+                        served_by: None,
+                        cached: false,
+                    });
+                }
+            };
+
+        info!(
+            document_url = %params.text_document_position.text_document.uri,
+            cursor_line = ?params.text_document_position.position.line,
+            cursor_character = ?params.text_document_position.position.character,
+            language_id = %document.language_id,
+            model = params.model,
+            backend = ?params.backend,
+            ide = %params.ide,
+            request_body = serde_json::to_string(&params.request_body).map_err(internal_error)?,
+            disable_url_path_completion = params.disable_url_path_completion,
+            stream = params.stream,
+            "received completion request",
+        );
+        if params.api_token.is_none() && params.backend.is_using_inference_api() {
+            let now = SystemTime::now();
+            let unauthenticated_warn_at = self.unauthenticated_warn_at.read().await;
+            if now
+                .duration_since(*unauthenticated_warn_at)
+                .unwrap_or_default()
+                > MAX_WARNING_REPEAT
+            {
+                drop(unauthenticated_warn_at);
+                self.client.show_message(MessageType::WARNING, "You are currently unauthenticated and will get rate limited. To reduce rate limiting, login with your API Token and consider subscribing to PRO: https://huggingface.co/pricing#pro").await;
+                let mut unauthenticated_warn_at = self.unauthenticated_warn_at.write().await;
+                *unauthenticated_warn_at = SystemTime::now();
+            }
+        }
+        let completion_type = should_complete(document, params.text_document_position.position)?;
+        info!(%completion_type, "completion type: {completion_type:?}");
+        if completion_type == CompletionType::Empty {
+            return Ok(GetCompletionsResult {
+                request_id,
+                completions: vec![],
+                served_by: None,
+                cached: false,
+            });
+        }
+        let language_id = document.language_id;
+        let template = match &params.prompt_template {
+            Some(template) => {
+                let workspace_folders = self.workspace_folders.read().await;
+                let workspace_folders = workspace_folders.as_deref().unwrap_or_default();
+                let uri = &params.text_document_position.text_document.uri;
+                let file_path = uri
+                    .to_file_path()
+                    .map_or_else(|_| uri.to_string(), |path| path.display().to_string());
+                let values = TemplateValues {
+                    language: &language_id.to_string(),
+                    file_path: &file_path,
+                    relative_path: &relative_path(uri.as_str(), workspace_folders),
+                    repo_name: repo_name(uri.as_str(), workspace_folders).unwrap_or_default(),
+                };
+                Some(Arc::new(PromptTemplate::parse(template, &values)?))
+            }
+            None => None,
+        };
+        if template.is_some() {
+            // the rendered template is the whole prompt, the suffix must not be sent on its own
+            params.fim.mode = FimMode::Template;
+        }
+        let mut snippets = vec![];
+        // the workspace index is searched once the documents are released
+        let mut index_query = None;
+        if params.context.neighboring_tabs || params.context.workspace_index {
+            let uri = params.text_document_position.text_document.uri.as_str();
+            let query_text = query_text(
+                &document.text,
+                params.text_document_position.position,
+                params.context.window_lines,
+            );
+            let workspace_folders = self.workspace_folders.read().await;
+            let workspace_folders = workspace_folders.as_deref().unwrap_or_default();
+            if params.context.neighboring_tabs {
+                snippets = neighboring_tabs(
+                    &document_map,
+                    uri,
+                    &query_text,
+                    workspace_folders,
+                    &params.context,
+                );
+            }
+            if params.context.workspace_index {
+                if self.workspace_index.index_folders(workspace_folders)
+                    && self.can_watch_files.swap(false, Ordering::Relaxed)
+                {
+                    self.watch_files();
+                }
+                // the open documents are more up to date than the index
+                let excluded: HashSet<PathBuf> = document_map
+                    .keys()
+                    .filter(|other_uri| params.context.neighboring_tabs || *other_uri == uri)
+                    .filter_map(|other_uri| Url::parse(other_uri).ok()?.to_file_path().ok())
+                    .collect();
+                index_query = Some((query_text, excluded));
+            }
+        }
+        // work on a snapshot of the document so that edits aren't blocked while we wait on the
+        // file system, the tokenizer and the backend
+        let text = document.text.clone();
+        let tree = document.tree.clone();
+        drop(document_map);
+        if let Some((query_text, excluded)) = index_query {
+            snippets.extend(
+                self.workspace_index
+                    .search(
+                        query_text,
+                        language_id,
+                        excluded,
+                        params.context.max_snippets,
+                    )
+                    .await?,
+            );
+        }
+        let file_path = params
+            .text_document_position
+            .text_document
+            .uri
+            .to_file_path();
+        if let (true, Ok(file_path), Some(tree)) = (params.context.imports, file_path, tree.clone())
+        {
+            let current_text = text.to_string();
+            let workspace_folders = self.workspace_folders.read().await.clone();
+            let max_snippets = params.context.max_snippets;
+            // the definitions used in the document go first, they are the most relevant
+            let definitions = tokio::task::spawn_blocking(move || {
+                let workspace_folders = workspace_folders.as_deref().unwrap_or_default();
+                let imports = find_imports(
+                    language_id,
+                    &tree,
+                    &current_text,
+                    &file_path,
+                    workspace_folders,
+                );
+                imported_definitions(&imports, &current_text, workspace_folders, max_snippets)
+            })
+            .await
+            .map_err(Error::from)?;
+            snippets.splice(0..0, definitions);
+        }
+
+        let mut repo_header = String::new();
+        let mut file_header = String::new();
+        let preset = presets::template(params.preset, &params.model);
+        if let Some(preset) = preset {
+            preset.apply(&mut params);
+        }
+        // a prompt template writes its own headers
+        if template.is_none() && params.metadata != MetadataFormat::None {
+            let workspace_folders = self.workspace_folders.read().await;
+            let workspace_folders = workspace_folders.as_deref().unwrap_or_default();
+            let uri = params.text_document_position.text_document.uri.as_str();
+            let repo_name = repo_name(uri, workspace_folders);
+            let path = relative_path(uri, workspace_folders);
+            match (params.metadata, preset) {
+                (MetadataFormat::SpecialTokens, Some(preset)) => {
+                    if let Some(header) = repo_name.and_then(|name| preset.repo_header(name)) {
+                        repo_header = header;
+                    }
+                    if let Some(header) = preset.file_header(&path) {
+                        file_header = header;
+                    }
+                }
+                (MetadataFormat::Comment, _) => {
+                    if let Some(header) = metadata_comment(language_id, repo_name, &path) {
+                        file_header = header;
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        let http_client = self.http_clients.get(&ClientConfig::from(&params)).await?;
+        let tokenizer = get_tokenizer(
+            &params.model,
+            &mut *self.tokenizer_map.write().await,
+            params.tokenizer_config.as_ref(),
+            &http_client,
+            &self.cache_dir,
+            params.ide,
+            &params.retry,
+        )
+        .await?;
+        if params.fim.enabled {
+            if let Some(tokenizer) = &tokenizer {
+                self.resolve_fim_tokens(&mut params.fim, &params.model, tokenizer)
+                    .await;
+            }
+        }
+        // the prefix and suffix would be concatenated without anything telling the model where to
+        // fill in
+        if template.is_none()
+            && params.fim.enabled
+            && params.fim.mode == FimMode::Template
+            && [&params.fim.prefix, &params.fim.suffix, &params.fim.middle]
+                .iter()
+                .all(|token| token.is_empty())
+        {
+            return Err(Error::InvalidParams(
+                "fim is enabled without fim tokens, configure them, a preset or their inference from the tokenizer".to_owned(),
+            )
+            .into());
+        }
+        let context_window = self.context_window(&http_client, &params).await;
+        let context_budget =
+            (context_window as f32 * params.context.max_context_ratio.clamp(0.0, 1.0)) as usize;
+        let (context, context_tokens) = match &template {
+            Some(template) if !template.has_snippets() => (String::new(), 0),
+            _ => render_snippets(
+                &snippets,
+                language_id,
+                &params.context,
+                tokenizer.as_deref(),
+                context_budget,
+            )?,
+        };
+        let fixed_text = match &template {
+            Some(template) => template.fixed_text(),
+            None => format!("{repo_header}{file_header}"),
+        };
+        let fixed_tokens = count_tokens(tokenizer.as_deref(), &fixed_text)?;
+        let context = format!("{repo_header}{context}{file_header}");
+        // the template takes the place of the FIM tokens, it decides whether there is a suffix
+        let fim = match &template {
+            Some(template) => FimParams {
+                enabled: template.has_suffix(),
+                prefix: String::new(),
+                suffix: String::new(),
+                middle: String::new(),
+                order: None,
+                ..params.fim.clone()
+            },
+            None => params.fim.clone(),
+        };
+        let prompt = fit_prompt(
+            params.text_document_position.position,
+            &text,
+            &fim,
+            tokenizer.as_deref(),
+            context_window,
+            context_window.saturating_sub(context_tokens + fixed_tokens),
+            tree.as_ref().map(|tree| Syntax {
+                tree,
+                text: &text,
+                language_id,
+            }),
+            &context,
+            template.as_ref(),
+        )?;
+
+        let key = cache_key(&prompt, &params);
+        let cacheable = is_cacheable(&params);
+        if cacheable {
+            if let Some((result, served_by)) = self.completion_cache.get(key, &params.cache).await {
+                info!(cache_key = key, "completion cache hit");
+                let completions =
+                    format_generations(result, &params.tokens_to_clear, completion_type);
+                return Ok(GetCompletionsResult {
+                    request_id,
+                    completions,
+                    served_by: Some(served_by),
+                    cached: true,
+                });
+            }
+        }
+
+        let (streamed, mut streamed_rx) = watch::channel(String::new());
+        let request = request_completion(
+            &http_client,
+            &self.backends,
+            &self.limiter,
+            &streamed,
+            prompt,
+            &params,
+        );
+        let completion = async {
+            if params.concurrency.coalesce {
+                let key = coalesce_key(key, params.stream);
+                self.coalescer.coalesce(key, &streamed, request).await
+            } else {
+                request.await
+            }
+        };
+        // length of the streamed text already forwarded to the client
+        let mut sent = 0;
+        tokio::pin!(completion);
+        let result = tokio::select! {
+            biased;
+            result = &mut completion => result,
+            // the stream was closed early, there is nothing left to forward until the end
+            () = forward_deltas(&self.client, request_id, &mut streamed_rx, &mut sent) => {
+                completion.await
+            }
+        };
+        if let Some(delta) = next_delta(&mut streamed_rx, &mut sent) {
+            self.client
+                .send_notification::<CompletionDelta>(CompletionDeltaParams { request_id, delta })
+                .await;
+        }
+        let (result, served_by) = match result {
+            Ok(res) => res,
+            Err(err) => {
+                self.show_backend_error(&err).await;
+                return Err(err.into());
+            }
+        };
+
+        if cacheable {
+            if let Err(err) = self
+                .completion_cache
+                .insert(key, result.clone(), served_by.clone(), &params.cache)
+                .await
+            {
+                error!("failed to persist the completion cache: {err}");
+            }
+        }
+
+        let completions = format_generations(result, &params.tokens_to_clear, completion_type);
+        Ok(GetCompletionsResult {
+            request_id,
+            completions,
+            served_by: Some(served_by),
+            cached: false,
+        })
+    }
+
+    /// Infers the FIM tokens from the tokenizer when asked to, then warns about the ones it does
+    /// not know as special tokens, once per model and tokens.
+    async fn resolve_fim_tokens(&self, fim: &mut FimParams, model: &str, tokenizer: &Tokenizer) {
+        if fim.infer_tokens && !infer_fim_tokens(fim, tokenizer) {
+            debug!(
+                model,
+                "no known fim tokens in the tokenizer, using the configured ones"
+            );
+        }
+        let invalid = invalid_fim_tokens(fim, tokenizer).join(", ");
+        if invalid.is_empty()
+            || !self
+                .fim_token_warnings
+                .write()
+                .await
+                .insert(format!("{model}:{invalid}"))
+        {
+            return;
+        }
+        warn!(
+            model,
+            invalid, "fim tokens are not special tokens of the tokenizer"
+        );
+        self.client
+            .show_message(
+                MessageType::WARNING,
+                format!("The FIM tokens {invalid} are not special tokens of {model}'s tokenizer, check your FIM settings"),
+            )
+            .await;
+    }
+
+    /// The configured context window, or the one reported by the backend when discovery is
+    /// enabled, less the tokens the backend needs for the generation. Backends are asked once,
+    /// failures are retried after `DISCOVERY_RETRY_INTERVAL`.
+    async fn context_window(
+        &self,
+        http_client: &reqwest::Client,
+        params: &GetCompletionsParams,
+    ) -> usize {
+        if !params.discover_context_window {
+            return params.context_window;
+        }
+        let backend = match self.backends.get(&params.backend) {
+            Ok(backend) => backend,
+            Err(err) => {
+                warn!("failed to discover the context window: {err}");
+                return params.context_window;
+            }
+        };
+        let key = format!(
+            "{}:{}:{}",
+            params.backend.tag(),
+            params.backend.clone().url(),
+            params.model
+        );
+        let cached = self.context_windows.read().await.get(&key).copied();
+        let discovered = match cached {
+            Some(Ok(discovered)) => discovered,
+            Some(Err(failed_at)) if failed_at.elapsed() < DISCOVERY_RETRY_INTERVAL => None,
+            _ => match discover_context_window(http_client, backend.as_ref(), params).await {
+                Ok(discovered) => {
+                    info!(
+                        model = params.model,
+                        ?discovered,
+                        "discovered context window"
+                    );
+                    self.context_windows
+                        .write()
+                        .await
+                        .insert(key, Ok(discovered));
+                    discovered
+                }
+                Err(err) => {
+                    warn!("failed to discover the context window: {err}");
+                    self.context_windows
+                        .write()
+                        .await
+                        .insert(key, Err(Instant::now()));
+                    None
+                }
+            },
+        };
+        match discovered {
+            Some(context_window) => {
+                context_window.saturating_sub(backend.generation_reserve(&params.request_body))
+            }
+            None => params.context_window,
+        }
+    }
+
+    /// Asks the client to notify the changes of the workspace files, to keep the workspace index
+    /// up to date with the files changed outside of the editor.
+    fn watch_files(&self) {
+        let registration = Registration {
+            id: "llm-ls/watchedFiles".to_owned(),
+            method: "workspace/didChangeWatchedFiles".to_owned(),
+            register_options: serde_json::to_value(DidChangeWatchedFilesRegistrationOptions {
+                watchers: vec![FileSystemWatcher {
+                    glob_pattern: GlobPattern::String("**/*".to_owned()),
+                    kind: None,
+                }],
+            })
+            .ok(),
+        };
+        let client = self.client.clone();
+        tokio::spawn(async move {
+            if let Err(err) = client.register_capability(vec![registration]).await {
+                debug!("failed to watch the workspace files: {err}");
+            }
+        });
+    }
+
+    /// Auth and not found errors usually come from a misconfiguration the user needs to know
+    /// about, show them at most once every `MAX_WARNING_REPEAT` per status.
+    async fn show_backend_error(&self, err: &Error) {
+        let message = match err {
+            Error::Unauthorized { .. } | Error::Forbidden { .. } => {
+                "The backend rejected the completion request, check your API token"
+            }
+            Error::NotFound { .. } => {
+                "The backend could not find the requested resource, check your backend url and model"
+            }
+            _ => return,
+        };
+        let Some(status) = err.status() else {
+            return;
+        };
+        let now = SystemTime::now();
+        let mut backend_error_warn_at = self.backend_error_warn_at.write().await;
+        if backend_error_warn_at.get(&status).is_some_and(|warn_at| {
+            now.duration_since(*warn_at).unwrap_or_default() <= MAX_WARNING_REPEAT
+        }) {
+            return;
+        }
+        backend_error_warn_at.insert(status, now);
+        drop(backend_error_warn_at);
+        self.client
+            .show_message(MessageType::ERROR, format!("{message} ({status})"))
+            .await;
+    }
+
+    async fn accept_completion(&self, accepted: AcceptCompletionParams) -> LspResult<()> {
+        info!(
+            request_id = %accepted.request_id,
+            accepted_position = accepted.accepted_completion,
+            shown_completions = serde_json::to_string(&accepted.shown_completions).map_err(internal_error)?,
+            "accepted completion"
+        );
+        Ok(())
+    }
+
+    async fn reject_completion(&self, rejected: RejectCompletionParams) -> LspResult<()> {
+        info!(
+            request_id = %rejected.request_id,
+            shown_completions = serde_json::to_string(&rejected.shown_completions).map_err(internal_error)?,
+            "rejected completion"
+        );
+        Ok(())
+    }
+
+    async fn resolve_backend_params(&self, params: &mut BackendParams) {
+        params.api_token = self
+            .credentials
+            .resolve(params.api_token.take(), &params.backend)
+            .await;
+    }
+
+    async fn check_backend(&self, mut params: BackendParams) -> LspResult<CheckBackendResult> {
+        info!(backend = ?params.backend, model = params.model, "checking backend");
+        self.resolve_backend_params(&mut params).await;
+        let backend = self.backends.get(&params.backend)?;
+        let http_client = self.http_clients.get(&ClientConfig::from(&params)).await?;
+        Ok(check::check_backend(&http_client, backend.as_ref(), &params).await)
+    }
+
+    async fn list_models(&self, mut params: BackendParams) -> LspResult<ListModelsResult> {
+        info!(backend = ?params.backend, "listing models");
+        self.resolve_backend_params(&mut params).await;
+        let backend = self.backends.get(&params.backend)?;
+        let http_client = self.http_clients.get(&ClientConfig::from(&params)).await?;
+        let models = check::list_models(&http_client, backend.as_ref(), &params).await?;
+        Ok(ListModelsResult { models })
+    }
+}
+
+#[tower_lsp::async_trait]
+impl LanguageServer for LlmService {
+    async fn initialize(&self, params: InitializeParams) -> LspResult<InitializeResult> {
+        *self.workspace_folders.write().await = params.workspace_folders;
+        let can_watch_files = params
+            .capabilities
+            .workspace
+            .as_ref()
+            .and_then(|workspace| workspace.did_change_watched_files.as_ref())
+            .and_then(|watched_files| watched_files.dynamic_registration)
+            .unwrap_or(false);
+        self.can_watch_files
+            .store(can_watch_files, Ordering::Relaxed);
+        if let Some(options) = params.initialization_options {
+            let options: InitializationOptions =
+                serde_json::from_value(options).map_err(internal_error)?;
+            if self.over_tcp
+                && !(options.credential_helpers.is_empty() && options.env_token_backends.is_empty())
+            {
+                warn!("ignoring the credentials configured over the tcp socket");
+            } else {
+                self.credentials.configure(options).await;
+            }
+        }
+        let position_encoding = params
+            .capabilities
+            .general
+            .and_then(|general_capabilities| {
+                general_capabilities
+                    .position_encodings
+                    .map(TryFrom::try_from)
+            })
+            .unwrap_or(Ok(document::PositionEncodingKind::Utf16))?;
+
+        *self.position_encoding.write().await = position_encoding;
+
+        Ok(InitializeResult {
+            server_info: Some(ServerInfo {
+                name: "llm-ls".to_owned(),
+                version: Some(VERSION.to_owned()),
+            }),
+            capabilities: ServerCapabilities {
+                text_document_sync: Some(TextDocumentSyncCapability::Kind(
+                    TextDocumentSyncKind::INCREMENTAL,
+                )),
+                position_encoding: Some(position_encoding.to_lsp_type()),
+                ..Default::default()
+            },
+        })
+    }
+
+    async fn initialized(&self, _: InitializedParams) {
+        self.client
+            .log_message(MessageType::INFO, "llm-ls initialized")
+            .await;
+        info!("initialized language server");
+    }
+
+    async fn did_open(&self, params: DidOpenTextDocumentParams) {
+        let uri = params.text_document.uri.to_string();
+        if uri == "file:///" {
+            return;
+        }
+        match Document::open(
+            &params.text_document.language_id,
+            &params.text_document.text,
+        )
+        .await
+        {
+            Ok(document) => {
+                self.document_map
+                    .write()
+                    .await
+                    .insert(uri.clone(), document);
+                info!("{uri} opened");
+            }
+            Err(err) => error!("error opening {uri}: {err}"),
+        }
+        self.client
+            .log_message(MessageType::INFO, format!("{uri} opened"))
+            .await;
+    }
+
+    async fn did_change(&self, params: DidChangeTextDocumentParams) {
+        let uri = params.text_document.uri.to_string();
+        if uri == "file:///" {
+            return;
+        }
+        if params.content_changes.is_empty() {
+            return;
+        }
+
+        // ignore the output scheme
+        if params.text_document.uri.scheme() == "output" {
+            return;
+        }
+
+        let mut document_map = self.document_map.write().await;
+        self.client
+            .log_message(MessageType::LOG, format!("{uri} changed"))
+            .await;
+        let doc = document_map.get_mut(&uri);
+        if let Some(doc) = doc {
+            for change in &params.content_changes {
+                match doc.apply_content_change(change, *self.position_encoding.read().await) {
+                    Ok(()) => info!("{uri} changed"),
+                    Err(err) => error!("error when changing {uri}: {err}"),
+                }
+            }
+        } else {
+            debug!("textDocument/didChange {uri}: document not found");
+        }
+    }
+
+    async fn did_save(&self, params: DidSaveTextDocumentParams) {
+        let uri = params.text_document.uri.to_string();
+        self.client
+            .log_message(MessageType::INFO, format!("{uri} saved"))
+            .await;
+        info!("{uri} saved");
+        if let Ok(path) = params.text_document.uri.to_file_path() {
+            self.workspace_index.update_file(path);
+        }
+    }
+
+    async fn did_change_watched_files(&self, params: DidChangeWatchedFilesParams) {
+        for change in params.changes {
+            let Ok(path) = change.uri.to_file_path() else {
+                continue;
+            };
+            if change.typ == FileChangeType::DELETED {
+                self.workspace_index.remove_file(&path);
+            } else {
+                self.workspace_index.update_file(path);
+            }
+        }
+    }
+
+    async fn did_close(&self, params: DidCloseTextDocumentParams) {
+        let uri = params.text_document.uri.to_string();
+        // dropping the sender cancels the completion request still in flight for this document
+        self.in_flight_requests.write().await.remove(&uri);
+        // closed documents are no longer neighboring tabs, the workspace index covers them again
+        self.document_map.write().await.remove(&uri);
+        self.client
+            .log_message(MessageType::INFO, format!("{uri} closed"))
+            .await;
+        info!("{uri} closed");
+    }
+
+    async fn shutdown(&self) -> LspResult<()> {
+        debug!("shutdown");
+        Ok(())
+    }
+}
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// Wether to use a tcp socket for data transfer
+    #[arg(long = "port")]
+    socket: Option<usize>,
+
+    /// Wether to use stdio transport for data transfer, ignored because it is the default
+    /// behaviour
+    #[arg(short, long, default_value_t = true)]
+    stdio: bool,
+}
+
+/// Runs the language server with the completion backends of `backends`, on stdio or on the TCP
+/// port given on the command line.
+///
+/// Binaries adding their own backends register them in a [`BackendRegistry::default`] before
+/// calling this, see [`CompletionBackend`].
+pub async fn run(backends: BackendRegistry) {
+    let args = Args::parse();
+
+    let home_dir = home::home_dir().ok_or(()).expect("failed to find home dir");
+    let cache_dir = home_dir.join(".cache/llm_ls");
+    tokio::fs::create_dir_all(&cache_dir)
+        .await
+        .expect("failed to create cache dir");
+
+    let log_file = rolling::never(&cache_dir, "llm-ls.log");
+    let builder = tracing_subscriber::fmt()
+        .with_writer(log_file)
+        .with_target(true)
+        .with_line_number(true)
+        .with_env_filter(
+            EnvFilter::try_from_env("LLM_LOG_LEVEL").unwrap_or_else(|_| EnvFilter::new("warn")),
+        );
+
+    builder
+        .json()
+        .flatten_event(true)
+        .with_current_span(false)
+        .with_span_list(true)
+        .init();
+
+    let completion_cache = CompletionCache::new(&cache_dir);
+    let (service, socket) = LspService::build(|client| LlmService {
+        cache_dir,
+        client,
+        position_encoding: Arc::new(RwLock::new(document::PositionEncodingKind::Utf16)),
+        document_map: Arc::new(RwLock::new(HashMap::new())),
+        http_clients: HttpClients::default(),
+        credentials: Credentials::default(),
+        over_tcp: args.socket.is_some(),
+        completion_cache,
+        limiter: BackendLimiter::default(),
+        coalescer: Coalescer::default(),
+        backends,
+        workspace_folders: Arc::new(RwLock::new(None)),
+        workspace_index: WorkspaceIndex::default(),
+        can_watch_files: AtomicBool::new(false),
+        context_windows: RwLock::default(),
+        fim_token_warnings: RwLock::default(),
+        tokenizer_map: Arc::new(RwLock::new(HashMap::new())),
+        in_flight_requests: Arc::new(RwLock::new(HashMap::new())),
+        backend_error_warn_at: Arc::new(RwLock::new(HashMap::new())),
+        unauthenticated_warn_at: Arc::new(RwLock::new(
+            SystemTime::now()
+                .checked_sub(MAX_WARNING_REPEAT)
+                .unwrap_or(SystemTime::now()),
+        )),
+    })
+    .custom_method("llm-ls/getCompletions", LlmService::get_completions)
+    .custom_method("llm-ls/acceptCompletion", LlmService::accept_completion)
+    .custom_method("llm-ls/rejectCompletion", LlmService::reject_completion)
+    .custom_method("llm-ls/checkBackend", LlmService::check_backend)
+    .custom_method("llm-ls/listModels", LlmService::list_models)
+    .finish();
+
+    if let Some(port) = args.socket {
+        let addr = format!("127.0.0.1:{port}");
+        let listener = TcpListener::bind(&addr)
+            .await
+            .unwrap_or_else(|_| panic!("failed to bind tcp listener to {addr}"));
+        let (stream, _) = listener
+            .accept()
+            .await
+            .unwrap_or_else(|_| panic!("failed to accept new connections on {addr}"));
+        let (read, write) = tokio::io::split(stream);
+        Server::new(read, write, socket).serve(service).await;
+    } else {
+        let (stdin, stdout) = (tokio::io::stdin(), tokio::io::stdout());
+        Server::new(stdin, stdout, socket).serve(service).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tokens::{bpe_tokenizer, byte_tokenizer};
+
+    /// Twenty lines of four bytes, hence four tokens
+    fn document() -> Rope {
+        Rope::from_str(&(0..20).map(|i| format!("l{i:02}\n")).collect::<String>())
+    }
+
+    fn fim(suffix_ratio: Option<f32>, max_suffix_lines: Option<usize>) -> FimParams {
+        FimParams {
+            enabled: true,
+            prefix: "P".to_owned(),
+            middle: "M".to_owned(),
+            suffix: "S".to_owned(),
+            mode: FimMode::Template,
+            order: None,
+            suffix_ratio,
+            max_suffix_lines,
+            infer_tokens: false,
+        }
+    }
+
+    /// Builds the prompt of the document with the cursor at the start of `line`, in a context
+    /// window leaving 24 tokens after the FIM tokens.
+    fn build(line: u32, fim: &FimParams) -> Prompt {
+        let tokenizer = byte_tokenizer();
+        build_prompt(
+            Position::new(line, 0),
+            &document(),
+            fim,
+            Some(&tokenizer),
+            3 + 24,
+            None,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_suffix_ratio() {
+        let prompt = build(10, &fim(Some(0.5), None));
+        assert_eq!(prompt.prefix, "l07\nl08\nl09\n");
+        assert_eq!(prompt.suffix.as_deref(), Some("l10\nl11\nl12\n"));
+
+        let prompt = build(10, &fim(Some(0.25), None));
+        // the suffix leaves 2 of its 6 tokens
+        assert_eq!(prompt.prefix, "l05\nl06\nl07\nl08\nl09\n");
+        assert_eq!(prompt.suffix.as_deref(), Some("l10\n"));
+    }
+
+    #[test]
+    fn test_suffix_ratio_spillover() {
+        // the suffix ends early, the prefix takes the rest
+        let prompt = build(18, &fim(Some(0.5), None));
+        assert_eq!(prompt.prefix, "l14\nl15\nl16\nl17\n");
+        assert_eq!(prompt.suffix.as_deref(), Some("l18\nl19\n"));
+
+        // the prefix ends early, the suffix takes the rest
+        let prompt = build(2, &fim(Some(0.5), None));
+        assert_eq!(prompt.prefix, "l00\nl01\n");
+        assert_eq!(prompt.suffix.as_deref(), Some("l02\nl03\nl04\nl05\n"));
+    }
+
+    #[test]
+    fn test_max_suffix_lines() {
+        let prompt = build(10, &fim(None, Some(2)));
+        assert_eq!(prompt.prefix, "l06\nl07\nl08\nl09\n");
+        assert_eq!(prompt.suffix.as_deref(), Some("l10\nl11\n"));
+
+        let prompt = build(2, &fim(Some(0.5), Some(2)));
+        assert_eq!(prompt.prefix, "l00\nl01\n");
+        assert_eq!(prompt.suffix.as_deref(), Some("l02\nl03\n"));
+    }
+
+    #[test]
+    fn test_render_order() {
+        let mut fim = fim(Some(0.5), None);
+        let prompt = build(18, &fim);
+        assert_eq!(prompt.render(&fim), "Pl14\nl15\nl16\nl17\nSl18\nl19\nM");
+        fim.order = Some(FimOrder::Spm);
+        assert_eq!(prompt.render(&fim), "PSl18\nl19\nMl14\nl15\nl16\nl17\n");
+    }
+
+    #[test]
+    fn test_fit_prompt() {
+        // the FIM tokens are a single token when counted together, not in the prompt
+        let mut tokenizers = vec![bpe_tokenizer(&[
+            ("x", "y"),
+            ("xy", "z"),
+            ("Ġ", "Ġ"),
+            ("ĠĠ", "ĠĠ"),
+            ("s", "e"),
+            ("se", "l"),
+            ("sel", "f"),
+            ("Ġ", "self"),
+        ])];
+        // and with the tokenizer of an actual model when there is one
+        if let Ok(path) = std::env::var("LLM_LS_BENCH_TOKENIZER") {
+            tokenizers.push(Tokenizer::from_file(path).unwrap());
+        }
+        let fim = FimParams {
+            prefix: "x".to_owned(),
+            suffix: "y".to_owned(),
+            middle: "z".to_owned(),
+            ..fim(None, None)
+        };
+        let text = Rope::from_str(include_str!("backend.rs"));
+        let pos = Position::new(text.len_lines() as u32 / 2, 8);
+        for tokenizer in &tokenizers {
+            for context_window in [8, 64, 512, 4096] {
+                let prompt = fit_prompt(
+                    pos,
+                    &text,
+                    &fim,
+                    Some(tokenizer),
+                    context_window,
+                    context_window,
+                    None,
+                    "",
+                    None,
+                )
+                .unwrap();
+                let tokens = count_tokens(Some(tokenizer), &prompt.render(&fim)).unwrap();
+                assert!(tokens <= context_window, "{tokens} > {context_window}");
+            }
+            // the context alone doesn't fit
+            let context = "// a header longer than the context window\n".repeat(4);
+            assert!(matches!(
+                fit_prompt(
+                    pos,
+                    &text,
+                    &fim,
+                    Some(tokenizer),
+                    8,
+                    0,
+                    None,
+                    &context,
+                    None
+                ),
+                Err(Error::PromptTooLarge { .. })
+            ));
+        }
+    }
+}
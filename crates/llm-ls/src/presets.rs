@@ -0,0 +1,173 @@
+use custom_types::llm_ls::{FimOrder, GetCompletionsParams, Preset};
+
+/// The prompt format a model family was trained with.
+#[derive(Debug, PartialEq)]
+pub(crate) struct PresetTemplate {
+    /// Prefix, suffix and middle FIM tokens
+    fim: [&'static str; 3],
+    order: FimOrder,
+    /// Written ahead of the prompt, `{repo_name}` is replaced with the name of the workspace
+    /// folder
+    repo_header: Option<&'static str>,
+    /// Written right before the current file, `{path}` is replaced with its path
+    file_header: Option<&'static str>,
+    stop: &'static [&'static str],
+    tokens_to_clear: &'static [&'static str],
+}
+
+const STARCODER: PresetTemplate = PresetTemplate {
+    fim: ["<fim_prefix>", "<fim_suffix>", "<fim_middle>"],
+    order: FimOrder::Psm,
+    repo_header: Some("<reponame>{repo_name}"),
+    file_header: Some("<filename>{path}\n"),
+    stop: &["<|endoftext|>"],
+    tokens_to_clear: &["<|endoftext|>"],
+};
+
+const STARCODER2: PresetTemplate = PresetTemplate {
+    fim: ["<fim_prefix>", "<fim_suffix>", "<fim_middle>"],
+    order: FimOrder::Psm,
+    repo_header: Some("<repo_name>{repo_name}"),
+    file_header: Some("<file_sep>{path}\n"),
+    stop: &["<|endoftext|>", "<file_sep>"],
+    tokens_to_clear: &["<|endoftext|>"],
+};
+
+const CODELLAMA: PresetTemplate = PresetTemplate {
+    fim: ["<PRE> ", " <SUF>", " <MID>"],
+    order: FimOrder::Psm,
+    repo_header: None,
+    file_header: None,
+    stop: &["<EOT>"],
+    tokens_to_clear: &["<EOT>"],
+};
+
+const DEEPSEEK: PresetTemplate = PresetTemplate {
+    fim: ["<｜fim▁begin｜>", "<｜fim▁hole｜>", "<｜fim▁end｜>"],
+    order: FimOrder::Psm,
+    repo_header: None,
+    file_header: None,
+    stop: &["<｜end▁of▁sentence｜>", "<|EOT|>"],
+    tokens_to_clear: &["<｜end▁of▁sentence｜>", "<|EOT|>"],
+};
+
+const QWEN: PresetTemplate = PresetTemplate {
+    fim: ["<|fim_prefix|>", "<|fim_suffix|>", "<|fim_middle|>"],
+    order: FimOrder::Psm,
+    repo_header: Some("<|repo_name|>{repo_name}\n"),
+    file_header: Some("<|file_sep|>{path}\n"),
+    stop: &[
+        "<|endoftext|>",
+        "<|file_sep|>",
+        "<|fim_pad|>",
+        "<|repo_name|>",
+    ],
+    tokens_to_clear: &["<|endoftext|>"],
+};
+
+const CODEGEMMA: PresetTemplate = PresetTemplate {
+    fim: ["<|fim_prefix|>", "<|fim_suffix|>", "<|fim_middle|>"],
+    order: FimOrder::Psm,
+    repo_header: None,
+    file_header: None,
+    stop: &["<|file_separator|>", "<eos>"],
+    tokens_to_clear: &["<|file_separator|>", "<eos>"],
+};
+
+/// Model name patterns, matched against the lowercased model name without separators. More
+/// specific patterns come first.
+const MODEL_PATTERNS: &[(&[&str], Preset)] = &[
+    (&["starcoder2"], Preset::StarCoder2),
+    (&["starcoder"], Preset::StarCoder),
+    (&["santacoder"], Preset::StarCoder),
+    (&["codellama"], Preset::CodeLlama),
+    (&["deepseek", "coder"], Preset::DeepSeek),
+    (&["qwen", "coder"], Preset::Qwen),
+    (&["codegemma"], Preset::CodeGemma),
+];
+
+fn detect(model: &str) -> Option<Preset> {
+    let model: String = model
+        .to_lowercase()
+        .chars()
+        .filter(|c| !matches!(c, '-' | '_' | ' '))
+        .collect();
+    MODEL_PATTERNS
+        .iter()
+        .find(|(patterns, _)| patterns.iter().all(|pattern| model.contains(pattern)))
+        .map(|(_, preset)| *preset)
+}
+
+/// The template of the configured preset, detected from the model name with [`Preset::Auto`].
+pub(crate) fn template(preset: Preset, model: &str) -> Option<&'static PresetTemplate> {
+    let preset = match preset {
+        Preset::Auto => detect(model)?,
+        preset => preset,
+    };
+    match preset {
+        Preset::Auto | Preset::None => None,
+        Preset::StarCoder => Some(&STARCODER),
+        Preset::StarCoder2 => Some(&STARCODER2),
+        Preset::CodeLlama => Some(&CODELLAMA),
+        Preset::DeepSeek => Some(&DEEPSEEK),
+        Preset::Qwen => Some(&QWEN),
+        Preset::CodeGemma => Some(&CODEGEMMA),
+    }
+}
+
+impl PresetTemplate {
+    /// Fills in what the request doesn't configure, explicit parameters take precedence.
+    pub(crate) fn apply(&self, params: &mut GetCompletionsParams) {
+        let fim = &mut params.fim;
+        for (token, preset_token) in [&mut fim.prefix, &mut fim.suffix, &mut fim.middle]
+            .into_iter()
+            .zip(self.fim)
+        {
+            if token.is_empty() {
+                *token = preset_token.to_owned();
+            }
+        }
+        fim.order.get_or_insert(self.order);
+        if params.stop.is_empty() {
+            params.stop = self.stop.iter().map(|stop| stop.to_string()).collect();
+        }
+        for token in self.tokens_to_clear {
+            if !params.tokens_to_clear.iter().any(|t| t == token) {
+                params.tokens_to_clear.push(token.to_string());
+            }
+        }
+    }
+
+    pub(crate) fn repo_header(&self, repo_name: &str) -> Option<String> {
+        Some(self.repo_header?.replace("{repo_name}", repo_name))
+    }
+
+    pub(crate) fn file_header(&self, path: &str) -> Option<String> {
+        Some(self.file_header?.replace("{path}", path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_template() {
+        assert_eq!(
+            template(Preset::Auto, "bigcode/starcoder2-15b"),
+            Some(&STARCODER2)
+        );
+        assert_eq!(
+            template(Preset::Auto, "deepseek-ai/deepseek-coder-6.7b-base"),
+            Some(&DEEPSEEK)
+        );
+        assert_eq!(template(Preset::Auto, "Qwen/Qwen2.5-Coder-7B"), Some(&QWEN));
+        assert_eq!(
+            template(Preset::Auto, "codellama:7b-code"),
+            Some(&CODELLAMA)
+        );
+        assert_eq!(template(Preset::Auto, "Qwen/Qwen2.5-7B-Instruct"), None);
+        assert_eq!(template(Preset::None, "bigcode/starcoder"), None);
+        assert_eq!(template(Preset::CodeGemma, "my-model"), Some(&CODEGEMMA));
+    }
+}
@@ -0,0 +1,112 @@
+use std::time::Duration;
+
+use custom_types::llm_ls::RetryConfig;
+use rand::Rng;
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use reqwest::StatusCode;
+use serde::Deserialize;
+
+/// Error body returned by the inference API while the model is being loaded.
+#[derive(Debug, Deserialize)]
+struct ModelLoading {
+    estimated_time: f64,
+}
+
+pub(crate) fn is_retryable_status(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::TOO_MANY_REQUESTS
+            | StatusCode::BAD_GATEWAY
+            | StatusCode::SERVICE_UNAVAILABLE
+            | StatusCode::GATEWAY_TIMEOUT
+    )
+}
+
+/// Delay requested through the `Retry-After` header, only the delay-seconds form is supported.
+pub(crate) fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let seconds = headers.get(RETRY_AFTER)?.to_str().ok()?;
+    seconds.trim().parse().ok().map(Duration::from_secs)
+}
+
+/// Time the inference API estimates it needs to finish loading the model.
+pub(crate) fn estimated_time(body: &str) -> Option<Duration> {
+    let loading: ModelLoading = serde_json::from_str(body).ok()?;
+    Duration::try_from_secs_f64(loading.estimated_time).ok()
+}
+
+/// Computes how long to wait before the next attempt, `attempt` being the number of attempts
+/// made so far.
+///
+/// The delay hinted by the server is preferred, otherwise we back off exponentially with jitter.
+/// Both are capped by `max_backoff_ms`.
+pub(crate) fn backoff(config: &RetryConfig, attempt: u32, hint: Option<Duration>) -> Duration {
+    let max_backoff = Duration::from_millis(config.max_backoff_ms);
+    if let Some(hint) = hint {
+        return hint.min(max_backoff);
+    }
+    let backoff_ms = config
+        .initial_backoff_ms
+        .saturating_mul(2u64.saturating_pow(attempt.saturating_sub(1)))
+        .min(config.max_backoff_ms);
+    let jitter_ms = rand::thread_rng().gen_range(0..=backoff_ms / 2);
+    Duration::from_millis(backoff_ms - backoff_ms / 2 + jitter_ms)
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::header::HeaderValue;
+
+    use super::*;
+
+    fn config() -> RetryConfig {
+        RetryConfig {
+            max_attempts: 5,
+            initial_backoff_ms: 100,
+            max_backoff_ms: 1_000,
+        }
+    }
+
+    #[test]
+    fn test_backoff() {
+        let config = config();
+        for _ in 0..100 {
+            let first = backoff(&config, 1, None);
+            assert!(first >= Duration::from_millis(50) && first <= Duration::from_millis(100));
+            let third = backoff(&config, 3, None);
+            assert!(third >= Duration::from_millis(200) && third <= Duration::from_millis(400));
+            let capped = backoff(&config, 30, None);
+            assert!(capped >= Duration::from_millis(500) && capped <= Duration::from_millis(1_000));
+        }
+        assert_eq!(
+            backoff(&config, 1, Some(Duration::from_millis(300))),
+            Duration::from_millis(300)
+        );
+        assert_eq!(
+            backoff(&config, 1, Some(Duration::from_secs(20))),
+            Duration::from_millis(1_000)
+        );
+    }
+
+    #[test]
+    fn test_retry_after() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(retry_after(&headers), None);
+        headers.insert(RETRY_AFTER, HeaderValue::from_static(" 3 "));
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(3)));
+        headers.insert(
+            RETRY_AFTER,
+            HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT"),
+        );
+        assert_eq!(retry_after(&headers), None);
+    }
+
+    #[test]
+    fn test_estimated_time() {
+        assert_eq!(
+            estimated_time(r#"{"error":"Model is currently loading","estimated_time":20.5}"#),
+            Some(Duration::from_millis(20_500))
+        );
+        assert_eq!(estimated_time(r#"{"estimated_time":-1.0}"#), None);
+        assert_eq!(estimated_time(r#"{"error":"Rate limit reached"}"#), None);
+    }
+}
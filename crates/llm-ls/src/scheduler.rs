@@ -0,0 +1,387 @@
+use custom_types::llm_ls::{BackendConfig, ConcurrencyConfig, ServedBy};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{oneshot, watch};
+use tracing::{debug, info};
+
+use crate::error::{Error, Result};
+use crate::Generation;
+
+type Queues = Arc<Mutex<HashMap<String, BackendQueue>>>;
+
+struct Waiter {
+    uri: String,
+    /// Receives `Ok` once the waiter is given a slot, or the reason it was dropped from the queue
+    slot: oneshot::Sender<Result<()>>,
+}
+
+#[derive(Default)]
+struct BackendQueue {
+    running: usize,
+    waiters: VecDeque<Waiter>,
+}
+
+/// Bounds the number of requests sent at the same time to each backend.
+///
+/// Requests past the limit wait in a bounded queue. When it is full, the queued requests of the
+/// same document are dropped first since they are superseded by the new one, then the oldest one.
+#[derive(Default)]
+pub(crate) struct BackendLimiter {
+    queues: Queues,
+}
+
+/// Holds one of the backend's slots until dropped, handing it over to the next waiter.
+pub(crate) struct Permit {
+    queues: Queues,
+    backend: String,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        release(&self.queues, &self.backend);
+    }
+}
+
+fn release(queues: &Queues, backend: &str) {
+    let mut queues = queues.lock().expect("backend queues lock poisoned");
+    let Some(queue) = queues.get_mut(backend) else {
+        return;
+    };
+    while let Some(waiter) = queue.waiters.pop_front() {
+        // the waiter may have been cancelled while queued
+        if waiter.slot.send(Ok(())).is_ok() {
+            return;
+        }
+    }
+    queue.running = queue.running.saturating_sub(1);
+    if queue.running == 0 {
+        queues.remove(backend);
+    }
+}
+
+/// A queued request, giving its slot back if it is granted one after being cancelled.
+struct Waiting {
+    queues: Queues,
+    backend: String,
+    slot: oneshot::Receiver<Result<()>>,
+}
+
+impl Drop for Waiting {
+    fn drop(&mut self) {
+        if let Ok(Ok(())) = self.slot.try_recv() {
+            release(&self.queues, &self.backend);
+        }
+    }
+}
+
+impl BackendLimiter {
+    pub(crate) async fn acquire(
+        &self,
+        config: &BackendConfig,
+        uri: &str,
+        concurrency: &ConcurrencyConfig,
+    ) -> Result<Permit> {
+        let backend = format!("{}:{}", config.backend.tag(), config.backend.clone().url());
+        let slot = {
+            let mut queues = self.queues.lock().expect("backend queues lock poisoned");
+            let queue = queues.entry(backend.clone()).or_default();
+            if queue.running < concurrency.max_concurrent_requests.max(1) {
+                queue.running += 1;
+                return Ok(Permit {
+                    queues: self.queues.clone(),
+                    backend,
+                });
+            }
+            queue.waiters.retain(|waiter| !waiter.slot.is_closed());
+            if queue.waiters.len() >= concurrency.max_queued_requests {
+                let superseded = queue.waiters.iter().position(|waiter| waiter.uri == uri);
+                let (idx, err) = match superseded {
+                    Some(idx) => (idx, Error::Cancelled),
+                    None => (0, Error::BackendQueueFull),
+                };
+                match queue.waiters.remove(idx) {
+                    Some(dropped) => {
+                        info!(uri = dropped.uri, "dropping queued request: {err}");
+                        let _ = dropped.slot.send(Err(err));
+                    }
+                    // the queue does not accept any request
+                    None => return Err(Error::BackendQueueFull),
+                }
+            }
+            let (tx, rx) = oneshot::channel();
+            queue.waiters.push_back(Waiter {
+                uri: uri.to_owned(),
+                slot: tx,
+            });
+            rx
+        };
+        debug!(backend, "waiting for a backend slot");
+        let mut waiting = Waiting {
+            queues: self.queues.clone(),
+            backend: backend.clone(),
+            slot,
+        };
+        match (&mut waiting.slot).await {
+            Ok(Ok(())) => Ok(Permit {
+                queues: self.queues.clone(),
+                backend,
+            }),
+            Ok(Err(err)) => Err(err),
+            Err(_) => Err(Error::Cancelled),
+        }
+    }
+}
+
+type Completed = (Vec<Generation>, ServedBy);
+type Shared = Option<std::result::Result<Completed, Arc<Error>>>;
+/// The leader's result and the text it streamed so far
+type InFlight = Arc<Mutex<HashMap<u64, (u64, watch::Receiver<Shared>, watch::Receiver<String>)>>>;
+
+/// Identical requests are coalesced, i.e. those with the same cache key, unless only one of them
+/// expects the text to be streamed.
+pub(crate) fn coalesce_key(cache_key: u64, stream: bool) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    (cache_key, stream).hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Shares the result of a backend request with the identical requests made while it is in
+/// flight.
+#[derive(Default)]
+pub(crate) struct Coalescer {
+    in_flight: InFlight,
+    next_id: Mutex<u64>,
+}
+
+/// Removes the leader's entry once it is done or cancelled, unless a new leader replaced it.
+struct Leader {
+    in_flight: InFlight,
+    key: u64,
+    id: u64,
+}
+
+impl Drop for Leader {
+    fn drop(&mut self) {
+        let mut in_flight = self.in_flight.lock().expect("in flight lock poisoned");
+        if in_flight
+            .get(&self.key)
+            .is_some_and(|(id, _, _)| *id == self.id)
+        {
+            in_flight.remove(&self.key);
+        }
+    }
+}
+
+enum Role {
+    Leader(watch::Sender<Shared>, Leader),
+    Follower(watch::Receiver<Shared>, watch::Receiver<String>),
+}
+
+impl Coalescer {
+    fn role(&self, key: u64, streamed: &watch::Sender<String>) -> Role {
+        let mut in_flight = self.in_flight.lock().expect("in flight lock poisoned");
+        if let Some((_, result, leader_streamed)) = in_flight.get(&key) {
+            return Role::Follower(result.clone(), leader_streamed.clone());
+        }
+        let id = {
+            let mut next_id = self.next_id.lock().expect("next id lock poisoned");
+            *next_id += 1;
+            *next_id
+        };
+        let (tx, rx) = watch::channel(None);
+        in_flight.insert(key, (id, rx, streamed.subscribe()));
+        let leader = Leader {
+            in_flight: self.in_flight.clone(),
+            key,
+            id,
+        };
+        Role::Leader(tx, leader)
+    }
+
+    /// Runs `request` unless an identical one, with the same `key`, is already in flight, in
+    /// which case its result is returned instead.
+    ///
+    /// `request` streams its text to `streamed`. When coalesced, the text streamed by the request
+    /// in flight is copied to `streamed` instead, so that every caller can forward it.
+    pub(crate) async fn coalesce(
+        &self,
+        key: u64,
+        streamed: &watch::Sender<String>,
+        request: impl Future<Output = Result<Completed>>,
+    ) -> Result<Completed> {
+        loop {
+            match self.role(key, streamed) {
+                Role::Follower(mut result, mut leader_streamed) => {
+                    let shared = loop {
+                        tokio::select! {
+                            shared = result.wait_for(Option::is_some) => {
+                                break shared.map(|shared| shared.clone());
+                            }
+                            Ok(()) = leader_streamed.changed() => {
+                                streamed.send_replace(leader_streamed.borrow_and_update().clone());
+                            }
+                        }
+                    };
+                    let Ok(shared) = shared else {
+                        // the request we waited on was cancelled, send our own, it streams from
+                        // the start again
+                        streamed.send_replace(String::new());
+                        continue;
+                    };
+                    streamed.send_replace(leader_streamed.borrow().clone());
+                    info!(key, "coalesced with an identical request in flight");
+                    return match shared {
+                        Some(Ok(completed)) => Ok(completed),
+                        Some(Err(err)) => Err(err.duplicate()),
+                        None => unreachable!("waited for the shared result to be set"),
+                    };
+                }
+                Role::Leader(tx, _leader) => {
+                    let result = request.await;
+                    let shared = match &result {
+                        Ok(completed) => Ok(completed.clone()),
+                        Err(err) => Err(Arc::new(err.duplicate())),
+                    };
+                    tx.send_replace(Some(shared));
+                    return result;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use custom_types::llm_ls::Backend;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use super::*;
+
+    fn config() -> BackendConfig {
+        BackendConfig {
+            model: "bigcode/starcoder".to_owned(),
+            api_token: None,
+            backend: Backend::default(),
+            request_timeout_ms: None,
+            extra_headers: HashMap::new(),
+        }
+    }
+
+    fn concurrency(max_queued_requests: usize) -> ConcurrencyConfig {
+        ConcurrencyConfig {
+            max_concurrent_requests: 1,
+            max_queued_requests,
+            coalesce: true,
+        }
+    }
+
+    /// Polls `future` for a little while, returning `None` if it is still pending.
+    async fn poll<F: Future + Unpin>(future: &mut F) -> Option<F::Output> {
+        tokio::time::timeout(Duration::from_millis(20), future)
+            .await
+            .ok()
+    }
+
+    fn completed(text: &str) -> Completed {
+        let generations = vec![Generation {
+            generated_text: text.to_owned(),
+        }];
+        let served_by = ServedBy::new("bigcode/starcoder".to_owned(), &Backend::default());
+        (generations, served_by)
+    }
+
+    #[tokio::test]
+    async fn test_permit_handoff() {
+        let limiter = BackendLimiter::default();
+        let (config, concurrency) = (config(), concurrency(1));
+        let permit = limiter.acquire(&config, "a", &concurrency).await.unwrap();
+        let mut queued = Box::pin(limiter.acquire(&config, "b", &concurrency));
+        assert!(poll(&mut queued).await.is_none());
+        drop(permit);
+        let permit = poll(&mut queued).await.unwrap().unwrap();
+        let mut queued = Box::pin(limiter.acquire(&config, "c", &concurrency));
+        assert!(poll(&mut queued).await.is_none());
+        drop(permit);
+        assert!(poll(&mut queued).await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_queue_bound() {
+        let limiter = BackendLimiter::default();
+        let config = config();
+        let _permit = limiter
+            .acquire(&config, "a", &concurrency(1))
+            .await
+            .unwrap();
+        assert!(matches!(
+            limiter.acquire(&config, "b", &concurrency(0)).await,
+            Err(Error::BackendQueueFull)
+        ));
+        let mut oldest = Box::pin(limiter.acquire(&config, "b", &concurrency(1)));
+        assert!(poll(&mut oldest).await.is_none());
+        let mut newest = Box::pin(limiter.acquire(&config, "c", &concurrency(1)));
+        assert!(poll(&mut newest).await.is_none());
+        assert!(matches!(
+            poll(&mut oldest).await,
+            Some(Err(Error::BackendQueueFull))
+        ));
+        // the queued request of the same document is superseded
+        let mut superseding = Box::pin(limiter.acquire(&config, "c", &concurrency(1)));
+        assert!(poll(&mut superseding).await.is_none());
+        assert!(matches!(
+            poll(&mut newest).await,
+            Some(Err(Error::Cancelled))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_coalesce() {
+        let coalescer = Coalescer::default();
+        let requests = AtomicUsize::new(0);
+        let (done, wait) = oneshot::channel::<()>();
+        let (leader_streamed, _) = watch::channel(String::new());
+        let (follower_streamed, follower_rx) = watch::channel(String::new());
+        let leader = coalescer.coalesce(1, &leader_streamed, async {
+            requests.fetch_add(1, Ordering::SeqCst);
+            leader_streamed.send_replace("def".to_owned());
+            wait.await.unwrap();
+            Ok(completed("def"))
+        });
+        let follower = coalescer.coalesce(1, &follower_streamed, async {
+            requests.fetch_add(1, Ordering::SeqCst);
+            Ok(completed("other"))
+        });
+        let release = async {
+            tokio::task::yield_now().await;
+            done.send(()).unwrap();
+        };
+        let (leader, follower, ()) = tokio::join!(leader, follower, release);
+        assert_eq!(leader.unwrap().0[0].generated_text, "def");
+        assert_eq!(follower.unwrap().0[0].generated_text, "def");
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        assert_eq!(*follower_rx.borrow(), "def");
+    }
+
+    #[tokio::test]
+    async fn test_coalesce_leader_cancelled() {
+        let coalescer = Coalescer::default();
+        let (leader_streamed, _) = watch::channel(String::new());
+        let (follower_streamed, _) = watch::channel(String::new());
+        let mut leader = Box::pin(coalescer.coalesce(
+            1,
+            &leader_streamed,
+            std::future::pending::<Result<Completed>>(),
+        ));
+        assert!(poll(&mut leader).await.is_none());
+        let mut follower =
+            Box::pin(coalescer.coalesce(1, &follower_streamed, async { Ok(completed("own")) }));
+        assert!(poll(&mut follower).await.is_none());
+        drop(leader);
+        let (generations, _) = poll(&mut follower).await.unwrap().unwrap();
+        assert_eq!(generations[0].generated_text, "own");
+    }
+}
@@ -0,0 +1,272 @@
+use custom_types::llm_ls::{ContextConfig, SnippetFormat};
+use ropey::Rope;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use tokenizers::Tokenizer;
+use tower_lsp::lsp_types::{Position, Url, WorkspaceFolder};
+
+use crate::document::Document;
+use crate::error::Result;
+use crate::language_id::LanguageId;
+
+/// A part of another file that may help the model complete the current one.
+#[derive(Clone, Debug)]
+pub(crate) struct Snippet {
+    /// Path of the file, relative to its workspace folder when it is in one
+    pub(crate) path: String,
+    pub(crate) text: String,
+    pub(crate) score: f32,
+}
+
+pub(crate) fn identifiers(text: &str) -> impl Iterator<Item = &str> {
+    text.split(|c: char| !c.is_alphanumeric() && c != '_')
+        .filter(|word| {
+            word.chars()
+                .next()
+                .is_some_and(|c| c.is_alphabetic() || c == '_')
+        })
+}
+
+fn jaccard(a: &HashSet<&str>, b: &HashSet<&str>) -> f32 {
+    let intersection = a.intersection(b).count();
+    let union = a.len() + b.len() - intersection;
+    if union == 0 {
+        0.0
+    } else {
+        intersection as f32 / union as f32
+    }
+}
+
+/// The path of `uri` relative to the workspace folder containing it, its full path otherwise.
+pub(crate) fn relative_path(uri: &str, workspace_folders: &[WorkspaceFolder]) -> String {
+    match Url::parse(uri).ok().and_then(|url| url.to_file_path().ok()) {
+        Some(path) => relative_file_path(&path, workspace_folders),
+        None => uri.to_owned(),
+    }
+}
+
+pub(crate) fn relative_file_path(path: &Path, workspace_folders: &[WorkspaceFolder]) -> String {
+    workspace_folders
+        .iter()
+        .filter_map(|folder| folder.uri.to_file_path().ok())
+        .find_map(|root| {
+            path.strip_prefix(root)
+                .ok()
+                .map(|relative| relative.display().to_string())
+        })
+        .unwrap_or_else(|| path.display().to_string())
+}
+
+/// The name of the workspace folder containing `uri`.
+pub(crate) fn repo_name<'a>(
+    uri: &str,
+    workspace_folders: &'a [WorkspaceFolder],
+) -> Option<&'a str> {
+    let path = Url::parse(uri).ok()?.to_file_path().ok()?;
+    workspace_folders
+        .iter()
+        .find(|folder| {
+            folder
+                .uri
+                .to_file_path()
+                .is_ok_and(|root| path.starts_with(root))
+        })
+        .map(|folder| folder.name.as_str())
+}
+
+/// Finds the window of `window_lines` lines of `text` with the most identifiers in common with
+/// `query`, windows overlap by half.
+fn best_window(text: &Rope, query: &HashSet<&str>, window_lines: usize) -> Option<(String, f32)> {
+    let lines: Vec<String> = text.lines().map(String::from).collect();
+    let line_identifiers: Vec<Vec<&str>> = lines
+        .iter()
+        .map(|line| identifiers(line).collect())
+        .collect();
+    let stride = (window_lines / 2).max(1);
+    let mut best: Option<(usize, f32)> = None;
+    let mut start = 0;
+    while start < lines.len() {
+        let end = (start + window_lines).min(lines.len());
+        let window: HashSet<&str> = line_identifiers[start..end]
+            .iter()
+            .flatten()
+            .copied()
+            .collect();
+        let score = jaccard(&window, query);
+        if score > 0.0 && !best.is_some_and(|(_, best)| score <= best) {
+            best = Some((start, score));
+        }
+        if end == lines.len() {
+            break;
+        }
+        start += stride;
+    }
+    let (start, score) = best?;
+    let end = (start + window_lines).min(lines.len());
+    Some((lines[start..end].concat(), score))
+}
+
+/// The lines before the cursor, which the snippets are compared to.
+pub(crate) fn query_text(text: &Rope, pos: Position, window_lines: usize) -> String {
+    let line = (pos.line as usize).min(text.len_lines().saturating_sub(1));
+    let start = (line + 1).saturating_sub(window_lines.max(1));
+    text.slice(text.line_to_char(start)..text.line_to_char(line + 1))
+        .to_string()
+}
+
+/// Picks the part of every other open document of the same language that is the most similar to
+/// the lines before the cursor, best first.
+pub(crate) fn neighboring_tabs(
+    document_map: &HashMap<String, Document>,
+    uri: &str,
+    query_text: &str,
+    workspace_folders: &[WorkspaceFolder],
+    config: &ContextConfig,
+) -> Vec<Snippet> {
+    let Some(document) = document_map.get(uri) else {
+        return vec![];
+    };
+    let window_lines = config.window_lines.max(1);
+    let query: HashSet<&str> = identifiers(query_text).collect();
+    if query.is_empty() {
+        return vec![];
+    }
+    let mut snippets: Vec<Snippet> = document_map
+        .iter()
+        .filter(|(other_uri, other)| {
+            other_uri.as_str() != uri && other.language_id == document.language_id
+        })
+        .filter_map(|(other_uri, other)| {
+            let (text, score) = best_window(&other.text, &query, window_lines)?;
+            Some(Snippet {
+                path: relative_path(other_uri, workspace_folders),
+                text,
+                score,
+            })
+        })
+        .collect();
+    snippets.sort_by(|a, b| b.score.total_cmp(&a.score));
+    snippets.truncate(config.max_snippets);
+    snippets
+}
+
+fn render_snippet(
+    snippet: &Snippet,
+    format: SnippetFormat,
+    file_separator: &str,
+    line_comment: &str,
+) -> String {
+    let mut rendered = match format {
+        SnippetFormat::Comment => {
+            let mut rendered = format!("{line_comment} Path: {}\n", snippet.path);
+            for line in snippet.text.lines() {
+                rendered.push_str(line_comment);
+                if !line.is_empty() {
+                    rendered.push(' ');
+                    rendered.push_str(line);
+                }
+                rendered.push('\n');
+            }
+            rendered
+        }
+        SnippetFormat::FileSeparator => {
+            format!("{file_separator}{}\n{}", snippet.path, snippet.text)
+        }
+    };
+    if !rendered.ends_with('\n') {
+        rendered.push('\n');
+    }
+    rendered
+}
+
+/// A comment line describing the current document, `None` when its language has no line comments.
+pub(crate) fn metadata_comment(
+    language_id: LanguageId,
+    repo_name: Option<&str>,
+    path: &str,
+) -> Option<String> {
+    let line_comment = language_id.line_comment()?;
+    let repo = repo_name
+        .map(|name| format!(" Repository: {name},"))
+        .unwrap_or_default();
+    Some(format!(
+        "{line_comment}{repo} Path: {path}, Language: {language_id}\n"
+    ))
+}
+
+/// Renders the snippets that fit in `budget` tokens, the best ones closest to the prefix.
+///
+/// Returns the rendered snippets and the number of tokens they use.
+pub(crate) fn render_snippets(
+    snippets: &[Snippet],
+    language_id: LanguageId,
+    config: &ContextConfig,
+    tokenizer: Option<&Tokenizer>,
+    budget: usize,
+) -> Result<(String, usize)> {
+    let line_comment = match (config.format, language_id.line_comment()) {
+        // the snippets can't be told apart from the code
+        (SnippetFormat::Comment, None) => return Ok((String::new(), 0)),
+        (_, line_comment) => line_comment.unwrap_or_default(),
+    };
+    let mut rendered = vec![];
+    let mut used = 0;
+    for snippet in snippets {
+        let text = render_snippet(snippet, config.format, &config.file_separator, line_comment);
+        let tokens = match tokenizer {
+            Some(tokenizer) => tokenizer.encode(text.as_str(), false)?.len(),
+            None => text.len(),
+        };
+        if used + tokens > budget {
+            continue;
+        }
+        used += tokens;
+        rendered.push(text);
+    }
+    Ok((rendered.into_iter().rev().collect(), used))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_snippets() {
+        let snippets = [
+            Snippet {
+                path: "src/a.rs".to_owned(),
+                text: "fn a() {}\n\nfn b() {}".to_owned(),
+                score: 0.5,
+            },
+            Snippet {
+                path: "src/c.rs".to_owned(),
+                text: "fn c() {}\n".to_owned(),
+                score: 0.2,
+            },
+        ];
+        let config = ContextConfig::default();
+        let (rendered, used) =
+            render_snippets(&snippets, LanguageId::Rust, &config, None, 1_000).unwrap();
+        assert_eq!(
+            rendered,
+            "// Path: src/c.rs\n// fn c() {}\n// Path: src/a.rs\n// fn a() {}\n//\n// fn b() {}\n"
+        );
+        assert_eq!(used, rendered.len());
+        let (rendered, _) =
+            render_snippets(&snippets, LanguageId::Rust, &config, None, 40).unwrap();
+        assert_eq!(rendered, "// Path: src/c.rs\n// fn c() {}\n");
+    }
+
+    #[test]
+    fn test_metadata_comment() {
+        assert_eq!(
+            metadata_comment(LanguageId::Python, Some("llm-ls"), "scripts/run.py").as_deref(),
+            Some("# Repository: llm-ls, Path: scripts/run.py, Language: python\n")
+        );
+        assert_eq!(
+            metadata_comment(LanguageId::Rust, None, "/tmp/main.rs").as_deref(),
+            Some("// Path: /tmp/main.rs, Language: rust\n")
+        );
+        assert_eq!(metadata_comment(LanguageId::Json, None, "a.json"), None);
+    }
+}
@@ -0,0 +1,163 @@
+use crate::error::{Error, Result};
+use crate::Prompt;
+
+/// What the request's document fills the template's fixed placeholders with.
+pub(crate) struct TemplateValues<'a> {
+    pub(crate) language: &'a str,
+    pub(crate) file_path: &'a str,
+    pub(crate) relative_path: &'a str,
+    pub(crate) repo_name: &'a str,
+}
+
+#[derive(Debug, PartialEq)]
+enum Part {
+    Text(String),
+    Prefix,
+    Suffix,
+    Snippets,
+}
+
+/// A user defined prompt layout, with its fixed placeholders already replaced. The prefix, the
+/// suffix and the snippets are filled in once they are cut to fit in the context window.
+#[derive(Debug, PartialEq)]
+pub(crate) struct PromptTemplate {
+    parts: Vec<Part>,
+}
+
+fn invalid(message: impl Into<String>) -> Error {
+    Error::InvalidPromptTemplate(message.into())
+}
+
+impl PromptTemplate {
+    /// Parses `{placeholder}`s, `{{` and `}}` being escaped braces.
+    pub(crate) fn parse(template: &str, values: &TemplateValues) -> Result<Self> {
+        let mut parts = vec![];
+        let mut text = String::new();
+        let mut chars = template.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.as_str().starts_with('{') => {
+                    chars.next();
+                    text.push('{');
+                }
+                '}' if chars.as_str().starts_with('}') => {
+                    chars.next();
+                    text.push('}');
+                }
+                '}' => return Err(invalid("unmatched `}`, write `}}` for a literal brace")),
+                '{' => {
+                    let rest = chars.as_str();
+                    let Some(end) = rest.find('}') else {
+                        return Err(invalid("unclosed `{`, write `{{` for a literal brace"));
+                    };
+                    let name = &rest[..end];
+                    chars = rest[end + 1..].chars();
+                    let part = match name {
+                        "prefix" => Part::Prefix,
+                        "suffix" => Part::Suffix,
+                        "snippets" => Part::Snippets,
+                        "language" => {
+                            text.push_str(values.language);
+                            continue;
+                        }
+                        "file_path" => {
+                            text.push_str(values.file_path);
+                            continue;
+                        }
+                        "relative_path" => {
+                            text.push_str(values.relative_path);
+                            continue;
+                        }
+                        "repo_name" => {
+                            text.push_str(values.repo_name);
+                            continue;
+                        }
+                        name => return Err(invalid(format!("unknown placeholder `{{{name}}}`"))),
+                    };
+                    if parts.contains(&part) {
+                        return Err(invalid(format!("`{{{name}}}` is used more than once")));
+                    }
+                    if !text.is_empty() {
+                        parts.push(Part::Text(std::mem::take(&mut text)));
+                    }
+                    parts.push(part);
+                }
+                c => text.push(c),
+            }
+        }
+        if !text.is_empty() {
+            parts.push(Part::Text(text));
+        }
+        if !parts.contains(&Part::Prefix) {
+            return Err(invalid("the template must contain `{prefix}`"));
+        }
+        Ok(Self { parts })
+    }
+
+    pub(crate) fn has_suffix(&self) -> bool {
+        self.parts.contains(&Part::Suffix)
+    }
+
+    pub(crate) fn has_snippets(&self) -> bool {
+        self.parts.contains(&Part::Snippets)
+    }
+
+    /// The text of the template around the placeholders, which always goes in the prompt.
+    pub(crate) fn fixed_text(&self) -> String {
+        self.parts
+            .iter()
+            .filter_map(|part| match part {
+                Part::Text(text) => Some(text.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub(crate) fn render(&self, prompt: &Prompt) -> String {
+        self.parts
+            .iter()
+            .map(|part| match part {
+                Part::Text(text) => text.as_str(),
+                Part::Prefix => prompt.prefix.as_str(),
+                Part::Suffix => prompt.suffix.as_deref().unwrap_or_default(),
+                Part::Snippets => prompt.context.as_str(),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let values = TemplateValues {
+            language: "rust",
+            file_path: "/repo/src/main.rs",
+            relative_path: "src/main.rs",
+            repo_name: "repo",
+        };
+        let template = PromptTemplate::parse(
+            "<repo>{repo_name} {{{language}}}\n{snippets}<pre>{prefix}<suf>{suffix}<mid>",
+            &values,
+        )
+        .unwrap();
+        assert_eq!(
+            template.parts,
+            [
+                Part::Text("<repo>repo {rust}\n".to_owned()),
+                Part::Snippets,
+                Part::Text("<pre>".to_owned()),
+                Part::Prefix,
+                Part::Text("<suf>".to_owned()),
+                Part::Suffix,
+                Part::Text("<mid>".to_owned()),
+            ]
+        );
+        assert_eq!(template.fixed_text(), "<repo>repo {rust}\n<pre><suf><mid>");
+        assert!(PromptTemplate::parse("{suffix}", &values).is_err());
+        assert!(PromptTemplate::parse("{prefix}{path}", &values).is_err());
+        assert!(PromptTemplate::parse("{prefix", &values).is_err());
+    }
+}
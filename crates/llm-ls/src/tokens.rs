@@ -0,0 +1,277 @@
+use tokenizers::Tokenizer;
+
+use crate::error::Result;
+
+/// Lines are tokenized in batches of about this many bytes, which is a few hundred lines of code.
+const BATCH_BYTES: usize = 16 * 1024;
+
+/// Counts the tokens of `text`, or its bytes without a tokenizer.
+pub(crate) fn count_tokens(tokenizer: Option<&Tokenizer>, text: &str) -> Result<usize> {
+    match tokenizer {
+        Some(tokenizer) if !text.is_empty() => Ok(tokenizer.encode(text, false)?.len()),
+        _ => Ok(text.len()),
+    }
+}
+
+/// Counts the tokens of every line with a single encoding of their concatenation, each token
+/// counting for the line it starts in.
+pub(crate) fn line_token_counts(
+    tokenizer: Option<&Tokenizer>,
+    lines: &[String],
+) -> Result<Vec<usize>> {
+    let Some(tokenizer) = tokenizer else {
+        return Ok(lines.iter().map(String::len).collect());
+    };
+    let encoding = tokenizer.encode(lines.concat(), false)?;
+    let line_ends: Vec<usize> = lines
+        .iter()
+        .scan(0, |end, line| {
+            *end += line.len();
+            Some(*end)
+        })
+        .collect();
+    let mut counts = vec![0; lines.len()];
+    for (start, _) in encoding.get_offsets() {
+        let line = line_ends.partition_point(|end| end <= start);
+        if let Some(count) = counts.get_mut(line) {
+            *count += 1;
+        }
+    }
+    Ok(counts)
+}
+
+/// Pairs lines with their token counts, tokenizing them by batches as they are consumed.
+pub(crate) struct TokenizedLines<'a, I> {
+    lines: I,
+    tokenizer: Option<&'a Tokenizer>,
+    /// Whether the lines go from the last to the first, they are tokenized in document order
+    reversed: bool,
+    batch: std::vec::IntoIter<(String, usize)>,
+}
+
+impl<'a, I: Iterator<Item = String>> TokenizedLines<'a, I> {
+    pub(crate) fn new(lines: I, tokenizer: Option<&'a Tokenizer>, reversed: bool) -> Self {
+        Self {
+            lines,
+            tokenizer,
+            reversed,
+            batch: Vec::new().into_iter(),
+        }
+    }
+
+    pub(crate) fn next(&mut self) -> Result<Option<(String, usize)>> {
+        if let Some(line) = self.batch.next() {
+            return Ok(Some(line));
+        }
+        let mut lines = vec![];
+        let mut bytes = 0;
+        while bytes < BATCH_BYTES {
+            let Some(line) = self.lines.next() else {
+                break;
+            };
+            bytes += line.len();
+            lines.push(line);
+        }
+        if self.reversed {
+            lines.reverse();
+        }
+        let counts = line_token_counts(self.tokenizer, &lines)?;
+        let mut batch: Vec<(String, usize)> = lines.into_iter().zip(counts).collect();
+        if self.reversed {
+            batch.reverse();
+        }
+        self.batch = batch.into_iter();
+        Ok(self.batch.next())
+    }
+}
+
+/// A byte level tokenizer without merges, every byte is a token.
+#[cfg(test)]
+pub(crate) fn byte_tokenizer() -> Tokenizer {
+    bpe_tokenizer(&[])
+}
+
+/// A byte level BPE tokenizer applying `merges` by priority, written with the byte level
+/// alphabet, e.g. `Ġ` for a space.
+#[cfg(test)]
+pub(crate) fn bpe_tokenizer(merges: &[(&str, &str)]) -> Tokenizer {
+    use tokenizers::models::bpe::BPE;
+    use tokenizers::models::ModelWrapper;
+    use tokenizers::pre_tokenizers::byte_level::ByteLevel;
+    use tokenizers::pre_tokenizers::PreTokenizerWrapper;
+
+    let mut vocab: std::collections::HashMap<String, u32> = ByteLevel::alphabet()
+        .into_iter()
+        .enumerate()
+        .map(|(id, c)| (c.to_string(), id as u32))
+        .collect();
+    for (left, right) in merges {
+        let id = vocab.len() as u32;
+        vocab.entry(format!("{left}{right}")).or_insert(id);
+    }
+    let merges = merges
+        .iter()
+        .map(|(left, right)| (left.to_string(), right.to_string()))
+        .collect();
+    let bpe = BPE::builder()
+        .vocab_and_merges(vocab, merges)
+        .build()
+        .unwrap();
+    let mut tokenizer = Tokenizer::new(ModelWrapper::from(bpe));
+    tokenizer.with_pre_tokenizer(PreTokenizerWrapper::from(ByteLevel::new(
+        false, false, true,
+    )));
+    tokenizer
+}
+
+#[cfg(test)]
+mod tests {
+    use custom_types::llm_ls::FimParams;
+    use ropey::Rope;
+    use std::time::Instant;
+    use tower_lsp::lsp_types::Position;
+
+    use super::*;
+
+    #[test]
+    fn test_tokenized_lines() {
+        let tokenizer = byte_tokenizer();
+        let lines = vec!["fn a() {\n".to_owned(), "}\n".to_owned(), "".to_owned()];
+        assert_eq!(
+            line_token_counts(Some(&tokenizer), &lines).unwrap(),
+            [9, 2, 0]
+        );
+        let mut reversed = TokenizedLines::new(lines.into_iter().rev(), Some(&tokenizer), true);
+        assert_eq!(reversed.next().unwrap(), Some((String::new(), 0)));
+        assert_eq!(reversed.next().unwrap(), Some(("}\n".to_owned(), 2)));
+        assert_eq!(reversed.next().unwrap(), Some(("fn a() {\n".to_owned(), 9)));
+        assert_eq!(reversed.next().unwrap(), None);
+    }
+
+    /// `build_prompt` as it was before the lines were tokenized in batches, each line encoded on
+    /// its own.
+    fn build_prompt_per_line(
+        pos: Position,
+        text: &Rope,
+        fim: &FimParams,
+        tokenizer: &Tokenizer,
+        context_window: usize,
+    ) -> String {
+        let cursor_line = pos.line as usize;
+        let col = pos.character as usize;
+        let mut remaining_token_count = context_window.saturating_sub(3);
+        let mut before_iter = text.lines_at(cursor_line + 1).reversed();
+        let mut after_iter = text.lines_at(cursor_line);
+        let mut before_line = before_iter
+            .next()
+            .map(|line| line.slice(0..col.min(line.len_chars())));
+        let mut after_line = after_iter
+            .next()
+            .map(|line| line.slice(col.min(line.len_chars())..));
+        let mut before = vec![];
+        let mut after = vec![];
+        while before_line.is_some() || after_line.is_some() {
+            if let Some(line) = before_line {
+                let line = line.to_string();
+                let tokens = count_tokens(Some(tokenizer), &line).unwrap();
+                if tokens > remaining_token_count {
+                    break;
+                }
+                remaining_token_count -= tokens;
+                before.push(line);
+            }
+            if let Some(line) = after_line {
+                let line = line.to_string();
+                let tokens = count_tokens(Some(tokenizer), &line).unwrap();
+                if tokens > remaining_token_count {
+                    break;
+                }
+                remaining_token_count -= tokens;
+                after.push(line);
+            }
+            before_line = before_iter.next();
+            after_line = after_iter.next();
+        }
+        before.reverse();
+        format!(
+            "{}{}{}{}{}",
+            fim.prefix,
+            before.concat(),
+            fim.suffix,
+            after.concat(),
+            fim.middle
+        )
+    }
+
+    /// Compares `build_prompt` with the per line encoding it replaced, on 10k lines of code with a
+    /// real BPE tokenizer:
+    ///
+    /// `LLM_LS_BENCH_TOKENIZER=path/to/tokenizer.json cargo test --release -p llm-ls bench_build_prompt -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn bench_build_prompt() {
+        const RUNS: usize = 10;
+        let path = std::env::var("LLM_LS_BENCH_TOKENIZER")
+            .expect("LLM_LS_BENCH_TOKENIZER should be the path of a tokenizer.json");
+        let tokenizer = Tokenizer::from_file(path).unwrap();
+        // this crate's sources, repeated up to 10k lines
+        let text = Rope::from_str(
+            &[include_str!("lib.rs"), include_str!("backend.rs")]
+                .concat()
+                .lines()
+                .cycle()
+                .take(10_000)
+                .map(|line| format!("{line}\n"))
+                .collect::<String>(),
+        );
+        let pos = Position::new(text.len_lines() as u32 / 2, 4);
+        let fim = FimParams {
+            enabled: true,
+            prefix: "<fim_prefix>".to_owned(),
+            middle: "<fim_middle>".to_owned(),
+            suffix: "<fim_suffix>".to_owned(),
+            mode: Default::default(),
+            order: None,
+            suffix_ratio: None,
+            max_suffix_lines: None,
+            infer_tokens: false,
+        };
+        let median_ms = |build: &dyn Fn() -> String| {
+            let mut runs: Vec<(f64, String)> = (0..RUNS)
+                .map(|_| {
+                    let start = Instant::now();
+                    let prompt = build();
+                    (start.elapsed().as_secs_f64() * 1000.0, prompt)
+                })
+                .collect();
+            runs.sort_by(|a, b| a.0.total_cmp(&b.0));
+            runs.swap_remove(RUNS / 2)
+        };
+
+        for context_window in [2_048, 8_192, 32_768] {
+            let (before_ms, before) =
+                median_ms(&|| build_prompt_per_line(pos, &text, &fim, &tokenizer, context_window));
+            let (after_ms, after) = median_ms(&|| {
+                crate::fit_prompt(
+                    pos,
+                    &text,
+                    &fim,
+                    Some(&tokenizer),
+                    context_window,
+                    context_window,
+                    None,
+                    "",
+                    None,
+                )
+                .unwrap()
+                .render(&fim)
+            });
+            let before_tokens = count_tokens(Some(&tokenizer), &before).unwrap();
+            let after_tokens = count_tokens(Some(&tokenizer), &after).unwrap();
+            assert!(after_tokens <= context_window);
+            println!(
+                "context window {context_window}: per line {before_ms:.2} ms ({before_tokens} tokens), batched {after_ms:.2} ms ({after_tokens} tokens)"
+            );
+        }
+    }
+}
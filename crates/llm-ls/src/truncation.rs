@@ -0,0 +1,252 @@
+use ropey::Rope;
+use std::collections::BTreeSet;
+use tree_sitter::{Node, Point, Tree};
+
+use crate::error::Result;
+use crate::language_id::LanguageId;
+
+/// Top level statements kept even when far from the cursor.
+const IMPORT_KINDS: &[&str] = &[
+    "extern_crate_declaration",
+    "import_declaration",
+    "import_from_statement",
+    "import_statement",
+    "preproc_include",
+    "use_declaration",
+    "using_directive",
+];
+
+/// Headers longer than this are more likely to be a long argument list than a signature.
+const MAX_HEADER_LINES: usize = 8;
+
+/// Whether `node` holds a sequence of statements or definitions, e.g. a function body.
+fn is_body(node: Node) -> bool {
+    let kind = node.kind();
+    match node.parent() {
+        None => true,
+        Some(parent) => {
+            kind.ends_with("block")
+                || kind.ends_with("body")
+                || kind == "declaration_list"
+                || parent.child_by_field_name("body") == Some(node)
+        }
+    }
+}
+
+/// The lines from the start of a scope to the start of its body, e.g. a function signature.
+fn header_lines(node: Node) -> Option<std::ops::RangeInclusive<usize>> {
+    let body = node.child_by_field_name("body")?;
+    let start = node.start_position().row;
+    let end = body
+        .prev_sibling()
+        .map_or(start, |prev| prev.end_position().row)
+        .max(start);
+    (end - start < MAX_HEADER_LINES).then_some(start..=end)
+}
+
+fn first_non_whitespace(line: &str) -> Option<usize> {
+    line.find(|c: char| !c.is_whitespace())
+}
+
+/// The parts of a document to keep when it doesn't fit in the prompt.
+#[derive(Clone, Copy)]
+pub(crate) struct Syntax<'a> {
+    pub(crate) tree: &'a Tree,
+    pub(crate) text: &'a Rope,
+    pub(crate) language_id: LanguageId,
+}
+
+impl Syntax<'_> {
+    /// Whether a syntactic unit, e.g. a statement or a definition, starts on `line`.
+    fn is_unit_start(&self, line: usize) -> bool {
+        let text = self.text.line(line).to_string();
+        let Some(column) = first_non_whitespace(&text) else {
+            // blank lines separate units
+            return true;
+        };
+        let point = Point::new(line, column);
+        let Some(mut node) = self
+            .tree
+            .root_node()
+            .named_descendant_for_point_range(point, point)
+        else {
+            return false;
+        };
+        while let Some(parent) = node.parent() {
+            if parent.start_byte() != node.start_byte() {
+                break;
+            }
+            node = parent;
+        }
+        node.start_position().row == line && node.parent().map_or(true, is_body)
+    }
+
+    /// The first line of `start..=end` where a unit starts, `start` when there is none.
+    pub(crate) fn unit_start(&self, start: usize, end: usize) -> usize {
+        (start..=end)
+            .find(|line| self.is_unit_start(*line))
+            .unwrap_or(start)
+    }
+
+    /// The last line of `start..=end` such that the next one starts a unit, `end` when there is
+    /// none.
+    pub(crate) fn unit_end(&self, start: usize, end: usize) -> usize {
+        (start..end)
+            .rev()
+            .find(|line| self.is_unit_start(line + 1))
+            .unwrap_or(end)
+    }
+
+    /// Groups of lines before `first_kept` worth keeping, most important first: the headers of
+    /// the scopes enclosing the cursor, the imports, then the headers of the definitions around
+    /// the cursor, nearest first, whose bodies are left out.
+    fn pinned_groups(&self, cursor: Point, first_kept: usize) -> Vec<Vec<usize>> {
+        let root = self.tree.root_node();
+        let mut groups = vec![];
+        let mut scopes = vec![];
+        let mut node = root.named_descendant_for_point_range(cursor, cursor);
+        while let Some(current) = node {
+            if current.start_position().row < first_kept {
+                if let Some(lines) = header_lines(current) {
+                    groups.push(lines.filter(|line| *line < first_kept).collect());
+                }
+            }
+            if is_body(current) {
+                scopes.push(current);
+            }
+            node = current.parent();
+        }
+        let mut cursor = root.walk();
+        for child in root.named_children(&mut cursor) {
+            if IMPORT_KINDS.contains(&child.kind()) && child.end_position().row < first_kept {
+                groups.push((child.start_position().row..=child.end_position().row).collect());
+            }
+        }
+        let mut siblings = vec![];
+        for scope in scopes {
+            let mut cursor = scope.walk();
+            for child in scope.named_children(&mut cursor) {
+                if child.end_position().row >= first_kept {
+                    continue;
+                }
+                if let Some(lines) = header_lines(child) {
+                    siblings.push(lines.collect::<Vec<_>>());
+                }
+            }
+        }
+        siblings.sort_by_key(|lines| std::cmp::Reverse(lines[0]));
+        groups.extend(siblings);
+        groups
+    }
+
+    fn marker(&self, next_line: Option<usize>) -> String {
+        let indent = next_line
+            .map(|line| {
+                let line = self.text.line(line).to_string();
+                let indent = first_non_whitespace(&line).unwrap_or(0);
+                line[..indent].to_owned()
+            })
+            .unwrap_or_default();
+        match self.language_id.line_comment() {
+            Some(comment) => format!("{indent}{comment} ...\n"),
+            None => format!("{indent}...\n"),
+        }
+    }
+
+    /// Rebuilds a prefix that doesn't reach the start of the document so that it starts on a
+    /// unit, and keeps the headers of the enclosing scopes and the imports, trading the lines
+    /// the furthest from the cursor for them. Skipped lines are replaced with a marker.
+    ///
+    /// `before` holds the kept lines with their token counts, the cursor's first.
+    pub(crate) fn elide_prefix(
+        &self,
+        mut before: Vec<(String, usize)>,
+        cursor: Point,
+        count_tokens: impl Fn(&str) -> Result<usize>,
+    ) -> Result<String> {
+        if before.is_empty() {
+            return Ok(String::new());
+        }
+        let first_kept = cursor.row + 1 - before.len();
+        let budget: usize = before.iter().map(|(_, tokens)| tokens).sum();
+        let mut pinned = BTreeSet::new();
+        let mut pinned_tokens = 0;
+        for group in self.pinned_groups(cursor, first_kept) {
+            if group.iter().all(|line| pinned.contains(line)) {
+                continue;
+            }
+            let mut tokens = count_tokens(&self.marker(None))?;
+            for line in group.iter().filter(|line| !pinned.contains(*line)) {
+                tokens += count_tokens(&self.text.line(*line).to_string())?;
+            }
+            // leave most of the prompt to the code around the cursor
+            if pinned_tokens + tokens > budget / 2 {
+                continue;
+            }
+            pinned_tokens += tokens;
+            pinned.extend(group);
+        }
+        let needed = pinned_tokens + count_tokens(&self.marker(None))?;
+        let mut freed = 0;
+        while freed < needed && before.len() > 1 {
+            if let Some((_, tokens)) = before.pop() {
+                freed += tokens;
+            }
+        }
+        let first_kept = cursor.row + 1 - before.len();
+        let unit_start = self.unit_start(first_kept, cursor.row);
+        before.truncate(cursor.row + 1 - unit_start);
+        pinned.retain(|line| *line < unit_start);
+
+        let mut prefix = String::new();
+        let mut next = 0;
+        for line in pinned {
+            if line > next {
+                prefix.push_str(&self.marker(Some(line)));
+            }
+            prefix.push_str(&self.text.line(line).to_string());
+            next = line + 1;
+        }
+        if unit_start > next {
+            prefix.push_str(&self.marker(Some(unit_start)));
+        }
+        for (line, _) in before.into_iter().rev() {
+            prefix.push_str(&line);
+        }
+        Ok(prefix)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::get_parser;
+
+    #[test]
+    fn test_elide_prefix() {
+        let src =
+            "use std::fs;\n\nfn a() {\n    let x = 1;\n    let y = 2;\n    let z = 3;\n    x\n}\n";
+        let text = Rope::from_str(src);
+        let tree = get_parser(LanguageId::Rust)
+            .unwrap()
+            .parse(src, None)
+            .unwrap();
+        let syntax = Syntax {
+            tree: &tree,
+            text: &text,
+            language_id: LanguageId::Rust,
+        };
+        let cursor = Point::new(6, 4);
+        let before = vec![
+            ("    ".to_owned(), 10),
+            ("    let z = 3;\n".to_owned(), 10),
+            ("    let y = 2;\n".to_owned(), 10),
+            ("    let x = 1;\n".to_owned(), 10),
+        ];
+        let prefix = syntax.elide_prefix(before, cursor, |_| Ok(1)).unwrap();
+        assert_eq!(
+            prefix,
+            "use std::fs;\n// ...\nfn a() {\n    // ...\n    let y = 2;\n    let z = 3;\n    "
+        );
+    }
+}
@@ -0,0 +1,484 @@
+use ignore::WalkBuilder;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Instant;
+use tower_lsp::lsp_types::WorkspaceFolder;
+use tracing::{debug, info, warn};
+use tree_sitter::Parser;
+
+use crate::document::get_parser;
+use crate::error::Result;
+use crate::language_id::LanguageId;
+use crate::snippets::{identifiers, Snippet};
+
+const MAX_INDEXED_FILES: usize = 10_000;
+const MAX_FILE_BYTES: u64 = 512 * 1024;
+const CHUNK_MAX_LINES: usize = 40;
+const BM25_K1: f32 = 1.2;
+const BM25_B: f32 = 0.75;
+
+/// A few top level syntax nodes of a file.
+struct Chunk {
+    language_id: LanguageId,
+    text: String,
+    /// Occurrences of every lowercased identifier of the chunk
+    term_counts: HashMap<String, u32>,
+    len: usize,
+}
+
+impl Chunk {
+    fn new(language_id: LanguageId, text: String) -> Option<Self> {
+        let mut term_counts: HashMap<String, u32> = HashMap::new();
+        let mut len = 0;
+        for term in identifiers(&text) {
+            *term_counts.entry(term.to_lowercase()).or_default() += 1;
+            len += 1;
+        }
+        if len == 0 {
+            return None;
+        }
+        Some(Self {
+            language_id,
+            text,
+            term_counts,
+            len,
+        })
+    }
+}
+
+struct IndexedFile {
+    /// Path relative to the workspace folder
+    path: String,
+    chunks: Vec<Chunk>,
+}
+
+#[derive(Default)]
+struct IndexState {
+    files: HashMap<PathBuf, IndexedFile>,
+    /// Number of chunks every term appears in
+    document_frequencies: HashMap<String, usize>,
+    chunk_count: usize,
+    total_len: usize,
+}
+
+impl IndexState {
+    fn insert(&mut self, path: PathBuf, file: IndexedFile) {
+        self.remove(&path);
+        for chunk in &file.chunks {
+            for term in chunk.term_counts.keys() {
+                *self.document_frequencies.entry(term.clone()).or_default() += 1;
+            }
+            self.chunk_count += 1;
+            self.total_len += chunk.len;
+        }
+        self.files.insert(path, file);
+    }
+
+    fn remove(&mut self, path: &Path) {
+        let Some(file) = self.files.remove(path) else {
+            return;
+        };
+        for chunk in &file.chunks {
+            for term in chunk.term_counts.keys() {
+                if let Some(frequency) = self.document_frequencies.get_mut(term) {
+                    *frequency -= 1;
+                    if *frequency == 0 {
+                        self.document_frequencies.remove(term);
+                    }
+                }
+            }
+            self.chunk_count -= 1;
+            self.total_len -= chunk.len;
+        }
+    }
+
+    /// Ranks the chunks of the given language with BM25, skipping the `excluded` files.
+    fn search(
+        &self,
+        query_text: &str,
+        language_id: LanguageId,
+        excluded: &HashSet<PathBuf>,
+        top_k: usize,
+    ) -> Vec<Snippet> {
+        if self.chunk_count == 0 {
+            return vec![];
+        }
+        let chunk_count = self.chunk_count as f32;
+        let average_len = self.total_len as f32 / chunk_count;
+        let terms: HashSet<String> = identifiers(query_text).map(str::to_lowercase).collect();
+        let idfs: Vec<(&String, f32)> = terms
+            .iter()
+            .filter_map(|term| {
+                let frequency = *self.document_frequencies.get(term)? as f32;
+                let idf = ((chunk_count - frequency + 0.5) / (frequency + 0.5) + 1.0).ln();
+                Some((term, idf))
+            })
+            .collect();
+        if idfs.is_empty() {
+            return vec![];
+        }
+        let mut scored: Vec<(f32, &IndexedFile, &Chunk)> = vec![];
+        for (path, file) in &self.files {
+            if excluded.contains(path) {
+                continue;
+            }
+            for chunk in &file.chunks {
+                if chunk.language_id != language_id {
+                    continue;
+                }
+                let norm = BM25_K1 * (1.0 - BM25_B + BM25_B * chunk.len as f32 / average_len);
+                let score: f32 = idfs
+                    .iter()
+                    .filter_map(|(term, idf)| {
+                        let count = *chunk.term_counts.get(*term)? as f32;
+                        Some(idf * count * (BM25_K1 + 1.0) / (count + norm))
+                    })
+                    .sum();
+                if score > 0.0 {
+                    scored.push((score, file, chunk));
+                }
+            }
+        }
+        scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+        scored
+            .into_iter()
+            .take(top_k)
+            .map(|(score, file, chunk)| Snippet {
+                path: file.path.clone(),
+                text: chunk.text.clone(),
+                score,
+            })
+            .collect()
+    }
+}
+
+/// Groups the top level nodes of the file in chunks of at most `CHUNK_MAX_LINES` lines, nodes
+/// larger than that are split.
+fn chunk_file(text: &str, language_id: LanguageId, parser: &mut Parser) -> Vec<Chunk> {
+    let lines: Vec<&str> = text.split_inclusive('\n').collect();
+    let mut ranges = vec![];
+    match parser.parse(text, None) {
+        Some(tree) => {
+            let root = tree.root_node();
+            let mut cursor = root.walk();
+            let mut current: Option<(usize, usize)> = None;
+            for node in root.children(&mut cursor) {
+                let node_start = node.start_position().row;
+                let node_end = node.end_position().row + 1;
+                current = match current {
+                    Some((start, _)) if node_end - start <= CHUNK_MAX_LINES => {
+                        Some((start, node_end))
+                    }
+                    Some(range) => {
+                        ranges.push(range);
+                        Some((node_start, node_end))
+                    }
+                    None => Some((node_start, node_end)),
+                };
+            }
+            ranges.extend(current);
+        }
+        None => ranges.push((0, lines.len())),
+    }
+    ranges
+        .into_iter()
+        .flat_map(|(start, end)| {
+            (start..end)
+                .step_by(CHUNK_MAX_LINES)
+                .map(move |start| (start, (start + CHUNK_MAX_LINES).min(end)))
+        })
+        .filter_map(|(start, end)| {
+            let text = lines.get(start..end.min(lines.len()))?.concat();
+            Chunk::new(language_id, text)
+        })
+        .collect()
+}
+
+/// Only source code is indexed, the other languages we know of are markup or data.
+fn indexed_language(path: &Path) -> Option<LanguageId> {
+    let extension = path.extension()?.to_str()?;
+    match LanguageId::from_extension(extension) {
+        LanguageId::Html | LanguageId::Json | LanguageId::Markdown | LanguageId::Unknown => None,
+        language_id => Some(language_id),
+    }
+}
+
+fn index_file(
+    root: &Path,
+    path: &Path,
+    parsers: &mut HashMap<LanguageId, Parser>,
+) -> Option<IndexedFile> {
+    let language_id = indexed_language(path)?;
+    if std::fs::metadata(path).ok()?.len() > MAX_FILE_BYTES {
+        return None;
+    }
+    let text = std::fs::read_to_string(path).ok()?;
+    let parser = match parsers.entry(language_id) {
+        Entry::Occupied(entry) => entry.into_mut(),
+        Entry::Vacant(entry) => entry.insert(get_parser(language_id).ok()?),
+    };
+    Some(IndexedFile {
+        path: path
+            .strip_prefix(root)
+            .unwrap_or(path)
+            .display()
+            .to_string(),
+        chunks: chunk_file(&text, language_id, parser),
+    })
+}
+
+/// Walks the files of `root` that are indexed, skipping hidden files and the ones ignored by
+/// `.gitignore`, `.ignore` and git's exclude files.
+fn walker(root: &Path) -> WalkBuilder {
+    WalkBuilder::new(root)
+}
+
+/// Whether `path` is one of the files `walker` yields, only reading the directories on its way.
+fn is_walked(root: &Path, path: &Path) -> bool {
+    let target = path.to_owned();
+    walker(root)
+        .filter_entry(move |entry| target.starts_with(entry.path()))
+        .build()
+        .filter_map(|entry| entry.ok())
+        .any(|entry| entry.path() == path)
+}
+
+fn crawl(state: &RwLock<IndexState>, root: &Path) {
+    let start = Instant::now();
+    let mut parsers = HashMap::new();
+    let mut indexed = 0;
+    for entry in walker(root).build() {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(err) => {
+                debug!("failed to walk the workspace: {err}");
+                continue;
+            }
+        };
+        if !entry
+            .file_type()
+            .is_some_and(|file_type| file_type.is_file())
+        {
+            continue;
+        }
+        let Some(file) = index_file(root, entry.path(), &mut parsers) else {
+            continue;
+        };
+        let mut state = state.write().expect("workspace index lock poisoned");
+        if state.files.len() >= MAX_INDEXED_FILES {
+            warn!(root = %root.display(), "stopped indexing after {MAX_INDEXED_FILES} files");
+            break;
+        }
+        state.insert(entry.into_path(), file);
+        indexed += 1;
+    }
+    info!(
+        root = %root.display(),
+        indexed,
+        index_ms = start.elapsed().as_millis(),
+        "indexed workspace folder"
+    );
+}
+
+/// A lexical index of the source files of the workspace folders, used to retrieve code from
+/// files that aren't open.
+#[derive(Default)]
+pub(crate) struct WorkspaceIndex {
+    state: Arc<RwLock<IndexState>>,
+    roots: Mutex<HashSet<PathBuf>>,
+}
+
+impl WorkspaceIndex {
+    /// Starts indexing the workspace folders that aren't yet, in the background.
+    ///
+    /// Returns whether a folder started being indexed.
+    pub(crate) fn index_folders(&self, workspace_folders: &[WorkspaceFolder]) -> bool {
+        let mut roots = self.roots.lock().expect("workspace roots lock poisoned");
+        let mut started = false;
+        for folder in workspace_folders {
+            let Ok(root) = folder.uri.to_file_path() else {
+                continue;
+            };
+            if !roots.insert(root.clone()) {
+                continue;
+            }
+            let state = self.state.clone();
+            tokio::task::spawn_blocking(move || crawl(&state, &root));
+            started = true;
+        }
+        started
+    }
+
+    fn root_of(&self, path: &Path) -> Option<PathBuf> {
+        let roots = self.roots.lock().expect("workspace roots lock poisoned");
+        roots.iter().find(|root| path.starts_with(root)).cloned()
+    }
+
+    /// Indexes the file again after it changed on disk, unless the crawl would have skipped it.
+    pub(crate) fn update_file(&self, path: PathBuf) {
+        let Some(root) = self.root_of(&path) else {
+            return;
+        };
+        let state = self.state.clone();
+        tokio::task::spawn_blocking(move || {
+            let file = if is_walked(&root, &path) {
+                index_file(&root, &path, &mut HashMap::new())
+            } else {
+                None
+            };
+            let mut state = state.write().expect("workspace index lock poisoned");
+            match file {
+                Some(file) if state.files.contains_key(&path) => state.insert(path, file),
+                Some(file) if state.files.len() < MAX_INDEXED_FILES => state.insert(path, file),
+                Some(_) => debug!(path = %path.display(), "workspace index is full"),
+                None => state.remove(&path),
+            }
+        });
+    }
+
+    pub(crate) fn remove_file(&self, path: &Path) {
+        self.state
+            .write()
+            .expect("workspace index lock poisoned")
+            .remove(path);
+    }
+
+    /// Ranks the chunks of the given language with BM25, skipping the `excluded` files, off the
+    /// async runtime since the whole index is scored.
+    pub(crate) async fn search(
+        &self,
+        query_text: String,
+        language_id: LanguageId,
+        excluded: HashSet<PathBuf>,
+        top_k: usize,
+    ) -> Result<Vec<Snippet>> {
+        let state = self.state.clone();
+        let snippets = tokio::task::spawn_blocking(move || {
+            state.read().expect("workspace index lock poisoned").search(
+                &query_text,
+                language_id,
+                &excluded,
+                top_k,
+            )
+        })
+        .await?;
+        Ok(snippets)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn index(files: &[(&str, &str)]) -> WorkspaceIndex {
+        let index = WorkspaceIndex::default();
+        let mut parser = get_parser(LanguageId::Rust).unwrap();
+        {
+            let mut state = index.state.write().unwrap();
+            for (path, text) in files {
+                let file = IndexedFile {
+                    path: path.to_string(),
+                    chunks: chunk_file(text, LanguageId::Rust, &mut parser),
+                };
+                state.insert(PathBuf::from(path), file);
+            }
+        }
+        index
+    }
+
+    fn search(index: &WorkspaceIndex, query: &str) -> Vec<String> {
+        index
+            .state
+            .read()
+            .unwrap()
+            .search(query, LanguageId::Rust, &HashSet::new(), 10)
+            .into_iter()
+            .map(|snippet| snippet.path)
+            .collect()
+    }
+
+    #[test]
+    fn test_search() {
+        let index = index(&[
+            ("tokens.rs", "fn count_tokens(tokenizer: &Tokenizer) -> usize {\n    tokenizer.encode()\n}\n"),
+            ("cache.rs", "fn cache_key(prompt: &Prompt) -> u64 {\n    prompt.hash()\n}\n"),
+            ("prompt.rs", "fn build_prompt(tokenizer: &Tokenizer, prompt: &Prompt) -> Prompt {\n    todo()\n}\n"),
+        ]);
+        // rarer terms weigh more, `cache_key` only appears in one file
+        assert_eq!(
+            search(&index, "cache_key(prompt)"),
+            ["cache.rs", "prompt.rs"]
+        );
+        assert_eq!(
+            search(&index, "tokenizer tokenizer"),
+            ["tokens.rs", "prompt.rs"]
+        );
+        assert!(search(&index, "unrelated").is_empty());
+        let excluded = HashSet::from([PathBuf::from("cache.rs")]);
+        let state = index.state.read().unwrap();
+        assert!(state
+            .search("cache_key", LanguageId::Rust, &excluded, 10)
+            .is_empty());
+        assert!(state
+            .search("prompt", LanguageId::Python, &HashSet::new(), 10)
+            .is_empty());
+    }
+
+    #[test]
+    fn test_remove_file() {
+        let index = index(&[
+            ("a.rs", "fn shared() {}\n"),
+            ("b.rs", "fn shared() {}\nfn other() {}\n"),
+        ]);
+        index.remove_file(Path::new("a.rs"));
+        assert_eq!(search(&index, "shared"), ["b.rs"]);
+        let state = index.state.read().unwrap();
+        assert_eq!(state.chunk_count, 1);
+        assert_eq!(state.document_frequencies.get("shared"), Some(&1));
+        index.remove_file(Path::new("b.rs"));
+        let state = index.state.read().unwrap();
+        assert_eq!((state.chunk_count, state.total_len), (0, 0));
+        assert!(state.document_frequencies.is_empty());
+    }
+
+    #[test]
+    fn test_is_walked() {
+        let root = std::env::temp_dir().join(format!("llm-ls-index-{}", uuid::Uuid::new_v4()));
+        let files = [
+            "src/lib.rs",
+            "src/gen.rs",
+            "target/debug/build.rs",
+            ".hidden/a.rs",
+        ];
+        for file in files {
+            let path = root.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, "fn a() {}\n").unwrap();
+        }
+        std::fs::write(root.join(".ignore"), "target/\n").unwrap();
+        std::fs::write(root.join("src/.ignore"), "gen.rs\n").unwrap();
+        let walked: Vec<bool> = files
+            .iter()
+            .map(|file| is_walked(&root, &root.join(file)))
+            .collect();
+        assert_eq!(walked, [true, false, false, false]);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_chunk_file() {
+        let mut parser = get_parser(LanguageId::Rust).unwrap();
+        let text = "use std::fs;\n\nfn a() {\n    fs::read(\"a\");\n}\n\nstruct B;\n";
+        let chunks = chunk_file(text, LanguageId::Rust, &mut parser);
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].text, text);
+        assert_eq!(chunks[0].term_counts["fs"], 2);
+
+        let text = "fn a() {}\n".repeat(CHUNK_MAX_LINES + 1);
+        let chunks = chunk_file(&text, LanguageId::Rust, &mut parser);
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[1].text, "fn a() {}\n");
+    }
+}
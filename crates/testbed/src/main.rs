@@ -545,19 +545,36 @@ async fn complete_holes(
             .send_request::<GetCompletions>(GetCompletionsParams {
                 api_token: api_token.clone(),
                 context_window,
+                discover_context_window: false,
                 fim: fim.clone(),
                 ide: Ide::default(),
                 model: model.clone(),
                 backend,
+                preset: Default::default(),
+                prompt_template: None,
+                metadata: Default::default(),
+                stop: vec![],
                 text_document_position: TextDocumentPositionParams {
                     position: hole.cursor,
                     text_document: TextDocumentIdentifier { uri },
                 },
                 tls_skip_verify_insecure,
+                proxy: None,
+                ca_cert_path: None,
+                client_cert_path: None,
+                client_key_path: None,
                 tokens_to_clear: tokens_to_clear.clone(),
                 tokenizer_config: tokenizer_config.clone(),
                 request_body: request_body.clone(),
                 disable_url_path_completion,
+                stream: false,
+                request_timeout_ms: None,
+                extra_headers: Default::default(),
+                fallback_backends: vec![],
+                retry: Default::default(),
+                cache: Default::default(),
+                concurrency: Default::default(),
+                context: Default::default(),
             })
             .await?;
 